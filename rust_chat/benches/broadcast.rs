@@ -0,0 +1,90 @@
+/* Compares ways of queueing one chat message for many connections and writing it out:
+ *  - copied: every connection gets its own copy of the payload, framed into another buffer
+ *    when it is sent and written in chunks, as the sender did before frames were shared
+ *  - shared: the message is framed once and every connection queues the same frame
+ *
+ * Run with `cargo bench -p rust_chat --bench broadcast`
+ */
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_chat::frame_header::{FrameHeader, FRAME_HEADER_SIZE};
+use rust_chat::{protocol, MesasgeFromUser, PacketSender, ServerMessage};
+use std::collections::VecDeque;
+use std::hint::black_box;
+use std::io::Write;
+
+const RECIPIENTS: [usize; 3] = [10, 100, 1000];
+
+// Largest write of the old sender
+const MAX_SEND_CHUNK: usize = 1024;
+
+// Queue of the old sender, payloads are owned by each connection and framed one by one
+#[derive(Default)]
+struct CopyingSender {
+    send_queue: VecDeque<Vec<u8>>,
+}
+
+impl CopyingSender {
+    fn add_to_send_queue(&mut self, data: Vec<u8>) {
+        self.send_queue.push_back(data);
+    }
+
+    fn advance<Stream: Write>(&mut self, stream: &mut Stream) -> std::io::Result<()> {
+        while let Some(payload) = self.send_queue.pop_front() {
+            let mut data = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+            data.extend_from_slice(&FrameHeader::new(payload.len() as u32).to_bytes());
+            data.extend_from_slice(&payload);
+            for chunk in data.chunks(MAX_SEND_CHUNK) {
+                stream.write_all(chunk)?;
+            }
+        }
+        stream.flush()
+    }
+}
+
+fn message() -> ServerMessage {
+    ServerMessage::MessageFromUser(MesasgeFromUser {
+        username: "alice".to_string(),
+        text: "The quick brown fox jumps over the lazy dog. ".repeat(4),
+        channel: "general".to_string(),
+        id: Some(123_456),
+        timestamp_ms: Some(1_700_000_000_000),
+    })
+}
+
+fn broadcast(c: &mut Criterion) {
+    let message = message();
+    let mut group = c.benchmark_group("broadcast");
+
+    for recipients in RECIPIENTS {
+        let mut copying_senders: Vec<CopyingSender> = (0..recipients).map(|_| CopyingSender::default()).collect();
+        let mut senders: Vec<PacketSender> = (0..recipients).map(|_| PacketSender::new()).collect();
+        group.throughput(Throughput::Elements(recipients as u64));
+
+        group.bench_with_input(BenchmarkId::new("copied", recipients), &message, |b, message| {
+            b.iter(|| {
+                let payload = protocol::encode(message);
+                for sender in &mut copying_senders {
+                    sender.add_to_send_queue(payload.clone());
+                    sender.advance(&mut std::io::sink()).unwrap();
+                }
+                black_box(&copying_senders);
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("shared", recipients), &message, |b, message| {
+            b.iter(|| {
+                let frame = protocol::encode_frame(message).unwrap();
+                for sender in &mut senders {
+                    sender.add_frame(frame.clone());
+                    sender.advance(&mut std::io::sink()).unwrap();
+                }
+                black_box(&senders);
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, broadcast);
+criterion_main!(benches);
@@ -0,0 +1,214 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::fmt::Display;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::frame_header::{FrameHeader, FrameHeaderError, FRAME_HEADER_SIZE, MAX_PACKET_SIZE};
+
+/* Same framing as incoming_packet/outgoing_packet for use with tokio_util::codec::Framed.
+ * Decoded items are packet payloads without the header.
+ */
+#[derive(Default)]
+pub struct FrameCodec {
+    // header of the packet which payload has not been received completely yet
+    header: Option<FrameHeader>,
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    ZeroSizedPacket,
+    SizeTooBig(usize),
+    InvalidHeader(FrameHeaderError),
+    StreamError(std::io::Error),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ZeroSizedPacket => write!(f, "Zero-sized packet"),
+            Self::SizeTooBig(size) => write!(f, "Packet too big ({size})"),
+            Self::InvalidHeader(err) => write!(f, "Invalid packet header: {err}"),
+            Self::StreamError(err) => write!(f, "stream error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<std::io::Error> for CodecError {
+    fn from(err: std::io::Error) -> Self {
+        CodecError::StreamError(err)
+    }
+}
+
+impl FrameCodec {
+    pub fn new() -> FrameCodec {
+        FrameCodec { header: None }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, CodecError> {
+        let header = match self.header {
+            Some(header) => header,
+            None => {
+                if src.len() < FRAME_HEADER_SIZE {
+                    src.reserve(FRAME_HEADER_SIZE - src.len());
+                    return Ok(None);
+                }
+
+                let mut header_bytes = [0; FRAME_HEADER_SIZE];
+                header_bytes.copy_from_slice(&src[..FRAME_HEADER_SIZE]);
+                let header = FrameHeader::from_bytes(&header_bytes).map_err(CodecError::InvalidHeader)?;
+                if header.length == 0 {
+                    return Err(CodecError::ZeroSizedPacket);
+                }
+                if header.length >= MAX_PACKET_SIZE {
+                    return Err(CodecError::SizeTooBig(header.length as usize));
+                }
+
+                src.advance(FRAME_HEADER_SIZE);
+                self.header = Some(header);
+                header
+            }
+        };
+
+        let length = header.length as usize;
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+
+        self.header = None;
+        Ok(Some(src.split_to(length)))
+    }
+}
+
+impl<T> Encoder<T> for FrameCodec
+where
+    T: AsRef<[u8]>,
+{
+    type Error = CodecError;
+
+    fn encode(&mut self, payload: T, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let payload = payload.as_ref();
+        if payload.is_empty() {
+            return Err(CodecError::ZeroSizedPacket);
+        }
+
+        if payload.len() >= MAX_PACKET_SIZE as usize {
+            return Err(CodecError::SizeTooBig(payload.len()));
+        }
+
+        dst.reserve(FRAME_HEADER_SIZE + payload.len());
+        dst.put_slice(&FrameHeader::new(payload.len() as u32).to_bytes());
+        dst.put_slice(payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outgoing_packet::Frame;
+    use crate::test_utils::{generate_random_string, make_buffer_for_packet};
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    #[test]
+    fn decode_fragmented_packets() {
+        let payload_a = generate_random_string(1, 10, 100);
+        let payload_b = generate_random_string(2, 10, 100);
+        let mut buffer = make_buffer_for_packet(&payload_a);
+        buffer.extend(make_buffer_for_packet(&payload_b));
+
+        let mut codec = FrameCodec::new();
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in buffer {
+            src.put_u8(byte);
+            while let Some(payload) = codec.decode(&mut src).unwrap() {
+                decoded.push(String::from_utf8(payload.to_vec()).unwrap());
+            }
+        }
+
+        assert_eq!(decoded, vec![payload_a, payload_b]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_invalid_header() {
+        let mut src = BytesMut::from(&make_buffer_for_packet("payload")[..]);
+        src[1] = 0;
+
+        match FrameCodec::new().decode(&mut src) {
+            Err(CodecError::InvalidHeader(FrameHeaderError::UnsupportedVersion(0))) => {}
+            result => panic!("Unexpected decode result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn decode_zero_sized_packet() {
+        let mut src = BytesMut::from(&FrameHeader::new(0).to_bytes()[..]);
+        assert!(matches!(
+            FrameCodec::new().decode(&mut src),
+            Err(CodecError::ZeroSizedPacket)
+        ));
+    }
+
+    #[test]
+    fn encode_same_as_frame() {
+        let payload = generate_random_string(1234, 10, 2000);
+
+        let mut encoded = BytesMut::new();
+        FrameCodec::new().encode(payload.as_bytes(), &mut encoded).unwrap();
+
+        let frame = Frame::new(payload.as_bytes()).unwrap();
+        assert_eq!(&encoded[..], frame.as_bytes());
+    }
+
+    #[test]
+    fn encode_rejects_invalid_payload() {
+        let mut dst = BytesMut::new();
+        assert!(matches!(
+            FrameCodec::new().encode(b"", &mut dst),
+            Err(CodecError::ZeroSizedPacket)
+        ));
+        assert!(matches!(
+            FrameCodec::new().encode(vec![0; MAX_PACKET_SIZE as usize], &mut dst),
+            Err(CodecError::SizeTooBig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn framed_round_trip() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = Framed::new(client, FrameCodec::new());
+        let mut server = Framed::new(server, FrameCodec::new());
+
+        let payloads: Vec<String> = (0..20)
+            .map(|index| generate_random_string(index, 1, 1000))
+            .collect();
+
+        let sent = payloads.clone();
+        let writer = async move {
+            for payload in sent {
+                client.send(payload.into_bytes()).await.unwrap();
+            }
+        };
+
+        let reader = async {
+            let mut received = Vec::new();
+            while received.len() < payloads.len() {
+                let payload = server.next().await.unwrap().unwrap();
+                received.push(String::from_utf8(payload.to_vec()).unwrap());
+            }
+            received
+        };
+
+        let ((), received) = tokio::join!(writer, reader);
+        assert_eq!(received, payloads);
+    }
+}
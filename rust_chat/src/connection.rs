@@ -1,181 +1,903 @@
+use crate::frame_header::MAX_PACKET_SIZE;
 use crate::packet_receiver::PacketReceiver;
-use crate::packet_sender::PacketSender;
-use serde_derive::{Deserialize, Serialize};
-use serde_json;
-use std::net::TcpStream;
+use crate::outgoing_packet::Frame;
+use crate::packet_sender::{PacketSender, SendQueueLimits, SendQueueStats};
+use crate::protocol::{self, HandshakeMessage, LoginAccepted, LoginRejected, ServerMessage};
+use crate::transport::{PeerAddress, PeerCredentials, Transport};
+use log::{info, warn};
+use std::time::{Duration, Instant};
 
+type FrameProgress = crate::incoming_packet::FrameProgress;
 type IncomingPacket = crate::incoming_packet::Packet;
 type IncomingPacketError = crate::incoming_packet::PacketError;
 
-type OutgoingPacket = crate::outgoing_packet::Packet;
 type OutgoingPacketError = crate::outgoing_packet::PacketError;
 
+#[derive(Debug, Clone)]
 pub struct ConnectionInfo {
-    pub address: std::net::SocketAddr,
+    pub address: PeerAddress,
+    // Set for local connections only
+    pub credentials: Option<PeerCredentials>,
 }
 
 pub struct LoginInfo {
     pub user: String,
 }
 
+/* Lowest acceptable speed of receiving a frame.
+ * A frame may take up to grace_period regardless of its size, after that it must
+ * have arrived at bytes_per_second on average. Peers trickling a large frame in
+ * would otherwise hold its buffer forever
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MinReceiveRate {
+    pub bytes_per_second: u32,
+    pub grace_period: Duration,
+}
+
+impl MinReceiveRate {
+    // When the frame becomes too slow unless more of it arrives
+    fn deadline(&self, progress: &FrameProgress) -> Instant {
+        let allowed = Duration::from_secs_f64(
+            progress.received as f64 / self.bytes_per_second.max(1) as f64,
+        );
+        progress.started + self.grace_period.max(allowed)
+    }
+}
+
 /* Just created connection.
  * Message with handshake data has not been accepted yet
  */
-pub struct HandshakeState {
+pub struct HandshakeState<T> {
     packet: IncomingPacket,
-    stream: TcpStream,
+    stream: T,
+    message: Option<HandshakeMessage>,
+    // when the connection was accepted
+    created: Instant,
+    max_packet_size: u32,
+    // None if the address of the peer could not be read, the handshake fails then
+    connection_info: Option<ConnectionInfo>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct HandshakeMessage {
-    username: String,
-}
-
-impl HandshakeState {
-    fn new(stream: TcpStream) -> HandshakeState {
-        stream
-            .set_nonblocking(true)
-            .expect("Failed to make tcp stream non-blocking");
+impl<T> HandshakeState<T>
+where
+    T: Transport,
+{
+    fn new(stream: T, max_packet_size: u32) -> HandshakeState<T> {
+        let connection_info = match stream.peer_address() {
+            Ok(address) => Some(ConnectionInfo {
+                address,
+                credentials: stream.peer_credentials(),
+            }),
+            Err(err) => {
+                warn!("Failed to get peer address: {err}");
+                None
+            }
+        };
         HandshakeState {
-            packet: IncomingPacket::new(),
-            stream: stream,
+            packet: IncomingPacket::with_max_size(max_packet_size),
+            stream,
+            message: None,
+            created: Instant::now(),
+            max_packet_size,
+            connection_info,
         }
     }
 
-    fn receive(mut self) -> Connection {
+    fn receive(mut self) -> Connection<T> {
+        if self.message.is_some() {
+            // waiting for the server to accept or reject the handshake
+            return Connection::HandShake(self);
+        }
+
         self.packet = self.packet.advance_until_would_block(&mut self.stream);
         match self.packet {
             IncomingPacket::Received(data) => {
-                match serde_json::from_slice::<HandshakeMessage>(&data) {
+                self.packet = IncomingPacket::with_max_size(self.max_packet_size);
+                match protocol::decode::<HandshakeMessage>(&data) {
                     Ok(message) => {
-                        println!("New connection with username: {}", message.username);
-                        Connection::Established(EstablishedConnection {
-                            connection_info: ConnectionInfo {
-                                address: self.stream.peer_addr().expect("Failed to get peer address")
-                            },
-                            login_info: LoginInfo {
-                                user: message.username,
-                            },
-                            stream: self.stream,
-                            sender: PacketSender::new(),
-                            receiver: PacketReceiver::new(),
-                        })
+                        info!("New connection with username: {}", message.username);
+                        self.message = Some(message);
+                        Connection::HandShake(self)
                     }
                     Err(parse_err) => {
-                        println!("failed to parse packet as handshake message: {parse_err:#?}");
-                        Connection::Closed(ClosedConnection {
-                            reason: ConnectionClosedReason::InvalidHandshakeMessage,
-                        })
+                        warn!("failed to parse packet as handshake message: {parse_err:#?}");
+                        self.close_with_rejection(
+                            LoginRejected::permanent("Invalid handshake message".to_string()),
+                            ConnectionClosedReason::InvalidHandshakeMessage,
+                        )
                     }
                 }
             }
             IncomingPacket::Failed(err) => Connection::Closed(ClosedConnection {
                 reason: ConnectionClosedReason::PacketReceiveError(err),
+                login_info: None,
+                session_id: None,
             }),
             IncomingPacket::InProgress(state) => Connection::HandShake(HandshakeState {
                 packet: IncomingPacket::InProgress(state),
-                stream: self.stream,
+                ..self
             }),
-            IncomingPacket::Size(state) => Connection::HandShake(HandshakeState {
-                packet: IncomingPacket::Size(state),
-                stream: self.stream,
+            IncomingPacket::Header(state) => Connection::HandShake(HandshakeState {
+                packet: IncomingPacket::Header(state),
+                ..self
             }),
         }
     }
 
-    fn send(self) -> Connection {
+    fn send(self) -> Connection<T> {
         // does nothing for now
         Connection::HandShake(self)
     }
+
+    // Closes the connection if the handshake has not been received in time
+    pub fn check_deadline(mut self, handshake_timeout: Duration) -> Connection<T> {
+        if self.message.is_some() || self.created.elapsed() < handshake_timeout {
+            return Connection::HandShake(self);
+        }
+
+        info!("Handshake not received within {handshake_timeout:?}");
+        self.stream.close();
+        Connection::Closed(ClosedConnection {
+            reason: ConnectionClosedReason::HandshakeTimeout,
+            login_info: None,
+            session_id: None,
+        })
+    }
+
+    pub fn handshake_deadline(&self, handshake_timeout: Duration) -> Instant {
+        self.created + handshake_timeout
+    }
+
+    // Handshake message sent by the client, once it has been received
+    pub fn handshake_message(&self) -> Option<&HandshakeMessage> {
+        self.message.as_ref()
+    }
+
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.connection_info.as_ref()
+    }
+
+    pub fn accept(mut self, login_accepted: LoginAccepted) -> Connection<T> {
+        let message = self
+            .message
+            .expect("Attempt to accept connection before handshake message is received");
+        let connection_info = match self.connection_info {
+            Some(connection_info) => connection_info,
+            None => {
+                self.stream.close();
+                return Connection::Closed(ClosedConnection {
+                    reason: ConnectionClosedReason::StreamError,
+                    login_info: None,
+                    session_id: None,
+                });
+            }
+        };
+
+        let mut sender = PacketSender::new();
+        sender.add_to_send_queue(protocol::encode(&ServerMessage::LoginAccepted(
+            login_accepted.clone(),
+        )));
+
+        Connection::Established(EstablishedConnection {
+            connection_info,
+            login_info: LoginInfo {
+                user: message.username,
+            },
+            session_id: login_accepted.session_id,
+            stream: self.stream,
+            sender,
+            receiver: PacketReceiver::with_max_packet_size(self.max_packet_size),
+            last_activity: Instant::now(),
+            ping_sent: false,
+        })
+    }
+
+    pub fn reject(self, rejection: LoginRejected) -> Connection<T> {
+        info!("Login rejected: {}", rejection.reason);
+        let close_reason = ConnectionClosedReason::LoginRejected(rejection.reason.clone());
+        self.close_with_rejection(rejection, close_reason)
+    }
+
+    fn close_with_rejection(self, rejection: LoginRejected, close_reason: ConnectionClosedReason) -> Connection<T> {
+        let mut sender = PacketSender::new();
+        sender.add_to_send_queue(protocol::encode(&ServerMessage::LoginRejected(rejection)));
+
+        Connection::Closing(ClosingConnection {
+            stream: self.stream,
+            sender,
+            reason: close_reason,
+            login_info: None,
+            session_id: None,
+        })
+    }
 }
 
 /* Initialized and accepted connection
  */
-pub struct EstablishedConnection {
+pub struct EstablishedConnection<T> {
     connection_info: ConnectionInfo,
     login_info: LoginInfo,
-    stream: TcpStream,
+    session_id: u64,
+    stream: T,
 
     sender: PacketSender,
     receiver: PacketReceiver,
-}
-
-#[derive(Serialize, Deserialize)]
-pub enum CommandType {
-    MessageFromUser,
-}
 
-#[derive(Serialize, Deserialize)]
-pub struct MesasgeFromUser {
-    pub username: String,
-    pub text: String,
+    // when the last packet was received from the peer
+    last_activity: Instant,
+    // Ping was sent after the peer went idle and no packet arrived since
+    ping_sent: bool,
 }
 
-impl EstablishedConnection {
-    pub fn receive(mut self) -> Connection {
+impl<T> EstablishedConnection<T>
+where
+    T: Transport,
+{
+    pub fn receive(mut self) -> Connection<T> {
         match self.receiver.advance(&mut self.stream) {
             Ok(()) => Connection::Established(self),
             Err(err) => Connection::Closed(ClosedConnection {
                 reason: ConnectionClosedReason::PacketReceiveError(err),
+                login_info: Some(self.login_info),
+                session_id: Some(self.session_id),
             }),
         }
     }
 
-    pub fn send(mut self) -> Connection {
+    pub fn send(mut self) -> Connection<T> {
+        // anything still queued would only grow further behind
+        if self.sender.overflowed() {
+            let stats = self.sender.stats();
+            warn!(
+                "{} does not keep up, {} packets ({} bytes) queued",
+                self.login_info.user, stats.queued_packets, stats.queued_bytes
+            );
+            self.stream.close();
+            return Connection::Closed(ClosedConnection {
+                reason: ConnectionClosedReason::SlowConsumer,
+                login_info: Some(self.login_info),
+                session_id: Some(self.session_id),
+            });
+        }
+
         match self.sender.advance(&mut self.stream) {
             Ok(()) => Connection::Established(self),
             Err(err) => Connection::Closed(ClosedConnection {
                 reason: ConnectionClosedReason::PacketSendError(err),
+                login_info: Some(self.login_info),
+                session_id: Some(self.session_id),
             }),
         }
     }
 
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.connection_info
+    }
+
+    pub fn login_info(&self) -> &LoginInfo {
+        &self.login_info
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    // Any packet, including Pong, counts as activity of the peer
     pub fn take_message(&mut self) -> Option<Vec<u8>> {
-        self.receiver.pop_packet()
+        let message = self.receiver.pop_packet();
+        if message.is_some() {
+            self.last_activity = Instant::now();
+            self.ping_sent = false;
+        }
+        message
+    }
+
+    pub fn enqueue_message(&mut self, message: Vec<u8>) {
+        self.sender.add_to_send_queue(message);
     }
 
-    pub fn enqueue_message(&mut self, message: String) {
-        self.sender.add_to_send_queue(message.into_bytes());
+    // Frame shared with other connections, queued without copying
+    pub fn enqueue_frame(&mut self, frame: Frame) {
+        self.sender.add_frame(frame);
+    }
+
+    // Message the send queue policy may drop when the peer falls behind
+    pub fn enqueue_non_essential(&mut self, frame: Frame) {
+        self.sender.add_non_essential(frame);
+    }
+
+    // None leaves the send queue unbounded
+    pub fn limit_send_queue(&mut self, limits: Option<SendQueueLimits>) {
+        self.sender.set_limits(limits);
+    }
+
+    pub fn limit_write_size(&mut self, size: usize) {
+        self.sender.set_max_write_size(size);
+    }
+
+    pub fn send_queue_stats(&self) -> SendQueueStats {
+        self.sender.stats()
+    }
+
+    // Stops receiving and closes the connection once queued messages are sent
+    pub fn close(self, reason: ConnectionClosedReason) -> Connection<T> {
+        Connection::Closing(ClosingConnection {
+            stream: self.stream,
+            sender: self.sender,
+            reason,
+            login_info: Some(self.login_info),
+            session_id: Some(self.session_id),
+        })
+    }
+
+    /* Pings the peer once it has been silent for idle_timeout and closes the connection
+     * if nothing arrives within dead_peer_timeout of the last packet.
+     * Half-open connections never report an error, so this is the only way to notice them
+     */
+    pub fn check_heartbeat(mut self, idle_timeout: Duration, dead_peer_timeout: Duration) -> Connection<T> {
+        let silent_for = self.last_activity.elapsed();
+        if silent_for >= dead_peer_timeout {
+            info!("{} did not respond for {silent_for:?}", self.login_info.user);
+            self.stream.close();
+            return Connection::Closed(ClosedConnection {
+                reason: ConnectionClosedReason::HeartbeatTimeout(silent_for),
+                login_info: Some(self.login_info),
+                session_id: Some(self.session_id),
+            });
+        }
+
+        if silent_for >= idle_timeout && !self.ping_sent {
+            self.enqueue_message(protocol::encode(&ServerMessage::Ping));
+            self.ping_sent = true;
+        }
+        Connection::Established(self)
+    }
+
+    // When check_heartbeat has something to do next
+    pub fn next_heartbeat(&self, idle_timeout: Duration, dead_peer_timeout: Duration) -> Instant {
+        if self.ping_sent {
+            self.last_activity + dead_peer_timeout
+        } else {
+            self.last_activity + idle_timeout.min(dead_peer_timeout)
+        }
     }
 }
 
-enum ConnectionClosedReason {
+#[derive(Debug)]
+pub enum ConnectionClosedReason {
     InvalidHandshakeMessage,
+    LoginRejected(String),
     PacketSendError(OutgoingPacketError),
     PacketReceiveError(IncomingPacketError),
     StreamError,
+    // Nothing was received from the peer for this long, not even a reply to Ping
+    HeartbeatTimeout(Duration),
+    HandshakeTimeout,
+    // Frame was received slower than the minimum rate, bytes received before eviction
+    ReceiveTooSlow(usize),
+    // Peer kept exceeding the rate limits after being warned and muted
+    RateLimitExceeded,
+    // Peer did not read fast enough to keep the send queue within its limits
+    SlowConsumer,
+    // Server is shutting down
+    ServerShutdown,
+}
+
+/* Connection that is about to be closed.
+ * Packets that are still in the send queue (i.e. login rejection) are flushed first
+ */
+pub struct ClosingConnection<T> {
+    stream: T,
+    sender: PacketSender,
+    reason: ConnectionClosedReason,
+    // set if the connection was established
+    login_info: Option<LoginInfo>,
+    session_id: Option<u64>,
+}
+
+impl<T> ClosingConnection<T>
+where
+    T: Transport,
+{
+    fn send(mut self) -> Connection<T> {
+        if let Err(err) = self.sender.advance(&mut self.stream) {
+            return Connection::Closed(ClosedConnection {
+                reason: ConnectionClosedReason::PacketSendError(err),
+                login_info: self.login_info,
+                session_id: self.session_id,
+            });
+        }
+
+        if self.sender.empty() {
+            self.stream.close();
+            Connection::Closed(ClosedConnection {
+                reason: self.reason,
+                login_info: self.login_info,
+                session_id: self.session_id,
+            })
+        } else {
+            Connection::Closing(self)
+        }
+    }
 }
 
 /* Closed connection
  */
 pub struct ClosedConnection {
     reason: ConnectionClosedReason,
+    // set if the connection was established before it was closed
+    login_info: Option<LoginInfo>,
+    session_id: Option<u64>,
 }
 
-pub enum Connection {
-    HandShake(HandshakeState),
-    Established(EstablishedConnection),
+impl ClosedConnection {
+    pub fn reason(&self) -> &ConnectionClosedReason {
+        &self.reason
+    }
+
+    pub fn login_info(&self) -> Option<&LoginInfo> {
+        self.login_info.as_ref()
+    }
+
+    pub fn session_id(&self) -> Option<u64> {
+        self.session_id
+    }
+}
+
+pub enum Connection<T> {
+    HandShake(HandshakeState<T>),
+    Established(EstablishedConnection<T>),
+    Closing(ClosingConnection<T>),
     Closed(ClosedConnection),
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Connection {
-        Connection::HandShake(HandshakeState::new(stream))
+impl<T> Connection<T>
+where
+    T: Transport,
+{
+    // Stream is expected to be registered in the poll of the server already
+    pub fn new(stream: T) -> Connection<T> {
+        Connection::with_max_packet_size(stream, MAX_PACKET_SIZE)
+    }
+
+    // Peer sending a frame with payload of max_packet_size bytes or more is disconnected
+    pub fn with_max_packet_size(stream: T, max_packet_size: u32) -> Connection<T> {
+        Connection::HandShake(HandshakeState::new(stream, max_packet_size))
     }
 
-    pub fn receive(self) -> Connection {
+    pub fn receive(self) -> Connection<T> {
         match self {
             Connection::HandShake(state) => state.receive(),
             Connection::Established(state) => state.receive(),
+            Connection::Closing(state) => Connection::Closing(state),
             Connection::Closed(state) => Connection::Closed(state),
         }
     }
 
-    pub fn send(self) -> Connection {
+    /* Closes the connection if the frame being received arrives slower than the minimum rate.
+     * Only frames the peer has started count, silence between frames is up to the heartbeat
+     */
+    pub fn check_receive_rate(self, min_rate: &MinReceiveRate) -> Connection<T> {
+        let progress = match self.frame_progress() {
+            Some(progress) if Instant::now() >= min_rate.deadline(&progress) => progress,
+            _ => return self,
+        };
+
+        info!(
+            "Frame stalled after {} bytes in {:?}",
+            progress.received,
+            progress.started.elapsed()
+        );
+        let (mut stream, login_info, session_id) = match self {
+            Connection::HandShake(state) => (state.stream, None, None),
+            Connection::Established(state) => {
+                (state.stream, Some(state.login_info), Some(state.session_id))
+            }
+            _ => unreachable!("Only connections that receive have frame progress"),
+        };
+        stream.close();
+        Connection::Closed(ClosedConnection {
+            reason: ConnectionClosedReason::ReceiveTooSlow(progress.received),
+            login_info,
+            session_id,
+        })
+    }
+
+    // Closes the stream right away, whatever is still queued is lost
+    pub fn abort(self, reason: ConnectionClosedReason) -> Connection<T> {
+        let (mut stream, login_info, session_id) = match self {
+            Connection::HandShake(state) => (state.stream, None, None),
+            Connection::Established(state) => {
+                (state.stream, Some(state.login_info), Some(state.session_id))
+            }
+            Connection::Closing(state) => (state.stream, state.login_info, state.session_id),
+            Connection::Closed(state) => return Connection::Closed(state),
+        };
+        stream.close();
+        Connection::Closed(ClosedConnection {
+            reason,
+            login_info,
+            session_id,
+        })
+    }
+
+    // When check_receive_rate may close the connection unless more data arrives
+    pub fn receive_rate_deadline(&self, min_rate: &MinReceiveRate) -> Option<Instant> {
+        self.frame_progress()
+            .map(|progress| min_rate.deadline(&progress))
+    }
+
+    fn frame_progress(&self) -> Option<FrameProgress> {
+        match self {
+            // the handshake frame is complete once the message is parsed
+            Connection::HandShake(state) => state.packet.progress(),
+            Connection::Established(state) => state.receiver.frame_progress(),
+            Connection::Closing(_) | Connection::Closed(_) => None,
+        }
+    }
+
+    pub fn send(self) -> Connection<T> {
         match self {
             Connection::HandShake(state) => state.send(),
             Connection::Established(state) => state.send(),
+            Connection::Closing(state) => state.send(),
             Connection::Closed(state) => Connection::Closed(state),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_transport::MemoryTransport;
+    use crate::protocol::{ClientMessage, Credentials, MesasgeFromUser};
+
+    // Client side of the in-memory connection
+    struct TestClient {
+        transport: MemoryTransport,
+        sender: PacketSender,
+        receiver: PacketReceiver,
+    }
+
+    impl TestClient {
+        fn send(&mut self, data: Vec<u8>) {
+            self.sender.add_to_send_queue(data);
+            self.sender.advance(&mut self.transport).unwrap();
+        }
+
+        fn receive(&mut self) -> Option<ServerMessage> {
+            // server may close the stream right after the last packet, it is kept anyway
+            let _ = self.receiver.advance(&mut self.transport);
+            self.receiver
+                .pop_packet()
+                .map(|data| protocol::decode(&data).unwrap())
+        }
+    }
+
+    fn make_connection() -> (Connection<MemoryTransport>, TestClient) {
+        let (server_end, client_end) = MemoryTransport::pair();
+        let client = TestClient {
+            transport: client_end,
+            sender: PacketSender::new(),
+            receiver: PacketReceiver::new(),
+        };
+        (Connection::new(server_end), client)
+    }
+
+    fn make_login_accepted() -> LoginAccepted {
+        LoginAccepted {
+            session_id: 42,
+            server_name: "test".to_string(),
+            protocol_version: protocol::PROTOCOL_VERSION,
+            motd: None,
+        }
+    }
+
+    fn send_handshake(client: &mut TestClient, username: &str) {
+        client.send(protocol::encode(&HandshakeMessage {
+            username: username.to_string(),
+            credentials: Credentials::Guest,
+        }));
+    }
+
+    #[test]
+    fn handshake_accepted() {
+        let (connection, mut client) = make_connection();
+        send_handshake(&mut client, "user");
+
+        let state = match connection.receive() {
+            Connection::HandShake(state) => state,
+            _ => panic!("Unexpected connection state"),
+        };
+        assert_eq!(state.handshake_message().unwrap().username, "user");
+
+        let connection = state.accept(make_login_accepted()).send();
+        assert_eq!(
+            client.receive(),
+            Some(ServerMessage::LoginAccepted(make_login_accepted()))
+        );
+
+        let message = protocol::encode(&ClientMessage::MessageFromUser(MesasgeFromUser {
+            username: "user".to_string(),
+            text: "Hello".to_string(),
+            channel: protocol::DEFAULT_CHANNEL.to_string(),
+            id: None,
+            timestamp_ms: None,
+        }));
+        client.send(message.clone());
+
+        match connection.receive() {
+            Connection::Established(mut state) => {
+                assert_eq!(state.session_id(), 42);
+                assert_eq!(state.login_info().user, "user");
+                assert_eq!(state.connection_info().address, PeerAddress::Memory);
+                assert_eq!(state.take_message(), Some(message));
+            }
+            _ => panic!("Unexpected connection state"),
+        }
+    }
+
+    #[test]
+    fn handshake_rejected() {
+        let (connection, mut client) = make_connection();
+        send_handshake(&mut client, "user");
+
+        let connection = match connection.receive() {
+            Connection::HandShake(state) => {
+                state.reject(LoginRejected::permanent("Go away".to_string()))
+            }
+            _ => panic!("Unexpected connection state"),
+        };
+
+        match connection.send() {
+            Connection::Closed(state) => {
+                assert!(matches!(
+                    state.reason(),
+                    ConnectionClosedReason::LoginRejected(reason) if reason == "Go away"
+                ));
+                assert!(state.login_info().is_none());
+            }
+            _ => panic!("Unexpected connection state"),
+        }
+
+        let expected = ServerMessage::LoginRejected(LoginRejected::permanent("Go away".to_string()));
+        assert_eq!(client.receive(), Some(expected));
+    }
+
+    #[test]
+    fn invalid_handshake() {
+        let (connection, mut client) = make_connection();
+        client.send(b"not a handshake".to_vec());
+
+        match connection.receive().send() {
+            Connection::Closed(state) => assert!(matches!(
+                state.reason(),
+                ConnectionClosedReason::InvalidHandshakeMessage
+            )),
+            _ => panic!("Unexpected connection state"),
+        }
+
+        assert!(matches!(
+            client.receive(),
+            Some(ServerMessage::LoginRejected(_))
+        ));
+    }
+
+    #[test]
+    fn closed_by_peer() {
+        let (connection, mut client) = make_connection();
+        send_handshake(&mut client, "user");
+
+        let connection = match connection.receive() {
+            Connection::HandShake(state) => state.accept(make_login_accepted()),
+            _ => panic!("Unexpected connection state"),
+        };
+        drop(client);
+
+        match connection.receive() {
+            Connection::Closed(state) => {
+                assert_eq!(state.login_info().unwrap().user, "user");
+                assert_eq!(state.session_id(), Some(42));
+            }
+            _ => panic!("Unexpected connection state"),
+        }
+    }
+
+    #[test]
+    fn close_established_after_flush() {
+        let (connection, mut client) = make_connection();
+        send_handshake(&mut client, "user");
+
+        let connection = match connection.receive() {
+            Connection::HandShake(state) => state.accept(make_login_accepted()),
+            _ => panic!("Unexpected connection state"),
+        };
+        let connection = match connection {
+            Connection::Established(mut state) => {
+                state.enqueue_message(protocol::encode(&ServerMessage::Ping));
+                state.close(ConnectionClosedReason::RateLimitExceeded)
+            }
+            _ => panic!("Unexpected connection state"),
+        };
+
+        match connection.send() {
+            Connection::Closed(state) => {
+                assert!(matches!(state.reason(), ConnectionClosedReason::RateLimitExceeded));
+                assert_eq!(state.login_info().unwrap().user, "user");
+                assert_eq!(state.session_id(), Some(42));
+            }
+            _ => panic!("Unexpected connection state"),
+        }
+        assert!(matches!(client.receive(), Some(ServerMessage::LoginAccepted(_))));
+        assert_eq!(client.receive(), Some(ServerMessage::Ping));
+    }
+
+    #[test]
+    fn abort_drops_queued_packets() {
+        let (connection, mut client) = make_connection();
+        send_handshake(&mut client, "user");
+
+        let connection = match connection.receive() {
+            Connection::HandShake(state) => state.accept(make_login_accepted()),
+            _ => panic!("Unexpected connection state"),
+        };
+        match connection.abort(ConnectionClosedReason::ServerShutdown) {
+            Connection::Closed(state) => {
+                assert!(matches!(state.reason(), ConnectionClosedReason::ServerShutdown));
+                assert_eq!(state.login_info().unwrap().user, "user");
+            }
+            _ => panic!("Unexpected connection state"),
+        }
+        assert_eq!(client.receive(), None);
+    }
+
+    #[test]
+    fn slow_consumer() {
+        let (connection, mut client) = make_connection();
+        send_handshake(&mut client, "user");
+
+        let connection = match connection.receive() {
+            Connection::HandShake(state) => state.accept(make_login_accepted()),
+            _ => panic!("Unexpected connection state"),
+        };
+        let connection = match connection {
+            Connection::Established(mut state) => {
+                state.limit_send_queue(Some(SendQueueLimits {
+                    max_packets: 2,
+                    max_bytes: 1024,
+                    policy: crate::packet_sender::OverflowPolicy::Disconnect,
+                }));
+                for _ in 0..3 {
+                    state.enqueue_non_essential(
+                        protocol::encode_frame(&ServerMessage::Ping).unwrap(),
+                    );
+                }
+                Connection::Established(state)
+            }
+            _ => panic!("Unexpected connection state"),
+        };
+
+        match connection.send() {
+            Connection::Closed(state) => {
+                assert!(matches!(state.reason(), ConnectionClosedReason::SlowConsumer));
+                assert_eq!(state.session_id(), Some(42));
+            }
+            _ => panic!("Unexpected connection state"),
+        }
+    }
+
+    #[test]
+    fn heartbeat() {
+        let (connection, mut client) = make_connection();
+        send_handshake(&mut client, "user");
+        let idle_timeout = Duration::from_millis(50);
+        let dead_peer_timeout = Duration::from_millis(150);
+
+        let connection = match connection.receive() {
+            Connection::HandShake(state) => state.accept(make_login_accepted()),
+            _ => panic!("Unexpected connection state"),
+        };
+        let check = |connection: Connection<MemoryTransport>| match connection {
+            Connection::Established(state) => state.check_heartbeat(idle_timeout, dead_peer_timeout),
+            connection => connection,
+        };
+
+        let connection = check(connection).send();
+        assert!(matches!(client.receive(), Some(ServerMessage::LoginAccepted(_))));
+        assert_eq!(client.receive(), None);
+
+        std::thread::sleep(idle_timeout);
+        let connection = check(check(connection)).send();
+        assert_eq!(client.receive(), Some(ServerMessage::Ping));
+        assert_eq!(client.receive(), None);
+
+        // reply resets the timer
+        client.send(protocol::encode(&ClientMessage::Pong));
+        let connection = match connection.receive() {
+            Connection::Established(mut state) => {
+                assert!(state.take_message().is_some());
+                Connection::Established(state)
+            }
+            _ => panic!("Unexpected connection state"),
+        };
+        let connection = check(connection);
+
+        std::thread::sleep(dead_peer_timeout);
+        match check(connection) {
+            Connection::Closed(state) => {
+                assert!(matches!(
+                    state.reason(),
+                    ConnectionClosedReason::HeartbeatTimeout(silent_for) if *silent_for >= dead_peer_timeout
+                ));
+                assert_eq!(state.session_id(), Some(42));
+            }
+            _ => panic!("Unexpected connection state"),
+        }
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let (connection, mut client) = make_connection();
+        let timeout = Duration::from_millis(50);
+
+        let connection = match connection {
+            Connection::HandShake(state) => state.check_deadline(timeout),
+            _ => panic!("Unexpected connection state"),
+        };
+        std::thread::sleep(timeout);
+
+        match connection {
+            Connection::HandShake(state) => match state.check_deadline(timeout) {
+                Connection::Closed(state) => assert!(matches!(
+                    state.reason(),
+                    ConnectionClosedReason::HandshakeTimeout
+                )),
+                _ => panic!("Unexpected connection state"),
+            },
+            _ => panic!("Unexpected connection state"),
+        }
+        assert_eq!(client.receive(), None);
+    }
+
+    #[test]
+    fn evict_slow_frame() {
+        let (connection, mut client) = make_connection();
+        let min_rate = MinReceiveRate {
+            bytes_per_second: 1000,
+            grace_period: Duration::from_millis(50),
+        };
+
+        // header of a large frame followed by two bytes of it
+        let mut data = crate::frame_header::FrameHeader::new(10_000).to_bytes().to_vec();
+        data.extend_from_slice(b"{ ");
+        std::io::Write::write_all(&mut client.transport, &data).unwrap();
+
+        let connection = connection.receive();
+        assert!(connection.receive_rate_deadline(&min_rate).is_some());
+        let connection = connection.check_receive_rate(&min_rate);
+        assert!(matches!(connection, Connection::HandShake(_)));
+
+        std::thread::sleep(min_rate.grace_period);
+        match connection.check_receive_rate(&min_rate) {
+            Connection::Closed(state) => assert!(matches!(
+                state.reason(),
+                ConnectionClosedReason::ReceiveTooSlow(received) if *received > 0
+            )),
+            _ => panic!("Unexpected connection state"),
+        }
+    }
+
+    #[test]
+    fn idle_between_frames_is_not_slow() {
+        let min_rate = MinReceiveRate {
+            bytes_per_second: 1000,
+            grace_period: Duration::from_millis(10),
+        };
+        let (connection, _client) = make_connection();
+        std::thread::sleep(min_rate.grace_period);
+        let connection = connection.receive().check_receive_rate(&min_rate);
+        assert!(matches!(connection, Connection::HandShake(_)));
+        assert_eq!(connection.receive_rate_deadline(&min_rate), None);
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt::Display;
+
+/* Every packet on the wire starts with fixed size header.
+ * All multi-byte fields are big-endian (network byte order).
+ *
+ *  offset | size | field
+ *  -------+------+------------------------------------------
+ *       0 |    1 | magic, always FRAME_MAGIC
+ *       1 |    1 | version of the frame format, FRAME_VERSION
+ *       2 |    2 | flags, see FLAG_* constants
+ *       4 |    4 | payload size in bytes
+ *
+ * The payload follows the header immediately.
+ * See docs/wire_format.md for the full specification.
+ */
+
+pub const FRAME_MAGIC: u8 = 0xC4;
+pub const FRAME_VERSION: u8 = 1;
+pub const FRAME_HEADER_SIZE: usize = 8;
+
+// Receivers reject packets with payload of this size or bigger
+pub const MAX_PACKET_SIZE: u32 = 65536;
+
+// Reserved for future use, receivers reject frames with these flags for now
+pub const FLAG_COMPRESSED: u16 = 0x0001;
+pub const FLAG_ENCRYPTED: u16 = 0x0002;
+
+// Flags this implementation understands
+pub const SUPPORTED_FLAGS: u16 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub flags: u16,
+    pub length: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FrameHeaderError {
+    InvalidMagic(u8),
+    UnsupportedVersion(u8),
+    UnsupportedFlags(u16),
+}
+
+impl Display for FrameHeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMagic(magic) => write!(f, "Invalid frame magic ({magic:#04x})"),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported frame version ({version})"),
+            Self::UnsupportedFlags(flags) => write!(f, "Unsupported frame flags ({flags:#06x})"),
+        }
+    }
+}
+
+impl FrameHeader {
+    pub fn new(length: u32) -> FrameHeader {
+        FrameHeader {
+            version: FRAME_VERSION,
+            flags: 0,
+            length,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; FRAME_HEADER_SIZE] {
+        let mut bytes = [0; FRAME_HEADER_SIZE];
+        bytes[0] = FRAME_MAGIC;
+        bytes[1] = self.version;
+        bytes[2..4].copy_from_slice(&self.flags.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; FRAME_HEADER_SIZE]) -> Result<FrameHeader, FrameHeaderError> {
+        if bytes[0] != FRAME_MAGIC {
+            return Err(FrameHeaderError::InvalidMagic(bytes[0]));
+        }
+
+        let version = bytes[1];
+        if version != FRAME_VERSION {
+            return Err(FrameHeaderError::UnsupportedVersion(version));
+        }
+
+        let flags = u16::from_be_bytes([bytes[2], bytes[3]]);
+        if flags & !SUPPORTED_FLAGS != 0 {
+            return Err(FrameHeaderError::UnsupportedFlags(flags));
+        }
+
+        Ok(FrameHeader {
+            version,
+            flags,
+            length: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_layout() {
+        let bytes = FrameHeader::new(0x01020304).to_bytes();
+        assert_eq!(bytes, [FRAME_MAGIC, FRAME_VERSION, 0, 0, 0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let header = FrameHeader::new(1234);
+        assert_eq!(FrameHeader::from_bytes(&header.to_bytes()), Ok(header));
+    }
+
+    #[test]
+    fn reject_invalid_header() {
+        let valid = FrameHeader::new(10).to_bytes();
+
+        let mut bytes = valid;
+        bytes[0] = 0;
+        assert_eq!(FrameHeader::from_bytes(&bytes), Err(FrameHeaderError::InvalidMagic(0)));
+
+        let mut bytes = valid;
+        bytes[1] = FRAME_VERSION + 1;
+        assert_eq!(
+            FrameHeader::from_bytes(&bytes),
+            Err(FrameHeaderError::UnsupportedVersion(FRAME_VERSION + 1))
+        );
+
+        let mut bytes = valid;
+        bytes[3] = FLAG_COMPRESSED as u8;
+        assert_eq!(
+            FrameHeader::from_bytes(&bytes),
+            Err(FrameHeaderError::UnsupportedFlags(FLAG_COMPRESSED))
+        );
+    }
+}
@@ -1,51 +1,64 @@
 use std::io::Read;
 use std::fmt::Display;
+use std::time::Instant;
 
-const MAX_PACKET_SIZE: u32 = 65536;
+use log::{debug, trace};
 
-pub struct PacketReadingSize {
-    size: u32,
+use crate::frame_header::{FrameHeader, FrameHeaderError, FRAME_HEADER_SIZE};
+
+pub struct PacketReadingHeader {
+    header: [u8; FRAME_HEADER_SIZE],
     read: usize,
+    // when the first byte of the frame arrived
+    started: Option<Instant>,
+    // frames with payload of this size or bigger are rejected
+    max_size: u32,
 }
 
-impl PacketReadingSize {
+impl PacketReadingHeader {
     fn advance<T>(mut self, stream: &mut T) -> (Packet, usize)
     where
         T: Read,
     {
-        assert!(self.read < 4);
-        let remaining_buf = unsafe {
-            let pointer = (&mut self.size as *mut _ as *mut u8).add(self.read);
-            std::slice::from_raw_parts_mut(pointer, 4 - self.read)
-        };
-        match stream.read(remaining_buf) {
+        assert!(self.read < FRAME_HEADER_SIZE);
+        match stream.read(&mut self.header[self.read..]) {
             Ok(bytes_read) => {
                 if bytes_read == 0 {
-                    println!("zero bytes");
+                    debug!("zero bytes");
                     return (Packet::Failed(PacketError::StreamClosed), 0);
                 }
 
-                assert!(self.read + bytes_read <= 4);
+                assert!(self.read + bytes_read <= FRAME_HEADER_SIZE);
                 self.read += bytes_read;
+                let started = *self.started.get_or_insert_with(Instant::now);
 
-                if self.read == 4 {
-                    if self.size >= MAX_PACKET_SIZE {
-                        return (Packet::Failed(PacketError::SizeTooBig(self.size as usize)), bytes_read)
-                    }
-                    println!("Incoming packet size: {} bytes", self.size);
-                    (Packet::InProgress(PacketInProgress {
-                        received: 0,
-                        data: vec![0; self.size as usize],
-                    }), bytes_read)
-                } else {
-                    (Packet::Size(self), bytes_read)
+                if self.read < FRAME_HEADER_SIZE {
+                    return (Packet::Header(self), bytes_read);
+                }
+
+                let header = match FrameHeader::from_bytes(&self.header) {
+                    Ok(header) => header,
+                    Err(err) => return (Packet::Failed(PacketError::InvalidHeader(err)), bytes_read),
+                };
+
+                if header.length == 0 {
+                    return (Packet::Failed(PacketError::ZeroSizedPacket), bytes_read);
+                }
+                if header.length >= self.max_size {
+                    return (Packet::Failed(PacketError::SizeTooBig(header.length as usize)), bytes_read)
                 }
+                trace!("Incoming packet size: {} bytes", header.length);
+                (Packet::InProgress(PacketInProgress {
+                    received: 0,
+                    data: vec![0; header.length as usize],
+                    started,
+                }), bytes_read)
             }
             Err(error) => {
                 if error.kind() == std::io::ErrorKind::WouldBlock {
-                    (Packet::Size(self), 0)
+                    (Packet::Header(self), 0)
                 } else {
-                    println!("{error:#?}");
+                    debug!("{error:#?}");
                     (Packet::Failed(PacketError::StreamError), 0)
                 }
             }
@@ -58,6 +71,8 @@ pub struct PacketInProgress {
     received: usize,
     // actual data
     data: Vec<u8>,
+    // when the first byte of the frame header arrived
+    started: Instant,
 }
 
 impl PacketInProgress {
@@ -69,15 +84,10 @@ impl PacketInProgress {
             return (Packet::Received(self.data), 0);
         }
 
-        let slice = unsafe {
-            let start = self.data.as_mut_ptr().add(self.received);
-            std::slice::from_raw_parts_mut(start, self.data.len() - self.received)
-        };
-
-        match stream.read(slice) {
+        match stream.read(&mut self.data[self.received..]) {
             Ok(bytes_read) => {
                 if bytes_read == 0 {
-                    println!("zero bytes received");
+                    debug!("zero bytes received");
                     return (Packet::Failed(PacketError::StreamClosed), 0);
                 }
 
@@ -87,14 +97,14 @@ impl PacketInProgress {
                     return (Packet::InProgress(self), bytes_read);
                 }
 
-                return (Packet::Received(self.data), bytes_read);
+                (Packet::Received(self.data), bytes_read)
             }
             Err(error) => {
                 if error.kind() == std::io::ErrorKind::WouldBlock {
                     return (Packet::InProgress(self), 0);
                 }
 
-                println!("{error:#?}");
+                debug!("{error:#?}");
                 (Packet::Failed(PacketError::StreamError), 0)
             }
         }
@@ -105,7 +115,9 @@ impl PacketInProgress {
 pub enum PacketError {
     StreamError,
     StreamClosed,
+    ZeroSizedPacket,
     SizeTooBig(usize),
+    InvalidHeader(FrameHeaderError),
 }
 
 impl Display for PacketError {
@@ -113,14 +125,24 @@ impl Display for PacketError {
         match self {
             Self::StreamError => write!(f, "Stream error happened"),
             Self::StreamClosed => write!(f, "Stream closed"),
+            Self::ZeroSizedPacket => write!(f, "Zero-sized packet"),
             Self::SizeTooBig(size) => write!(f, "Packet too big ({size})"),
+            Self::InvalidHeader(err) => write!(f, "Invalid packet header: {err}"),
         }
     }
 }
 
+// Partially received frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameProgress {
+    pub started: Instant,
+    // bytes of the header and the payload received so far
+    pub received: usize,
+}
+
 pub enum Packet {
-    // In the process of reading size
-    Size(PacketReadingSize),
+    // In the process of reading frame header
+    Header(PacketReadingHeader),
 
     // Packet is in process of reading
     InProgress(PacketInProgress),
@@ -133,22 +155,46 @@ pub enum Packet {
 }
 
 impl Packet {
-    pub fn new() -> Packet {
-        Packet::Size(PacketReadingSize { size: 0, read: 0 })
+    pub fn with_max_size(max_size: u32) -> Packet {
+        Packet::Header(PacketReadingHeader {
+            header: [0; FRAME_HEADER_SIZE],
+            read: 0,
+            started: None,
+            max_size,
+        })
+    }
+
+    // None until the first byte of the frame arrives and once the frame is complete
+    pub fn progress(&self) -> Option<FrameProgress> {
+        match self {
+            Packet::Header(state) => state.started.map(|started| FrameProgress {
+                started,
+                received: state.read,
+            }),
+            Packet::InProgress(state) => Some(FrameProgress {
+                started: state.started,
+                received: FRAME_HEADER_SIZE + state.received,
+            }),
+            Packet::Received(_) | Packet::Failed(_) => None,
+        }
     }
 
+    // Steps of a single read, only tests look at them one by one
+    #[cfg(test)]
     pub fn advance<T>(self, stream: &mut T) -> Packet
     where
         T: Read,
     {
         match self {
-            Packet::Size(state) => state.advance(stream).0,
+            Packet::Header(state) => state.advance(stream).0,
             Packet::InProgress(state) => state.advance(stream).0,
             Packet::Received(_) => self,
             Packet::Failed(_) => self,
         }
     }
 
+    // Blocks on blocking streams and spins on non-blocking ones, for tests only
+    #[cfg(test)]
     pub fn advance_until_received<T>(self, stream: &mut T) -> Packet
     where
         T: Read,
@@ -157,7 +203,7 @@ impl Packet {
         let mut finished = false;
         while !finished {
             packet = match packet {
-                Packet::Size(state) => state.advance(stream).0,
+                Packet::Header(state) => state.advance(stream).0,
                 Packet::InProgress(state) => state.advance(stream).0,
                 Packet::Received(data) => {
                     finished = true;
@@ -165,7 +211,7 @@ impl Packet {
                 }
                 Packet::Failed(err) => {
                     finished = true;
-                    println!("{err:#?}");
+                    debug!("{err:#?}");
                     Packet::Failed(err)
                 }
             }
@@ -182,7 +228,7 @@ impl Packet {
         let mut finished = false;
         while !finished {
             packet = match packet {
-                Packet::Size(state) => {
+                Packet::Header(state) => {
                     let (new_state, read_count) = state.advance(stream);
                     if read_count == 0 {
                         finished = true;
@@ -202,7 +248,7 @@ impl Packet {
                 }
                 Packet::Failed(err) => {
                     finished = true;
-                    println!("{err:#?}");
+                    debug!("{err:#?}");
                     Packet::Failed(err)
                 }
             }
@@ -215,8 +261,9 @@ impl Packet {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::frame_header::{FRAME_MAGIC, MAX_PACKET_SIZE};
     use crate::test_utils::{
-        generate_random_string, make_buffer_for_packet, next_localhost_address,
+        generate_random_string, make_buffer_for_packet, next_localhost_address, ChunkedReader,
     };
     use rand::{self, Rng, SeedableRng};
     use std::io::{BufReader, Write};
@@ -227,22 +274,10 @@ mod tests {
         let buffer = make_buffer_for_packet(payload);
         let mut reader = BufReader::new(&buffer[..]);
 
-        let packet = Packet::new();
+        let packet = Packet::with_max_size(MAX_PACKET_SIZE);
         match &packet {
-            Packet::Size(state) => {
+            Packet::Header(state) => {
                 assert_eq!(state.read, 0);
-                assert_eq!(state.size, 0);
-            }
-            _ => {
-                panic!("Unexpected state of packet");
-            }
-        }
-
-        let packet = packet.advance(&mut reader);
-        match &packet {
-            Packet::Size(state) => {
-                assert_eq!(state.read, 4);
-                assert_eq!(state.size, payload.len() as u32);
             }
             _ => {
                 panic!("Unexpected state of packet");
@@ -275,12 +310,106 @@ mod tests {
         }
     }
 
+    #[test]
+    fn header_split_across_reads() {
+        let payload = "Hello, world!";
+        let chunks = make_buffer_for_packet(payload)
+            .into_iter()
+            .map(|byte| vec![byte])
+            .collect();
+        let mut reader = ChunkedReader::new(chunks);
+
+        let mut packet = Packet::with_max_size(MAX_PACKET_SIZE);
+        for expected_read in 1..FRAME_HEADER_SIZE {
+            packet = packet.advance_until_would_block(&mut reader);
+            match &packet {
+                Packet::Header(state) => assert_eq!(state.read, expected_read),
+                _ => panic!("Unexpected state of packet"),
+            }
+        }
+
+        packet = packet.advance_until_would_block(&mut reader);
+        match &packet {
+            Packet::InProgress(state) => assert_eq!(state.data.len(), payload.len()),
+            _ => panic!("Unexpected state of packet"),
+        }
+    }
+
+    #[test]
+    fn progress_counts_header_and_payload() {
+        let payload = "Hello, world!";
+        let chunks = make_buffer_for_packet(payload)
+            .into_iter()
+            .map(|byte| vec![byte])
+            .collect();
+        let mut reader = ChunkedReader::new(chunks);
+
+        let mut packet = Packet::with_max_size(MAX_PACKET_SIZE);
+        assert_eq!(packet.progress(), None);
+
+        packet = packet.advance_until_would_block(&mut reader);
+        let started = packet.progress().unwrap().started;
+        for expected_received in 2..FRAME_HEADER_SIZE + payload.len() {
+            packet = packet.advance_until_would_block(&mut reader);
+            assert_eq!(
+                packet.progress(),
+                Some(FrameProgress {
+                    started,
+                    received: expected_received
+                })
+            );
+        }
+
+        packet = packet.advance_until_would_block(&mut reader);
+        assert!(matches!(packet, Packet::Received(_)));
+        assert_eq!(packet.progress(), None);
+    }
+
+    #[test]
+    fn reject_invalid_header() {
+        let mut buffer = make_buffer_for_packet("payload");
+        buffer[0] = !FRAME_MAGIC;
+        let mut reader = BufReader::new(&buffer[..]);
+
+        match Packet::with_max_size(MAX_PACKET_SIZE).advance_until_received(&mut reader) {
+            Packet::Failed(error) => assert_eq!(
+                error,
+                PacketError::InvalidHeader(FrameHeaderError::InvalidMagic(!FRAME_MAGIC))
+            ),
+            _ => panic!("Unexpected state of packet"),
+        }
+    }
+
+    #[test]
+    fn reject_too_big_packet() {
+        let buffer = FrameHeader::new(MAX_PACKET_SIZE).to_bytes();
+        let mut reader = BufReader::new(&buffer[..]);
+
+        match Packet::with_max_size(MAX_PACKET_SIZE).advance_until_received(&mut reader) {
+            Packet::Failed(error) => {
+                assert_eq!(error, PacketError::SizeTooBig(MAX_PACKET_SIZE as usize))
+            }
+            _ => panic!("Unexpected state of packet"),
+        }
+    }
+
+    #[test]
+    fn reject_zero_sized_packet() {
+        let buffer = FrameHeader::new(0).to_bytes();
+        let mut reader = BufReader::new(&buffer[..]);
+
+        match Packet::with_max_size(MAX_PACKET_SIZE).advance_until_received(&mut reader) {
+            Packet::Failed(error) => assert_eq!(error, PacketError::ZeroSizedPacket),
+            _ => panic!("Unexpected state of packet"),
+        }
+    }
+
     #[test]
     fn advance_until_received() {
         let payload = "Example string";
-        let buffer = make_buffer_for_packet(&payload);
+        let buffer = make_buffer_for_packet(payload);
         let mut reader = BufReader::new(&buffer[..]);
-        let packet = Packet::new().advance_until_received(&mut reader);
+        let packet = Packet::with_max_size(MAX_PACKET_SIZE).advance_until_received(&mut reader);
 
         if let Packet::Received(data) = packet {
             assert_eq!(data.len(), payload.len());
@@ -298,13 +427,13 @@ mod tests {
         let payload_a = "Example string 1";
         let payload_b = "Example string 2";
         let buffer = {
-            let mut temp = make_buffer_for_packet(&payload_a);
-            temp.extend(make_buffer_for_packet(&payload_b));
+            let mut temp = make_buffer_for_packet(payload_a);
+            temp.extend(make_buffer_for_packet(payload_b));
             temp
         };
         let mut reader = BufReader::new(&buffer[..]);
 
-        if let Packet::Received(data) = Packet::new().advance_until_received(&mut reader) {
+        if let Packet::Received(data) = Packet::with_max_size(MAX_PACKET_SIZE).advance_until_received(&mut reader) {
             assert_eq!(data.len(), payload_a.len());
             assert_eq!(
                 String::from_utf8(data).expect("Failed to make a string from buffer"),
@@ -314,7 +443,7 @@ mod tests {
             panic!("Unexpected state of packet");
         }
 
-        if let Packet::Received(data) = Packet::new().advance_until_received(&mut reader) {
+        if let Packet::Received(data) = Packet::with_max_size(MAX_PACKET_SIZE).advance_until_received(&mut reader) {
             assert_eq!(data.len(), payload_b.len());
             assert_eq!(
                 String::from_utf8(data).expect("Failed to make a string from buffer"),
@@ -328,10 +457,10 @@ mod tests {
     #[test]
     fn read_packet_with_invalid_size() {
         let payload = "Example string";
-        let buffer = make_buffer_for_packet(&payload);
+        let buffer = make_buffer_for_packet(payload);
         let mut reader = BufReader::new(&buffer[0..5]);
 
-        if let Packet::Failed(error) = Packet::new().advance_until_received(&mut reader) {
+        if let Packet::Failed(error) = Packet::with_max_size(MAX_PACKET_SIZE).advance_until_received(&mut reader) {
             assert_eq!(error, PacketError::StreamClosed {});
         } else {
             panic!("Unexpected state of packet");
@@ -343,12 +472,13 @@ mod tests {
         let payload = generate_random_string(1234, 2000, 10000);
         let address = next_localhost_address();
 
+        // bind before connecting so the client can't get ahead of the listener
+        let listener = std::net::TcpListener::bind(&address).unwrap();
+
         let join_handle = {
             // clone values before they move to spawned thread
             let payload = payload.clone();
-            let address = address.clone();
             std::thread::spawn(move || {
-                let listener = std::net::TcpListener::bind(address).unwrap();
                 match listener.incoming().next().expect("") {
                     Ok(mut stream) => {
                         std::thread::sleep(std::time::Duration::from_millis(100));
@@ -384,7 +514,7 @@ mod tests {
                 .set_nonblocking(true)
                 .expect("Can't make stream nonblocking");
             let mut reader = BufReader::new(stream);
-            let packet = Packet::new().advance_until_received(&mut reader);
+            let packet = Packet::with_max_size(MAX_PACKET_SIZE).advance_until_received(&mut reader);
 
             if let Packet::Received(data) = packet {
                 assert_eq!(data.len(), payload.len());
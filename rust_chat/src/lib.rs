@@ -1,21 +1,64 @@
 mod chat_result;
+#[cfg(feature = "tokio")]
+pub mod codec;
 mod connection;
 
+pub mod frame_header;
+
 mod incoming_packet;
+mod memory_transport;
 mod packet_receiver;
 
 pub mod outgoing_packet;
 pub mod packet_sender;
+pub mod protocol;
+mod tls_transport;
+pub mod transport;
 
+#[cfg(test)]
 mod test_utils;
 
 pub use chat_result::ChatError;
 pub use chat_result::ChatResult;
 pub use chat_result::ConvertibleToChatResult;
+pub use connection::ClosedConnection;
 pub use connection::Connection;
+pub use connection::ConnectionClosedReason;
+pub use connection::HandshakeState;
+pub use memory_transport::MemoryTransport;
 pub use packet_receiver::PacketReceiver;
+pub use outgoing_packet::Frame;
+pub use packet_sender::OverflowPolicy;
 pub use packet_sender::PacketSender;
+pub use packet_sender::SendQueueLimits;
+pub use packet_sender::SendQueueStats;
 pub use connection::ConnectionInfo;
 pub use connection::LoginInfo;
-pub use connection::MesasgeFromUser;
-pub use connection::CommandType;
\ No newline at end of file
+pub use connection::MinReceiveRate;
+pub use tls_transport::load_client_config;
+pub use tls_transport::load_server_config;
+pub use tls_transport::TlsTransport;
+pub use tls_transport::TlsTrust;
+pub use transport::PeerAddress;
+pub use transport::PeerCredentials;
+pub use transport::Transport;
+pub use protocol::ChannelInfo;
+pub use protocol::ChannelList;
+pub use protocol::ChannelName;
+pub use protocol::ClientMessage;
+pub use protocol::CommandFailed;
+pub use protocol::Credentials;
+pub use protocol::DirectMessage;
+pub use protocol::DirectMessageFromUser;
+pub use protocol::FetchHistory;
+pub use protocol::HandshakeMessage;
+pub use protocol::HistoryPage;
+pub use protocol::LoginAccepted;
+pub use protocol::LoginRejected;
+pub use protocol::MesasgeFromUser;
+pub use protocol::RateLimitAction;
+pub use protocol::RateLimited;
+pub use protocol::Roster;
+pub use protocol::ServerShutdown;
+pub use protocol::ServerMessage;
+pub use protocol::UserPresence;
\ No newline at end of file
@@ -0,0 +1,177 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::transport::{PeerAddress, Transport};
+
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    closed: bool,
+    // None for unbounded pipe
+    capacity: Option<usize>,
+}
+
+type SharedPipeBuffer = Arc<Mutex<PipeBuffer>>;
+
+/* One end of in-memory duplex pipe.
+ * Behaves like a non-blocking socket: reading from empty pipe reports WouldBlock
+ * and reading from pipe closed by the other end returns zero bytes.
+ * Writing to a full bounded pipe reports WouldBlock as well.
+ */
+pub struct MemoryTransport {
+    incoming: SharedPipeBuffer,
+    outgoing: SharedPipeBuffer,
+}
+
+impl MemoryTransport {
+    // Creates two connected ends of the pipe
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        Self::make_pair(None)
+    }
+
+    // Each direction of the pipe holds at most capacity bytes not read yet,
+    // like socket buffers of a peer that does not read
+    pub fn pair_with_capacity(capacity: usize) -> (MemoryTransport, MemoryTransport) {
+        Self::make_pair(Some(capacity))
+    }
+
+    fn make_pair(capacity: Option<usize>) -> (MemoryTransport, MemoryTransport) {
+        let make_buffer = || {
+            Arc::new(Mutex::new(PipeBuffer {
+                data: VecDeque::new(),
+                closed: false,
+                capacity,
+            }))
+        };
+
+        let a_to_b = make_buffer();
+        let b_to_a = make_buffer();
+        (
+            MemoryTransport {
+                incoming: b_to_a.clone(),
+                outgoing: a_to_b.clone(),
+            },
+            MemoryTransport {
+                incoming: a_to_b,
+                outgoing: b_to_a,
+            },
+        )
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut incoming = self.incoming.lock().unwrap();
+        if incoming.data.is_empty() {
+            if incoming.closed {
+                return Ok(0);
+            }
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+
+        let count = std::cmp::min(buf.len(), incoming.data.len());
+        for (target, byte) in buf.iter_mut().zip(incoming.data.drain(..count)) {
+            *target = byte;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        if outgoing.closed {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+
+        let count = match outgoing.capacity {
+            Some(capacity) => std::cmp::min(buf.len(), capacity.saturating_sub(outgoing.data.len())),
+            None => buf.len(),
+        };
+        if count == 0 && !buf.is_empty() {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+
+        outgoing.data.extend(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn peer_address(&self) -> std::io::Result<PeerAddress> {
+        Ok(PeerAddress::Memory)
+    }
+
+    fn close(&mut self) {
+        self.incoming.lock().unwrap().closed = true;
+        self.outgoing.lock().unwrap().closed = true;
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_both_directions() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        a.write_all(b"ping").unwrap();
+        b.write_all(b"pong").unwrap();
+
+        let mut buf = [0; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn empty_pipe_would_block() {
+        let (mut a, _b) = MemoryTransport::pair();
+        let mut buf = [0; 4];
+        assert_eq!(
+            a.read(&mut buf).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn full_pipe_would_block() {
+        let (mut a, mut b) = MemoryTransport::pair_with_capacity(4);
+        assert_eq!(a.write(b"pingpong").unwrap(), 4);
+        assert_eq!(
+            a.write(b"pong").unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+
+        let mut buf = [0; 2];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(a.write(b"pong").unwrap(), 2);
+    }
+
+    #[test]
+    fn closed_pipe() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        a.write_all(b"data").unwrap();
+        drop(a);
+
+        // data written before close is still delivered
+        let mut buf = [0; 8];
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            b.write(b"data").unwrap_err().kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+    }
+}
@@ -1,48 +1,189 @@
 use std::io::Read;
 use std::collections::VecDeque;
 
+use crate::frame_header::MAX_PACKET_SIZE;
+use crate::incoming_packet::FrameProgress;
 use crate::incoming_packet::Packet;
 use crate::incoming_packet::PacketError;
 
 pub struct PacketReceiver {
     received: VecDeque<Vec<u8>>,
     current: Option<Packet>,
+    max_packet_size: u32,
+}
+
+impl Default for PacketReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PacketReceiver {
     pub fn new() -> PacketReceiver {
+        PacketReceiver::with_max_packet_size(MAX_PACKET_SIZE)
+    }
+
+    // Stream fails once a frame with payload of max_packet_size bytes or more arrives
+    pub fn with_max_packet_size(max_packet_size: u32) -> PacketReceiver {
         PacketReceiver {
             received: VecDeque::new(),
-            current: Some(Packet::new()),
+            current: Some(Packet::with_max_size(max_packet_size)),
+            max_packet_size,
         }
     }
 
+    // Reads every complete packet available in the stream without blocking
     pub fn advance<Stream>(&mut self, stream: &mut Stream) -> std::result::Result<(), PacketError>
     where
         Stream: Read,
     {
-        let mut packet = self.current.take().unwrap().advance_until_would_block(stream);
-        match packet {
-            Packet::Size(state) => {
-                packet = Packet::Size(state)
+        loop {
+            let packet = self.current.take().unwrap().advance_until_would_block(stream);
+            match packet {
+                Packet::Received(data) => {
+                    self.received.push_back(data);
+                    self.current = Some(Packet::with_max_size(self.max_packet_size));
+                }
+                Packet::Failed(err) => {
+                    // the stream is unusable, but calling advance again must not panic
+                    self.current = Some(Packet::with_max_size(self.max_packet_size));
+                    return Err(err);
+                }
+                packet => {
+                    // stream would block in the middle of the packet
+                    self.current = Some(packet);
+                    return Ok(());
+                }
             }
-            Packet::InProgress(state) => {
-                packet = Packet::InProgress(state)
-            },
-            Packet::Received(data) => {
-                self.received.push_back(data);
-                packet = Packet::new();
-            },
-            Packet::Failed(err) => {
-                return Err(err);
-            },
         }
+    }
 
-        self.current = Some(packet);
-        Ok(())
+    // Frame that has been started but not completed yet
+    pub fn frame_progress(&self) -> Option<FrameProgress> {
+        self.current.as_ref().and_then(Packet::progress)
     }
 
+    // Packets are returned in the order they were received
     pub fn pop_packet(&mut self) -> Option<Vec<u8>> {
-        self.received.pop_back()
+        self.received.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{generate_random_string, make_buffer_for_packet, ChunkedReader};
+    use rand::{Rng, SeedableRng};
+
+    fn make_payloads(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|index| format!("{index}:{}", generate_random_string(index as u64, 1, 100)))
+            .collect()
+    }
+
+    fn make_stream_buffer(payloads: &[String]) -> Vec<u8> {
+        payloads
+            .iter()
+            .flat_map(|payload| make_buffer_for_packet(payload))
+            .collect()
+    }
+
+    fn receive_all(receiver: &mut PacketReceiver, reader: &mut ChunkedReader) -> Vec<String> {
+        let mut received = Vec::new();
+        while !reader.is_empty() {
+            receiver.advance(reader).expect("Failed to receive packets");
+            while let Some(packet) = receiver.pop_packet() {
+                received.push(String::from_utf8(packet).unwrap());
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn coalesced_packets_in_one_read() {
+        let payloads = make_payloads(10);
+        let mut reader = ChunkedReader::new(vec![make_stream_buffer(&payloads)]);
+        let mut receiver = PacketReceiver::new();
+
+        // all packets are available after a single call
+        receiver.advance(&mut reader).unwrap();
+        for payload in &payloads {
+            assert_eq!(receiver.pop_packet().unwrap(), payload.as_bytes());
+        }
+        assert!(receiver.pop_packet().is_none());
+    }
+
+    #[test]
+    fn fragmented_byte_by_byte() {
+        let payloads = make_payloads(5);
+        let chunks = make_stream_buffer(&payloads)
+            .into_iter()
+            .map(|byte| vec![byte])
+            .collect();
+        let mut reader = ChunkedReader::new(chunks);
+        let mut receiver = PacketReceiver::new();
+
+        assert_eq!(receive_all(&mut receiver, &mut reader), payloads);
+    }
+
+    #[test]
+    fn fragmented_and_coalesced_at_random() {
+        let payloads = make_payloads(100);
+        let buffer = make_stream_buffer(&payloads);
+
+        // chunk boundaries fall anywhere: inside size, inside payload, across packets
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1234);
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < buffer.len() {
+            let end = std::cmp::min(buffer.len(), start + rng.gen_range(1..300));
+            chunks.push(buffer[start..end].to_vec());
+            start = end;
+        }
+
+        let mut reader = ChunkedReader::new(chunks);
+        let mut receiver = PacketReceiver::new();
+        assert_eq!(receive_all(&mut receiver, &mut reader), payloads);
+    }
+
+    #[test]
+    fn partial_packet_is_kept_between_calls() {
+        let payloads = make_payloads(2);
+        let buffer = make_stream_buffer(&payloads);
+        let split = buffer.len() - 1;
+        let mut reader =
+            ChunkedReader::new(vec![buffer[..split].to_vec(), buffer[split..].to_vec()]);
+        let mut receiver = PacketReceiver::new();
+
+        receiver.advance(&mut reader).unwrap();
+        assert_eq!(receiver.pop_packet().unwrap(), payloads[0].as_bytes());
+        assert!(receiver.pop_packet().is_none());
+
+        receiver.advance(&mut reader).unwrap();
+        assert_eq!(receiver.pop_packet().unwrap(), payloads[1].as_bytes());
+        assert!(receiver.pop_packet().is_none());
+    }
+
+    #[test]
+    fn packets_received_before_stream_closed_are_kept() {
+        let payloads = make_payloads(3);
+        let mut reader = ChunkedReader::new(vec![make_stream_buffer(&payloads)]).closed_at_end();
+        let mut receiver = PacketReceiver::new();
+
+        assert_eq!(receiver.advance(&mut reader), Err(PacketError::StreamClosed));
+        for payload in &payloads {
+            assert_eq!(receiver.pop_packet().unwrap(), payload.as_bytes());
+        }
+    }
+
+    #[test]
+    fn packets_over_custom_limit_fail() {
+        let payloads = vec!["a".repeat(10), "b".repeat(20)];
+        let mut reader = ChunkedReader::new(vec![make_stream_buffer(&payloads)]);
+        let mut receiver = PacketReceiver::with_max_packet_size(20);
+
+        assert_eq!(receiver.advance(&mut reader), Err(PacketError::SizeTooBig(20)));
+        assert_eq!(receiver.pop_packet().unwrap(), payloads[0].as_bytes());
+        assert!(receiver.pop_packet().is_none());
     }
 }
@@ -1,12 +1,73 @@
-use std::io::Write;
+use std::io::{IoSlice, Write};
 use std::collections::VecDeque;
 
-use crate::outgoing_packet::Packet;
+use crate::outgoing_packet::Frame;
 use crate::outgoing_packet::PacketError;
 
+// Frames handed to the stream in one vectored write at most
+const MAX_WRITE_FRAMES: usize = 64;
+
+// Bytes handed to the stream in one write unless set otherwise
+pub const DEFAULT_MAX_WRITE_SIZE: usize = 64 * 1024;
+
+// What happens when a packet is queued beyond the limits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    // oldest queued packets are dropped to make room
+    DropOldest,
+    // queued packets that are not essential are dropped, oldest first.
+    // The queue overflows if that does not make enough room
+    DropNonEssential,
+    // the queue overflows right away
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SendQueueLimits {
+    pub max_packets: usize,
+    pub max_bytes: usize,
+    pub policy: OverflowPolicy,
+}
+
+// Snapshot of the queue for monitoring
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SendQueueStats {
+    pub queued_packets: usize,
+    pub queued_bytes: usize,
+    pub dropped_packets: u64,
+}
+
+struct QueuedPacket {
+    frame: Frame,
+    // essential packets are kept by DropNonEssential
+    essential: bool,
+}
+
+// Frame partially written to the stream, it can no longer be dropped
+struct FrameInProgress {
+    frame: Frame,
+    sent: usize,
+}
+
 pub struct PacketSender {
-    send_queue: VecDeque<Vec<u8>>,
-    current: Option<Packet>,
+    send_queue: VecDeque<QueuedPacket>,
+    current: Option<FrameInProgress>,
+    // packet that could not be framed, reported by the next advance
+    failed: Option<PacketError>,
+    // None for unbounded queue
+    limits: Option<SendQueueLimits>,
+    // payload bytes of queued packets, the one being sent is not counted
+    queued_bytes: usize,
+    dropped_packets: u64,
+    // packet could not be queued within the limits, the peer does not keep up
+    overflowed: bool,
+    max_write_size: usize,
+}
+
+impl Default for PacketSender {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PacketSender {
@@ -14,39 +75,416 @@ impl PacketSender {
         PacketSender {
             send_queue: VecDeque::new(),
             current: None,
+            failed: None,
+            limits: None,
+            queued_bytes: 0,
+            dropped_packets: 0,
+            overflowed: false,
+            max_write_size: DEFAULT_MAX_WRITE_SIZE,
+        }
+    }
+
+    pub fn with_limits(limits: SendQueueLimits) -> PacketSender {
+        PacketSender {
+            limits: Some(limits),
+            ..PacketSender::new()
         }
     }
 
+    pub fn set_limits(&mut self, limits: Option<SendQueueLimits>) {
+        self.limits = limits;
+        self.enforce_limits();
+    }
+
+    // Smaller writes let other connections have their turn sooner, larger ones need fewer calls
+    pub fn set_max_write_size(&mut self, size: usize) {
+        assert!(size > 0, "Max write size must not be zero");
+        self.max_write_size = size;
+    }
+
+    // Sends queued packets until the queue is empty or the stream would block.
+    // Several frames are written at once with vectored writes.
+    // Once the queue is empty the stream is flushed so that transports buffering
+    // data internally (i.e. TLS) get it out as well
     pub fn advance<Stream>(&mut self, stream: &mut Stream) -> std::result::Result<(), PacketError>
     where
         Stream: Write,
     {
-        let packet = match self.current.take() {
-            Some(packet) => packet,
-            None => {
-                match self.send_queue.pop_front() {
-                    Some(data) => Packet::new(&data),
-                    None => return Ok(())
+        if let Some(err) = self.failed.take() {
+            return Err(err);
+        }
+
+        loop {
+            if self.current.is_none() {
+                self.current = self.pop_front();
+            }
+            let Some(current) = &self.current else {
+                return Self::flush(stream);
+            };
+
+            let write_result = {
+                let mut slices = Vec::with_capacity(MAX_WRITE_FRAMES.min(self.send_queue.len() + 1));
+                let mut budget = self.max_write_size;
+                let frames = std::iter::once(&current.frame.as_bytes()[current.sent..]).chain(
+                    self.send_queue
+                        .iter()
+                        .take(MAX_WRITE_FRAMES - 1)
+                        .map(|queued| queued.frame.as_bytes()),
+                );
+                for bytes in frames {
+                    if budget == 0 {
+                        break;
+                    }
+                    let count = bytes.len().min(budget);
+                    slices.push(IoSlice::new(&bytes[..count]));
+                    budget -= count;
                 }
-            },
-        };
+                stream.write_vectored(&slices)
+            };
+
+            match write_result {
+                Ok(0) => return Ok(()),
+                Ok(written) => self.consume(written),
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(PacketError::StreamError(err)),
+            }
+        }
+    }
 
-        let packet = packet.advance_until_would_block(stream);
-        match packet {
-            Packet::InProgress(in_progress) => {
-                self.current = Some(Packet::InProgress(in_progress));
-                Ok(())
+    // Moves past bytes written to the stream, they may span several frames
+    fn consume(&mut self, mut written: usize) {
+        while written > 0 {
+            let current = self.current.as_mut().expect("Written more than was queued");
+            let remaining = current.frame.as_bytes().len() - current.sent;
+            if written < remaining {
+                current.sent += written;
+                return;
             }
-            Packet::Sent => Ok(()),
-            Packet::Failed(err) => Err(err),
+
+            written -= remaining;
+            self.current = self.pop_front();
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<FrameInProgress> {
+        let queued = self.send_queue.pop_front()?;
+        self.queued_bytes -= queued.frame.payload().len();
+        Some(FrameInProgress {
+            frame: queued.frame,
+            sent: 0,
+        })
+    }
+
+    fn flush<Stream>(stream: &mut Stream) -> std::result::Result<(), PacketError>
+    where
+        Stream: Write,
+    {
+        match stream.flush() {
+            Err(err) if err.kind() != std::io::ErrorKind::WouldBlock => {
+                Err(PacketError::StreamError(err))
+            }
+            _ => Ok(()),
         }
     }
 
     pub fn add_to_send_queue(&mut self, data: Vec<u8>) {
-        self.send_queue.push_back(data);
+        match Frame::new(&data) {
+            Ok(frame) => self.push(frame, true),
+            Err(err) => self.failed = Some(err),
+        }
+    }
+
+    // Frame shared with other senders, i.e. a broadcast message
+    pub fn add_frame(&mut self, frame: Frame) {
+        self.push(frame, true);
+    }
+
+    // Frame that may be dropped by DropNonEssential policy, i.e. broadcast events
+    pub fn add_non_essential(&mut self, frame: Frame) {
+        self.push(frame, false);
+    }
+
+    fn push(&mut self, frame: Frame, essential: bool) {
+        self.queued_bytes += frame.payload().len();
+        self.send_queue.push_back(QueuedPacket { frame, essential });
+        self.enforce_limits();
+    }
+
+    fn over_limits(&self) -> bool {
+        self.limits.is_some_and(|limits| {
+            self.send_queue.len() > limits.max_packets || self.queued_bytes > limits.max_bytes
+        })
+    }
+
+    fn enforce_limits(&mut self) {
+        let policy = match self.limits {
+            Some(limits) if self.over_limits() => limits.policy,
+            _ => return,
+        };
+
+        match policy {
+            OverflowPolicy::DropOldest => {
+                // the newest packet is kept even if it does not fit alone
+                while self.over_limits() && self.send_queue.len() > 1 {
+                    self.drop_at(0);
+                }
+            }
+            OverflowPolicy::DropNonEssential => {
+                let mut index = 0;
+                while self.over_limits() && index < self.send_queue.len() {
+                    if self.send_queue[index].essential {
+                        index += 1;
+                    } else {
+                        self.drop_at(index);
+                    }
+                }
+            }
+            OverflowPolicy::Disconnect => {}
+        }
+
+        if self.over_limits() {
+            self.overflowed = true;
+        }
+    }
+
+    fn drop_at(&mut self, index: usize) {
+        if let Some(dropped) = self.send_queue.remove(index) {
+            self.queued_bytes -= dropped.frame.payload().len();
+            self.dropped_packets += 1;
+        }
     }
 
     pub fn empty(&self) -> bool {
-        self.current.is_none() && self.send_queue.len() == 0
+        self.current.is_none() && self.send_queue.is_empty() && self.failed.is_none()
+    }
+
+    pub fn stats(&self) -> SendQueueStats {
+        SendQueueStats {
+            queued_packets: self.queued_packets(),
+            queued_bytes: self.queued_bytes(),
+            dropped_packets: self.dropped_packets(),
+        }
+    }
+
+    // Packets waiting to be sent, the one being sent is not counted
+    pub fn queued_packets(&self) -> usize {
+        self.send_queue.len()
+    }
+
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    // Packets dropped to stay within the limits since the sender was created
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped_packets
+    }
+
+    // Whether a packet could not be queued within the limits
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_header::MAX_PACKET_SIZE;
+    use crate::test_utils::{generate_random_string, next_localhost_address};
+    use std::io::Read;
+
+    fn limited(policy: OverflowPolicy) -> PacketSender {
+        PacketSender::with_limits(SendQueueLimits {
+            max_packets: 3,
+            max_bytes: 10,
+            policy,
+        })
+    }
+
+    fn queued(sender: &PacketSender) -> Vec<&[u8]> {
+        sender.send_queue.iter().map(|queued| queued.frame.payload()).collect()
+    }
+
+    fn frame(payload: &[u8]) -> Frame {
+        Frame::new(payload).unwrap()
+    }
+
+    #[test]
+    fn unbounded_by_default() {
+        let mut sender = PacketSender::new();
+        for _ in 0..1000 {
+            sender.add_non_essential(frame(&[0; 100]));
+        }
+        assert_eq!(sender.queued_packets(), 1000);
+        assert_eq!(sender.queued_bytes(), 100_000);
+        assert!(!sender.overflowed());
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut sender = limited(OverflowPolicy::DropOldest);
+        for data in [b"a", b"b", b"c", b"d"] {
+            sender.add_to_send_queue(data.to_vec());
+        }
+        assert_eq!(queued(&sender), [b"b", b"c", b"d"]);
+
+        sender.add_to_send_queue(b"0123456789".to_vec());
+        assert_eq!(queued(&sender), [b"0123456789"]);
+        assert_eq!(sender.queued_bytes(), 10);
+        assert_eq!(sender.dropped_packets(), 4);
+        assert!(!sender.overflowed());
+    }
+
+    #[test]
+    fn drop_non_essential() {
+        let mut sender = limited(OverflowPolicy::DropNonEssential);
+        sender.add_to_send_queue(b"a".to_vec());
+        sender.add_non_essential(frame(b"b"));
+        sender.add_to_send_queue(b"c".to_vec());
+        sender.add_non_essential(frame(b"d"));
+        assert_eq!(queued(&sender), [b"a", b"c", b"d"]);
+        assert!(!sender.overflowed());
+
+        sender.add_to_send_queue(b"e".to_vec());
+        assert_eq!(queued(&sender), [b"a", b"c", b"e"]);
+        sender.add_to_send_queue(b"f".to_vec());
+        assert!(sender.overflowed());
+        assert_eq!(sender.dropped_packets(), 2);
+    }
+
+    #[test]
+    fn disconnect_on_overflow() {
+        let mut sender = limited(OverflowPolicy::Disconnect);
+        sender.add_non_essential(frame(b"0123456789"));
+        assert!(!sender.overflowed());
+        sender.add_non_essential(frame(b"a"));
+        assert!(sender.overflowed());
+        assert_eq!(sender.dropped_packets(), 0);
+    }
+
+    #[test]
+    fn sent_packets_leave_the_queue() {
+        let mut sender = limited(OverflowPolicy::Disconnect);
+        sender.add_to_send_queue(b"abc".to_vec());
+        sender.add_to_send_queue(b"de".to_vec());
+        let mut stream = Vec::new();
+        sender.advance(&mut stream).unwrap();
+        assert!(sender.empty());
+        assert_eq!(sender.queued_bytes(), 0);
+        assert_eq!(sender.queued_packets(), 0);
+    }
+
+    // Accepts at most given number of bytes per write, of any number of slices
+    struct ChunkedStream {
+        data: Vec<u8>,
+        chunk: usize,
+        writes: usize,
+    }
+
+    impl Write for ChunkedStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+            self.writes += 1;
+            let mut written = 0;
+            for buf in bufs {
+                let count = buf.len().min(self.chunk - written);
+                self.data.extend_from_slice(&buf[..count]);
+                written += count;
+            }
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn vectored_writes_span_frames() {
+        let shared = frame(b"shared");
+        let mut sender = PacketSender::new();
+        sender.add_to_send_queue(b"first".to_vec());
+        sender.add_frame(shared.clone());
+        sender.add_non_essential(shared.clone());
+
+        let mut stream = ChunkedStream {
+            data: Vec::new(),
+            chunk: 10,
+            writes: 0,
+        };
+        sender.advance(&mut stream).unwrap();
+        assert!(sender.empty());
+
+        let mut expected = frame(b"first").as_bytes().to_vec();
+        expected.extend_from_slice(shared.as_bytes());
+        expected.extend_from_slice(shared.as_bytes());
+        assert_eq!(stream.data, expected);
+        assert_eq!(stream.writes, expected.len().div_ceil(10));
+    }
+
+    #[test]
+    fn writes_are_limited() {
+        let mut sender = PacketSender::new();
+        sender.set_max_write_size(4);
+        sender.add_to_send_queue(b"first".to_vec());
+        sender.add_to_send_queue(b"second".to_vec());
+
+        let mut stream = ChunkedStream {
+            data: Vec::new(),
+            chunk: usize::MAX,
+            writes: 0,
+        };
+        sender.advance(&mut stream).unwrap();
+        assert!(sender.empty());
+
+        let mut expected = frame(b"first").as_bytes().to_vec();
+        expected.extend_from_slice(frame(b"second").as_bytes());
+        assert_eq!(stream.data, expected);
+        assert_eq!(stream.writes, expected.len().div_ceil(4));
+    }
+
+    #[test]
+    fn big_frames_over_tcp() {
+        let payload = generate_random_string(1234, 32 * 1024, MAX_PACKET_SIZE as usize - 1);
+        let address = next_localhost_address();
+
+        // bind before connecting so the client can't get ahead of the listener
+        let listener = std::net::TcpListener::bind(&address).unwrap();
+        let join_handle = {
+            let expected = [frame(payload.as_bytes()).as_bytes(), frame(b"after").as_bytes()].concat();
+            std::thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut data = Vec::new();
+                stream.read_to_end(&mut data).expect("Failed to read from stream");
+                assert_eq!(data, expected);
+            })
+        };
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.set_nonblocking(true).expect("Can't make stream nonblocking");
+        let mut sender = PacketSender::new();
+        // every frame takes several writes
+        sender.set_max_write_size(4 * 1024);
+        sender.add_to_send_queue(payload.into_bytes());
+        sender.add_to_send_queue(b"after".to_vec());
+        while !sender.empty() {
+            sender.advance(&mut stream).unwrap();
+        }
+        drop(stream);
+
+        join_handle.join().unwrap()
+    }
+
+    #[test]
+    fn empty_packet_fails_on_advance() {
+        let mut sender = PacketSender::new();
+        sender.add_to_send_queue(Vec::new());
+        assert!(matches!(
+            sender.advance(&mut Vec::new()),
+            Err(PacketError::ZeroSizedPacket)
+        ));
     }
 }
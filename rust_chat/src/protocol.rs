@@ -0,0 +1,438 @@
+use crate::chat_result::{ChatResult, ConvertibleToChatResult};
+use crate::outgoing_packet::{Frame, PacketError};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+
+/* Every packet payload after the handshake is a json object of the form
+ * { "Type": "<variant name>", "Data": <variant payload> }
+ * Both sides go through encode/decode below so the framing of commands
+ * lives in one place.
+ */
+
+pub const PROTOCOL_VERSION: u32 = 10;
+
+// Channel every user joins on login
+pub const DEFAULT_CHANNEL: &str = "general";
+
+fn default_channel() -> String {
+    DEFAULT_CHANNEL.to_string()
+}
+
+// Proof of identity sent with the handshake
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "Type", content = "Data")]
+pub enum Credentials {
+    // Unregistered name, allowed only if the server accepts guests
+    #[default]
+    Guest,
+    // Password of registered account
+    Password(String),
+    // Creates account with the password and logs in
+    Register(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HandshakeMessage {
+    pub username: String,
+    #[serde(default)]
+    pub credentials: Credentials,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoginAccepted {
+    pub session_id: u64,
+    pub server_name: String,
+    pub protocol_version: u32,
+    // Message of the day the client shows after login
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoginRejected {
+    pub reason: String,
+    // The same login may succeed later, i.e. the username is held by a session the server
+    // has not noticed is gone yet. Missing from older servers, their rejections are final
+    #[serde(default)]
+    pub retryable: bool,
+}
+
+impl LoginRejected {
+    pub fn permanent(reason: String) -> LoginRejected {
+        LoginRejected {
+            reason,
+            retryable: false,
+        }
+    }
+
+    pub fn temporary(reason: String) -> LoginRejected {
+        LoginRejected {
+            reason,
+            retryable: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MesasgeFromUser {
+    pub username: String,
+    pub text: String,
+    #[serde(default = "default_channel")]
+    pub channel: String,
+    // Position in the server history, set by the server if the history is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    // Milliseconds since unix epoch when the server received the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_ms: Option<i64>,
+}
+
+// Requests messages older than before_id, or the newest ones if before_id is None
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FetchHistory {
+    #[serde(default = "default_channel")]
+    pub channel: String,
+    pub before_id: Option<u64>,
+    pub limit: u32,
+}
+
+// Messages from the history ordered from oldest to newest
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryPage {
+    pub channel: String,
+    pub messages: Vec<MesasgeFromUser>,
+    // Whether there are even older messages
+    pub has_more: bool,
+}
+
+// Names the channel a command or notification is about
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChannelName {
+    pub channel: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChannelInfo {
+    pub name: String,
+    pub members: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChannelList {
+    pub channels: Vec<ChannelInfo>,
+}
+
+// Private message to one user, not stored in the history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DirectMessage {
+    pub to: String,
+    pub text: String,
+}
+
+// Direct message as delivered by the server, both to the recipient and back to the sender
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DirectMessageFromUser {
+    pub from: String,
+    pub to: String,
+    pub text: String,
+    // milliseconds since unix epoch, set by the server
+    pub timestamp_ms: i64,
+}
+
+// User that logged in or out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserPresence {
+    pub username: String,
+}
+
+// Everyone logged in, including the receiving user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Roster {
+    pub users: Vec<String>,
+}
+
+// Response of the server to a client sending too much
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RateLimitAction {
+    Warning,
+    // chat messages are dropped until the mute expires
+    Muted,
+    // the connection is closed after this message
+    Disconnected,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RateLimited {
+    pub action: RateLimitAction,
+    // remaining duration of the mute, set for Muted only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub muted_for_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerShutdown {
+    pub reason: String,
+    // how long clients should wait before reconnecting, if the server expects to be back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconnect_after_ms: Option<u64>,
+}
+
+// Reply to a command the server could not carry out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommandFailed {
+    pub reason: String,
+}
+
+// Messages sent by the client to the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "Type", content = "Data")]
+pub enum ClientMessage {
+    // Sent to members of the channel the client has joined
+    MessageFromUser(MesasgeFromUser),
+    FetchHistory(FetchHistory),
+    DirectMessage(DirectMessage),
+
+    // Creates a channel and joins it
+    CreateChannel(ChannelName),
+    JoinChannel(ChannelName),
+    LeaveChannel(ChannelName),
+    ListChannels,
+
+    // Heartbeat, sent by either side when the other one has been silent for a while
+    Ping,
+    Pong,
+}
+
+// Messages sent by the server to the client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "Type", content = "Data")]
+pub enum ServerMessage {
+    // Reply to the handshake, always the first message sent by the server
+    LoginAccepted(LoginAccepted),
+    LoginRejected(LoginRejected),
+
+    MessageFromUser(MesasgeFromUser),
+    // Sent after joining a channel with the latest messages and as reply to FetchHistory
+    History(HistoryPage),
+    DirectMessage(DirectMessageFromUser),
+
+    // The connection is a member of the channel now, sent on login for the default channel too
+    ChannelJoined(ChannelName),
+    ChannelLeft(ChannelName),
+    ChannelList(ChannelList),
+    CommandFailed(CommandFailed),
+
+    // Sent after login, later changes arrive as UserJoined and UserLeft
+    Roster(Roster),
+    UserJoined(UserPresence),
+    UserLeft(UserPresence),
+
+    Ping,
+    Pong,
+
+    // Client exceeded its rate limits, repeated violations escalate the action
+    RateLimited(RateLimited),
+    // Server is going down, the connection is closed after this message
+    ServerShutdown(ServerShutdown),
+}
+
+pub fn encode<T>(message: &T) -> Vec<u8>
+where
+    T: serde::Serialize,
+{
+    serde_json::to_vec(message).expect("Protocol messages are always serializable")
+}
+
+// Message framed once, for sending the same message to many connections.
+// Fails if the encoded message is too big for a frame
+pub fn encode_frame<T>(message: &T) -> Result<Frame, PacketError>
+where
+    T: serde::Serialize,
+{
+    Frame::new(&encode(message))
+}
+
+pub fn decode<T>(data: &[u8]) -> ChatResult<T>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_slice::<T>(data).to_chat_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_without_credentials_is_guest() {
+        let decoded = decode::<HandshakeMessage>(br#"{"username":"user"}"#).unwrap();
+        assert_eq!(decoded.credentials, Credentials::Guest);
+
+        let message = HandshakeMessage {
+            username: "user".to_string(),
+            credentials: Credentials::Register("password".to_string()),
+        };
+        assert_eq!(decode::<HandshakeMessage>(&encode(&message)).unwrap(), message);
+    }
+
+    #[test]
+    fn client_message_round_trip() {
+        let message = ClientMessage::MessageFromUser(MesasgeFromUser {
+            username: "user".to_string(),
+            text: "Hello, world!".to_string(),
+            channel: DEFAULT_CHANNEL.to_string(),
+            id: None,
+            timestamp_ms: None,
+        });
+
+        let decoded = decode::<ClientMessage>(&encode(&message)).unwrap();
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn message_has_type_and_data_keys() {
+        let message = ServerMessage::MessageFromUser(MesasgeFromUser {
+            username: "user".to_string(),
+            text: "text".to_string(),
+            channel: DEFAULT_CHANNEL.to_string(),
+            id: None,
+            timestamp_ms: None,
+        });
+
+        let json = serde_json::from_slice::<serde_json::Value>(&encode(&message)).unwrap();
+        assert_eq!(json["Type"], "MessageFromUser");
+        assert_eq!(json["Data"]["username"], "user");
+        assert_eq!(json["Data"]["text"], "text");
+    }
+
+    #[test]
+    fn login_rejected_round_trip() {
+        let message = ServerMessage::LoginRejected(LoginRejected::temporary(
+            "Username is taken".to_string(),
+        ));
+
+        let json = serde_json::from_slice::<serde_json::Value>(&encode(&message)).unwrap();
+        assert_eq!(json["Type"], "LoginRejected");
+        assert_eq!(json["Data"]["reason"], "Username is taken");
+        assert_eq!(json["Data"]["retryable"], true);
+
+        let decoded = decode::<ServerMessage>(&encode(&message)).unwrap();
+        assert_eq!(decoded, message);
+
+        let old_format = br#"{ "Type": "LoginRejected", "Data": { "reason": "Go away" } }"#;
+        assert_eq!(
+            decode::<ServerMessage>(old_format).unwrap(),
+            ServerMessage::LoginRejected(LoginRejected::permanent("Go away".to_string()))
+        );
+    }
+
+    #[test]
+    fn history_page_round_trip() {
+        let message = ServerMessage::History(HistoryPage {
+            channel: DEFAULT_CHANNEL.to_string(),
+            messages: vec![MesasgeFromUser {
+                username: "user".to_string(),
+                text: "text".to_string(),
+                channel: DEFAULT_CHANNEL.to_string(),
+                id: Some(42),
+                timestamp_ms: Some(1_700_000_000_000),
+            }],
+            has_more: true,
+        });
+        assert_eq!(decode::<ServerMessage>(&encode(&message)).unwrap(), message);
+
+        let request = ClientMessage::FetchHistory(FetchHistory {
+            channel: "random".to_string(),
+            before_id: Some(42),
+            limit: 50,
+        });
+        assert_eq!(decode::<ClientMessage>(&encode(&request)).unwrap(), request);
+    }
+
+    #[test]
+    fn channel_commands() {
+        let data = br#"{ "Type": "ListChannels" }"#;
+        assert_eq!(decode::<ClientMessage>(data).unwrap(), ClientMessage::ListChannels);
+
+        let join = ClientMessage::JoinChannel(ChannelName {
+            channel: "random".to_string(),
+        });
+        let json = serde_json::from_slice::<serde_json::Value>(&encode(&join)).unwrap();
+        assert_eq!(json["Type"], "JoinChannel");
+        assert_eq!(json["Data"]["channel"], "random");
+
+        // messages without channel go to the default one
+        let data = br#"{ "Type": "MessageFromUser", "Data": { "username": "user", "text": "text" } }"#;
+        match decode::<ClientMessage>(data).unwrap() {
+            ClientMessage::MessageFromUser(message) => assert_eq!(message.channel, DEFAULT_CHANNEL),
+            message => panic!("Unexpected message {message:?}"),
+        }
+    }
+
+    #[test]
+    fn direct_message_round_trip() {
+        let data = br#"{ "Type": "DirectMessage", "Data": { "to": "bob", "text": "psst" } }"#;
+        assert_eq!(
+            decode::<ClientMessage>(data).unwrap(),
+            ClientMessage::DirectMessage(DirectMessage {
+                to: "bob".to_string(),
+                text: "psst".to_string(),
+            })
+        );
+
+        let message = ServerMessage::DirectMessage(DirectMessageFromUser {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            text: "psst".to_string(),
+            timestamp_ms: 1_700_000_000_000,
+        });
+        assert_eq!(decode::<ServerMessage>(&encode(&message)).unwrap(), message);
+    }
+
+    #[test]
+    fn presence_round_trip() {
+        let messages = [
+            ServerMessage::Roster(Roster {
+                users: vec!["alice".to_string(), "bob".to_string()],
+            }),
+            ServerMessage::UserJoined(UserPresence {
+                username: "carol".to_string(),
+            }),
+            ServerMessage::UserLeft(UserPresence {
+                username: "bob".to_string(),
+            }),
+        ];
+        for message in messages {
+            assert_eq!(decode::<ServerMessage>(&encode(&message)).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn server_shutdown_format() {
+        let message = ServerMessage::ServerShutdown(ServerShutdown {
+            reason: "Server is shutting down".to_string(),
+            reconnect_after_ms: None,
+        });
+        let json = serde_json::from_slice::<serde_json::Value>(&encode(&message)).unwrap();
+        assert_eq!(json["Type"], "ServerShutdown");
+        assert!(json["Data"].get("reconnect_after_ms").is_none());
+        assert_eq!(decode::<ServerMessage>(&encode(&message)).unwrap(), message);
+    }
+
+    #[test]
+    fn rate_limited_format() {
+        let message = ServerMessage::RateLimited(RateLimited {
+            action: RateLimitAction::Muted,
+            muted_for_ms: Some(30_000),
+        });
+        let json = serde_json::from_slice::<serde_json::Value>(&encode(&message)).unwrap();
+        assert_eq!(json["Data"]["action"], "Muted");
+        assert_eq!(decode::<ServerMessage>(&encode(&message)).unwrap(), message);
+    }
+
+    #[test]
+    fn decode_unknown_type() {
+        let data = br#"{ "Type": "Unknown", "Data": {} }"#;
+        assert!(decode::<ClientMessage>(data).is_err());
+    }
+}
@@ -1,5 +1,8 @@
 use rand::{Rng, SeedableRng};
-use std::io::Write;
+use std::collections::VecDeque;
+use std::io::Read;
+
+use crate::frame_header::FrameHeader;
 
 static PORT_COUNTER: std::sync::Mutex<std::cell::RefCell<i32>> =
     std::sync::Mutex::new(std::cell::RefCell::new(5432));
@@ -19,17 +22,9 @@ pub fn next_localhost_address() -> String {
 pub fn make_buffer_for_packet(payload: &str) -> Vec<u8> {
     assert_ne!(payload.len(), 0);
     let len: u32 = payload.len().try_into().expect("");
-    let slice = unsafe {
-        let pointer = &len as *const _ as *const u8;
-        std::slice::from_raw_parts(pointer, 4)
-    };
     let mut buffer: Vec<u8> = Vec::new();
-    buffer
-        .write(&slice)
-        .expect("Failed to write size to buffer");
-    buffer
-        .write(payload.as_bytes())
-        .expect("Failed to write payload to buffer");
+    buffer.extend_from_slice(&FrameHeader::new(len).to_bytes());
+    buffer.extend_from_slice(payload.as_bytes());
     buffer
 }
 
@@ -41,3 +36,61 @@ pub fn generate_random_string(seed: u64, min_length: usize, max_length: usize) -
         .map(|_| rng.gen_range(b'a'..b'z' + 1) as char)
         .collect()
 }
+
+
+/* Reader that behaves like a non-blocking socket receiving data in separate segments:
+ * each read returns bytes from one chunk only and WouldBlock is reported between chunks
+ */
+pub struct ChunkedReader {
+    chunks: VecDeque<Vec<u8>>,
+    would_block: bool,
+    closed_at_end: bool,
+}
+
+impl ChunkedReader {
+    pub fn new(chunks: Vec<Vec<u8>>) -> ChunkedReader {
+        ChunkedReader {
+            chunks: chunks.into(),
+            would_block: false,
+            closed_at_end: false,
+        }
+    }
+
+    // Report closed stream instead of WouldBlock once all chunks are read
+    pub fn closed_at_end(mut self) -> ChunkedReader {
+        self.closed_at_end = true;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+impl Read for ChunkedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.chunks.is_empty() && self.closed_at_end {
+            return Ok(0);
+        }
+
+        if self.would_block {
+            self.would_block = false;
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+
+        let mut chunk = match self.chunks.pop_front() {
+            Some(chunk) => chunk,
+            None => return Err(std::io::ErrorKind::WouldBlock.into()),
+        };
+
+        let count = std::cmp::min(buf.len(), chunk.len());
+        buf[..count].copy_from_slice(&chunk[..count]);
+        if count < chunk.len() {
+            self.chunks.push_front(chunk.split_off(count));
+        } else {
+            self.would_block = true;
+        }
+
+        Ok(count)
+    }
+}
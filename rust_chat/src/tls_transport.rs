@@ -0,0 +1,441 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+
+use crate::chat_result::{ChatError, ChatResult, ConvertibleToChatResult};
+use crate::transport::{PeerAddress, PeerCredentials, Transport};
+
+/* TLS session on top of another non-blocking transport.
+ * rustls buffers encrypted records internally, every read and write first tries to push
+ * them to the underlying stream. Records that could not be written because the stream
+ * would block stay buffered until the next read, write or flush.
+ */
+pub struct TlsTransport<T> {
+    session: rustls::Connection,
+    stream: T,
+}
+
+// How the client decides whether the server certificate can be trusted
+#[derive(Debug, Clone, PartialEq)]
+pub enum TlsTrust {
+    // Certificate authorities bundled with webpki-roots
+    PublicRoots,
+    // Certificate authorities from the PEM file, i.e. private CA of the deployment
+    CustomCa(PathBuf),
+    // Only the certificate from the PEM file is accepted, its issuer is not checked
+    PinnedCertificate(PathBuf),
+}
+
+fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certificates(path: &Path) -> ChatResult<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| ChatError(format!("Failed to load {}: {err}", path.display())))?;
+
+    if certificates.is_empty() {
+        return Err(ChatError(format!("No certificates in {}", path.display())));
+    }
+    Ok(certificates)
+}
+
+// Server side configuration from PEM certificate chain and private key
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> ChatResult<Arc<rustls::ServerConfig>> {
+    let certificates = load_certificates(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|err| ChatError(format!("Failed to load {}: {err}", key_path.display())))?;
+
+    let config = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .to_chat_result()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .to_chat_result()?;
+    Ok(Arc::new(config))
+}
+
+pub fn load_client_config(trust: &TlsTrust) -> ChatResult<Arc<rustls::ClientConfig>> {
+    let builder = rustls::ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .to_chat_result()?;
+
+    let config = match trust {
+        TlsTrust::PublicRoots => {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        TlsTrust::CustomCa(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(path)? {
+                roots.add(certificate).to_chat_result()?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        TlsTrust::PinnedCertificate(path) => {
+            let certificate = load_certificates(path)?.swap_remove(0);
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertificateVerifier {
+                    certificate,
+                    provider: crypto_provider(),
+                }))
+                .with_no_client_auth()
+        }
+    };
+    Ok(Arc::new(config))
+}
+
+/* Accepts the server only if it presents exactly the pinned certificate.
+ * Handshake signatures are still verified so the server has to own the matching key.
+ */
+#[derive(Debug)]
+struct PinnedCertificateVerifier {
+    certificate: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl<T> TlsTransport<T>
+where
+    T: Transport,
+{
+    pub fn server(stream: T, config: Arc<rustls::ServerConfig>) -> ChatResult<TlsTransport<T>> {
+        let session = rustls::ServerConnection::new(config).to_chat_result()?;
+        Ok(TlsTransport {
+            session: session.into(),
+            stream,
+        })
+    }
+
+    // server_name is checked against the server certificate, i.e. "localhost" or "127.0.0.1"
+    pub fn client(
+        stream: T,
+        config: Arc<rustls::ClientConfig>,
+        server_name: &str,
+    ) -> ChatResult<TlsTransport<T>> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|err| ChatError(format!("Invalid server name {server_name}: {err}")))?;
+        let session = rustls::ClientConnection::new(config, server_name).to_chat_result()?;
+        Ok(TlsTransport {
+            session: session.into(),
+            stream,
+        })
+    }
+
+    // Sends records of plaintext just accepted by the session
+    fn finish_write(&mut self, written: usize, requested: usize) -> std::io::Result<usize> {
+        match self.write_records() {
+            Err(err) if err.kind() != std::io::ErrorKind::WouldBlock => return Err(err),
+            _ => {}
+        }
+
+        if written == 0 && requested > 0 {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        Ok(written)
+    }
+
+    // Writes buffered records until there are none left or the stream would block
+    fn write_records(&mut self) -> std::io::Result<()> {
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Read for TlsTransport<T>
+where
+    T: Transport,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            // handshake replies must get out for the peer to send anything useful
+            match self.write_records() {
+                Err(err) if err.kind() != std::io::ErrorKind::WouldBlock => return Err(err),
+                _ => {}
+            }
+
+            match self.session.reader().read(buf) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            if self.session.read_tls(&mut self.stream)? == 0 {
+                return Ok(0);
+            }
+
+            if let Err(err) = self.session.process_new_packets() {
+                // let the peer know why the session ends
+                let _ = self.write_records();
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+            }
+        }
+    }
+}
+
+impl<T> Write for TlsTransport<T>
+where
+    T: Transport,
+{
+    // Data written before the handshake is complete is buffered and sent right after it
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.session.writer().write(buf)?;
+        self.finish_write(written, buf.len())
+    }
+
+    // All slices go into the same records instead of one slice per write
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+        let written = self.session.writer().write_vectored(bufs)?;
+        self.finish_write(written, bufs.iter().map(|buf| buf.len()).sum())
+    }
+
+    // Reports WouldBlock while some records are still buffered
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_records()?;
+        self.stream.flush()
+    }
+}
+
+impl<T> Transport for TlsTransport<T>
+where
+    T: Transport,
+{
+    fn peer_address(&self) -> std::io::Result<PeerAddress> {
+        self.stream.peer_address()
+    }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.stream.peer_credentials()
+    }
+
+    fn close(&mut self) {
+        self.session.send_close_notify();
+        let _ = self.write_records();
+        self.stream.close();
+    }
+
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> std::io::Result<()> {
+        self.stream.register(registry, token, interests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_transport::MemoryTransport;
+    use crate::packet_receiver::PacketReceiver;
+    use crate::packet_sender::PacketSender;
+    use crate::test_utils::generate_random_string;
+
+    struct TestCertificate {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    }
+
+    impl TestCertificate {
+        // Self-signed certificate for localhost written to temporary PEM files
+        fn generate(name: &str) -> TestCertificate {
+            let certified_key =
+                rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+            let directory = std::env::temp_dir();
+            let prefix = format!("rust_chat_{}_{name}", std::process::id());
+            let certificate = TestCertificate {
+                cert_path: directory.join(format!("{prefix}_cert.pem")),
+                key_path: directory.join(format!("{prefix}_key.pem")),
+            };
+            std::fs::write(&certificate.cert_path, certified_key.cert.pem()).unwrap();
+            std::fs::write(&certificate.key_path, certified_key.signing_key.serialize_pem())
+                .unwrap();
+            certificate
+        }
+    }
+
+    impl Drop for TestCertificate {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.cert_path);
+            let _ = std::fs::remove_file(&self.key_path);
+        }
+    }
+
+    fn make_pair(
+        server_certificate: &TestCertificate,
+        trust: &TlsTrust,
+    ) -> (TlsTransport<MemoryTransport>, TlsTransport<MemoryTransport>) {
+        let server_config =
+            load_server_config(&server_certificate.cert_path, &server_certificate.key_path).unwrap();
+        let client_config = load_client_config(trust).unwrap();
+
+        let (client, server) = MemoryTransport::pair();
+        (
+            TlsTransport::client(client, client_config, "localhost").unwrap(),
+            TlsTransport::server(server, server_config).unwrap(),
+        )
+    }
+
+    // Moves packets between both ends until the client receives the expected count
+    fn exchange(
+        client: &mut TlsTransport<MemoryTransport>,
+        server: &mut TlsTransport<MemoryTransport>,
+        payloads: &[String],
+    ) -> Result<Vec<String>, String> {
+        let mut client_sender = PacketSender::new();
+        let mut server_receiver = PacketReceiver::new();
+        let mut server_sender = PacketSender::new();
+        let mut client_receiver = PacketReceiver::new();
+
+        for payload in payloads {
+            client_sender.add_to_send_queue(payload.clone().into_bytes());
+        }
+
+        let mut echoed = Vec::new();
+        for _ in 0..100 {
+            client_sender.advance(client).map_err(|err| err.to_string())?;
+            server_receiver.advance(server).map_err(|err| err.to_string())?;
+            while let Some(packet) = server_receiver.pop_packet() {
+                server_sender.add_to_send_queue(packet);
+            }
+            server_sender.advance(server).map_err(|err| err.to_string())?;
+            client_receiver.advance(client).map_err(|err| err.to_string())?;
+            while let Some(packet) = client_receiver.pop_packet() {
+                echoed.push(String::from_utf8(packet).unwrap());
+            }
+
+            if echoed.len() == payloads.len() {
+                return Ok(echoed);
+            }
+        }
+
+        Err("Packets were not delivered".to_string())
+    }
+
+    fn make_payloads() -> Vec<String> {
+        (0..10)
+            .map(|index| generate_random_string(index, 1, 5000))
+            .collect()
+    }
+
+    #[test]
+    fn echo_with_custom_ca() {
+        let certificate = TestCertificate::generate("custom_ca");
+        let (mut client, mut server) =
+            make_pair(&certificate, &TlsTrust::CustomCa(certificate.cert_path.clone()));
+
+        let payloads = make_payloads();
+        assert_eq!(exchange(&mut client, &mut server, &payloads), Ok(payloads));
+    }
+
+    #[test]
+    fn echo_with_pinned_certificate() {
+        let certificate = TestCertificate::generate("pinned");
+        let (mut client, mut server) = make_pair(
+            &certificate,
+            &TlsTrust::PinnedCertificate(certificate.cert_path.clone()),
+        );
+
+        let payloads = make_payloads();
+        assert_eq!(exchange(&mut client, &mut server, &payloads), Ok(payloads));
+    }
+
+    #[test]
+    fn reject_unknown_certificate() {
+        let server_certificate = TestCertificate::generate("server");
+        let other_certificate = TestCertificate::generate("other");
+
+        for trust in [
+            TlsTrust::PublicRoots,
+            TlsTrust::CustomCa(other_certificate.cert_path.clone()),
+            TlsTrust::PinnedCertificate(other_certificate.cert_path.clone()),
+        ] {
+            let (mut client, mut server) = make_pair(&server_certificate, &trust);
+            assert!(
+                exchange(&mut client, &mut server, &make_payloads()).is_err(),
+                "Server certificate accepted with {trust:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn reject_missing_files() {
+        let missing = std::env::temp_dir().join("rust_chat_missing_certificate.pem");
+        assert!(load_server_config(&missing, &missing).is_err());
+        assert!(load_client_config(&TlsTrust::CustomCa(missing.clone())).is_err());
+        assert!(load_client_config(&TlsTrust::PinnedCertificate(missing)).is_err());
+    }
+
+    #[test]
+    fn payload_is_encrypted() {
+        let certificate = TestCertificate::generate("encrypted");
+        let (mut client, mut server) =
+            make_pair(&certificate, &TlsTrust::CustomCa(certificate.cert_path.clone()));
+        let payloads = make_payloads();
+        assert_eq!(exchange(&mut client, &mut server, &payloads), Ok(payloads));
+
+        let secret = "secret username";
+        client.write_all(secret.as_bytes()).unwrap();
+
+        // bypass the session and look at the records as they are on the wire
+        let mut raw = vec![0; 4096];
+        let count = server.stream.read(&mut raw).unwrap();
+        assert!(count > secret.len());
+        assert!(!raw[..count]
+            .windows(secret.len())
+            .any(|window| window == secret.as_bytes()));
+    }
+}
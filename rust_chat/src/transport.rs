@@ -0,0 +1,265 @@
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const UNIX_ADDRESS_PREFIX: &str = "unix:";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerAddress {
+    Tcp(SocketAddr),
+    // Path of the unix domain socket file the server listens on
+    Unix(PathBuf),
+    // in-process pipe, see memory_transport
+    Memory,
+}
+
+impl Display for PeerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{address}"),
+            Self::Unix(path) => write!(f, "{UNIX_ADDRESS_PREFIX}{}", path.display()),
+            Self::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+// Accepts socket address ("127.0.0.1:8787") or unix socket path ("unix:/tmp/chat.sock")
+impl FromStr for PeerAddress {
+    type Err = String;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = address.strip_prefix(UNIX_ADDRESS_PREFIX) {
+            if path.is_empty() {
+                return Err("Unix socket path is empty".to_string());
+            }
+            return Ok(PeerAddress::Unix(PathBuf::from(path)));
+        }
+
+        SocketAddr::from_str(address)
+            .map(PeerAddress::Tcp)
+            .map_err(|err| err.to_string())
+    }
+}
+
+// Identity of the process on the other end of a local connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerCredentials {
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/* Byte stream connections are built on.
+ * Reads and writes are expected to be non-blocking and report WouldBlock
+ * when no progress can be made. Transports are Send so the server can run on its own thread.
+ */
+pub trait Transport: Read + Write + Send {
+    fn peer_address(&self) -> std::io::Result<PeerAddress>;
+
+    // Only available for local transports, i.e. unix domain sockets
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        None
+    }
+
+    // Shuts down both directions of the stream
+    fn close(&mut self);
+
+    // Registers the transport in the poll of the server to get readiness events.
+    // Transports that are not backed by a socket have nothing to register
+    fn register(
+        &mut self,
+        _registry: &mio::Registry,
+        _token: mio::Token,
+        _interests: mio::Interest,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T> Transport for Box<T>
+where
+    T: Transport + ?Sized,
+{
+    fn peer_address(&self) -> std::io::Result<PeerAddress> {
+        (**self).peer_address()
+    }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        (**self).peer_credentials()
+    }
+
+    fn close(&mut self) {
+        (**self).close()
+    }
+
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> std::io::Result<()> {
+        (**self).register(registry, token, interests)
+    }
+}
+
+impl Transport for mio::net::TcpStream {
+    fn peer_address(&self) -> std::io::Result<PeerAddress> {
+        self.peer_addr().map(PeerAddress::Tcp)
+    }
+
+    fn close(&mut self) {
+        let _ = self.shutdown(std::net::Shutdown::Both);
+    }
+
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> std::io::Result<()> {
+        registry.register(self, token, interests)
+    }
+}
+
+impl Transport for std::net::TcpStream {
+    fn peer_address(&self) -> std::io::Result<PeerAddress> {
+        self.peer_addr().map(PeerAddress::Tcp)
+    }
+
+    fn close(&mut self) {
+        let _ = self.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{PeerAddress, PeerCredentials, Transport};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::os::unix::net::SocketAddr;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn peer_credentials(fd: RawFd) -> std::io::Result<PeerCredentials> {
+        let mut credentials: libc::ucred = unsafe { std::mem::zeroed() };
+        let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut credentials as *mut _ as *mut libc::c_void,
+                &mut length,
+            )
+        };
+
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(PeerCredentials {
+            pid: Some(credentials.pid),
+            uid: credentials.uid,
+            gid: credentials.gid,
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn peer_credentials(fd: RawFd) -> std::io::Result<PeerCredentials> {
+        let mut uid: libc::uid_t = 0;
+        let mut gid: libc::gid_t = 0;
+        if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(PeerCredentials { pid: None, uid, gid })
+    }
+
+    // Accepted sockets are usually unnamed, so the path of the listening socket is used then
+    fn socket_path(
+        peer: std::io::Result<SocketAddr>,
+        local: std::io::Result<SocketAddr>,
+    ) -> std::io::Result<PeerAddress> {
+        let peer = peer?;
+        let path = match peer.as_pathname() {
+            Some(path) => path.to_path_buf(),
+            None => local?.as_pathname().map(|path| path.to_path_buf()).unwrap_or_default(),
+        };
+        Ok(PeerAddress::Unix(path))
+    }
+
+    impl Transport for mio::net::UnixStream {
+        fn peer_address(&self) -> std::io::Result<PeerAddress> {
+            socket_path(self.peer_addr(), self.local_addr())
+        }
+
+        fn peer_credentials(&self) -> Option<PeerCredentials> {
+            peer_credentials(self.as_raw_fd()).ok()
+        }
+
+        fn close(&mut self) {
+            let _ = self.shutdown(std::net::Shutdown::Both);
+        }
+
+        fn register(
+            &mut self,
+            registry: &mio::Registry,
+            token: mio::Token,
+            interests: mio::Interest,
+        ) -> std::io::Result<()> {
+            registry.register(self, token, interests)
+        }
+    }
+
+    impl Transport for std::os::unix::net::UnixStream {
+        fn peer_address(&self) -> std::io::Result<PeerAddress> {
+            socket_path(self.peer_addr(), self.local_addr())
+        }
+
+        fn peer_credentials(&self) -> Option<PeerCredentials> {
+            peer_credentials(self.as_raw_fd()).ok()
+        }
+
+        fn close(&mut self) {
+            let _ = self.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_peer_address() {
+        assert_eq!(
+            PeerAddress::from_str("127.0.0.1:8787"),
+            Ok(PeerAddress::Tcp(SocketAddr::from(([127, 0, 0, 1], 8787))))
+        );
+        assert_eq!(
+            PeerAddress::from_str("unix:/tmp/chat.sock"),
+            Ok(PeerAddress::Unix(PathBuf::from("/tmp/chat.sock")))
+        );
+        assert!(PeerAddress::from_str("unix:").is_err());
+        assert!(PeerAddress::from_str("localhost").is_err());
+    }
+
+    #[test]
+    fn peer_address_display_round_trip() {
+        for address in ["127.0.0.1:8787", "unix:/tmp/chat.sock"] {
+            assert_eq!(PeerAddress::from_str(address).unwrap().to_string(), address);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_peer_credentials() {
+        let (a, _b) = std::os::unix::net::UnixStream::pair().unwrap();
+        let credentials = a.peer_credentials().expect("Failed to get peer credentials");
+        assert_eq!(credentials.uid, unsafe { libc::getuid() });
+        assert_eq!(credentials.gid, unsafe { libc::getgid() });
+        if let Some(pid) = credentials.pid {
+            assert_eq!(pid as u32, std::process::id());
+        }
+    }
+}
@@ -1,11 +1,22 @@
 use std::str::FromStr;
 
+use crate::client::ChatTarget;
 use crate::client::Client;
 use crate::client::LoggedInState;
+use crate::client::ReconnectingState;
+use crate::client::TlsOptions;
+use crate::client::TrustMode;
 use crate::client::WaitingForConnectionInfoState;
+use rust_chat::PeerAddress;
 
 pub struct Application {
     client: Option<Client>,
+    // kept here so the choice survives returning to the connection page
+    tls_options: TlsOptions,
+    // lost connections are reconnected instead of going to the disconnected page
+    auto_reconnect: bool,
+    // whether the chat was scrolled to the top in the previous frame
+    scrolled_to_top: bool,
 }
 
 impl Default for Application {
@@ -14,6 +25,9 @@ impl Default for Application {
             client: Some(Client::WaitingForConnectionInfo(
                 WaitingForConnectionInfoState::new(),
             )),
+            tls_options: TlsOptions::default(),
+            auto_reconnect: true,
+            scrolled_to_top: false,
         }
     }
 }
@@ -35,7 +49,7 @@ impl Application {
 
         egui::CentralPanel::default()
             .show(ctx, |ui| {
-                let can_parse_address = std::net::SocketAddr::from_str(&state.address).is_ok();
+                let can_parse_address = PeerAddress::from_str(&state.address).is_ok();
                 ui.horizontal(|ui| {
                     let name_label = ui.label("Server address: ");
                     ui.text_edit_singleline(&mut state.address)
@@ -43,15 +57,43 @@ impl Application {
 
                     if !can_parse_address {
                         let error_text =
-                            egui::RichText::new("Inavlid address").color(egui::Color32::RED);
+                            egui::RichText::new("Inavlid address, expected ip:port or unix:/path").color(egui::Color32::RED);
                         ui.label(error_text);
                     }
                 });
 
+                let tls_options = &mut self.tls_options;
+                ui.checkbox(&mut tls_options.enabled, "Use TLS");
+                if tls_options.enabled {
+                    ui.horizontal(|ui| {
+                        let server_name_label = ui.label("Server name: ");
+                        ui.text_edit_singleline(&mut tls_options.server_name)
+                            .labelled_by(server_name_label.id);
+                    });
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut tls_options.trust_mode, TrustMode::PublicRoots, "Public CAs");
+                        ui.radio_value(&mut tls_options.trust_mode, TrustMode::CustomCa, "Custom CA");
+                        ui.radio_value(
+                            &mut tls_options.trust_mode,
+                            TrustMode::PinnedCertificate,
+                            "Pinned certificate",
+                        );
+                    });
+                    if tls_options.trust_mode != TrustMode::PublicRoots {
+                        ui.horizontal(|ui| {
+                            let path_label = ui.label("Certificate file (PEM): ");
+                            ui.text_edit_singleline(&mut tls_options.certificate_path)
+                                .labelled_by(path_label.id);
+                        });
+                    }
+                }
+
+                ui.checkbox(&mut self.auto_reconnect, "Reconnect automatically");
+
                 let connect_button =
                     ui.add_enabled(can_parse_address, egui::Button::new("Connect"));
                 if connect_button.clicked() {
-                    state.connect()
+                    state.connect(&self.tls_options)
                 } else {
                     Client::WaitingForConnectionInfo(state)
                 }
@@ -59,7 +101,87 @@ impl Application {
             .inner
     }
 
-    fn chat_page(&mut self, ctx: &egui::Context, mut state: LoggedInState) -> Client {
+    fn chat_page(&mut self, ctx: &egui::Context, mut state: Box<LoggedInState>) -> Client {
+        egui::TopBottomPanel::top("top_panel")
+            .resizable(false)
+            .min_height(0.0)
+            .show(ctx, |ui| {
+                ui.label(&state.session.server_name);
+                if let Some(motd) = &state.session.motd {
+                    ui.label(egui::RichText::new(motd).italics());
+                }
+                if let Some(error) = &state.last_error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+            });
+
+        egui::SidePanel::left("channels_panel")
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.heading("Channels");
+                let mut selected = None;
+                for view in &state.channels {
+                    let target = ChatTarget::Channel(view.name.clone());
+                    if ui.selectable_label(state.active == target, format!("#{}", view.name)).clicked() {
+                        selected = Some(target);
+                    }
+                }
+
+                if let ChatTarget::Channel(channel) = state.active.clone() {
+                    if ui.button("Leave").clicked() {
+                        state.leave_channel(&channel);
+                    }
+                }
+
+                ui.separator();
+                ui.text_edit_singleline(&mut state.channel_input);
+                ui.horizontal(|ui| {
+                    if ui.button("Join").clicked() {
+                        state.join_channel(false);
+                    }
+                    if ui.button("Create").clicked() {
+                        state.join_channel(true);
+                    }
+                });
+
+                ui.separator();
+                if ui.button("List channels").clicked() {
+                    state.list_channels();
+                }
+                let mut to_join = None;
+                if let Some(channels) = &state.available_channels {
+                    for info in channels {
+                        let label = format!("#{} ({})", info.name, info.members);
+                        if ui.link(label).clicked() {
+                            to_join = Some(info.name.clone());
+                        }
+                    }
+                }
+                if let Some(channel) = to_join {
+                    state.channel_input = channel;
+                    state.join_channel(false);
+                }
+
+                ui.separator();
+                ui.heading("Direct messages");
+                for conversation in &state.conversations {
+                    let target = ChatTarget::User(conversation.peer.clone());
+                    if ui.selectable_label(state.active == target, &conversation.peer).clicked() {
+                        selected = Some(target);
+                    }
+                }
+                ui.text_edit_singleline(&mut state.peer_input);
+                if ui.button("Message user").clicked() {
+                    state.start_conversation();
+                    self.scrolled_to_top = false;
+                }
+
+                if let Some(target) = selected {
+                    state.active = target;
+                    self.scrolled_to_top = false;
+                }
+            });
+
         egui::TopBottomPanel::bottom("bottom_panel")
             .resizable(false)
             .min_height(0.0)
@@ -74,25 +196,115 @@ impl Application {
                 });
             });
 
+        egui::SidePanel::right("users_panel")
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.heading(format!("Online ({})", state.online_users.len()));
+                let mut to_message = None;
+                for username in &state.online_users {
+                    if ui.link(username).clicked() {
+                        to_message = Some(username.clone());
+                    }
+                }
+                // clicking a user opens the conversation with them
+                if let Some(username) = to_message {
+                    state.peer_input = username;
+                    state.start_conversation();
+                    self.scrolled_to_top = false;
+                }
+            });
+
         egui::CentralPanel::default().show(ctx, |ui| {
-            egui::ScrollArea::vertical()
+            let scroll_output = egui::ScrollArea::vertical()
                 .auto_shrink([false; 2])
                 .stick_to_bottom(true)
-                .show(ui, |ui| {
-                    ui.vertical(|ui| {
-                        for message in &state.received_messages {
-                            ui.horizontal(|ui| {
-                                ui.colored_label(egui::Color32::GREEN, &message.username);
-                                ui.separator();
-                                ui.colored_label(egui::Color32::WHITE, &message.text);
-                            });
-                        }
-                    });
-                });
+                .show(ui, |ui| show_messages(ui, &state));
+
+            // load older messages when the user scrolls to the top
+            let at_top = scroll_output.state.offset.y <= 0.0;
+            if at_top && !self.scrolled_to_top {
+                state.fetch_older_history();
+            }
+            self.scrolled_to_top = at_top;
         });
 
+        if state.active_view().is_some_and(|view| view.history_requested) {
+            ctx.request_repaint();
+        } else {
+            // wake up for the heartbeat even if the user does nothing
+            ctx.request_repaint_after(state.next_heartbeat_in());
+        }
+
         state.tick()
     }
+
+    // Messages received before the connection was lost stay visible below the banner
+    fn reconnecting_page(&mut self, ctx: &egui::Context, state: ReconnectingState) -> Client {
+        if !self.auto_reconnect {
+            return state.cancel();
+        }
+
+        let cancel = egui::TopBottomPanel::top("top_panel")
+            .resizable(false)
+            .min_height(0.0)
+            .show(ctx, |ui| {
+                ui.heading(format!(
+                    "Reconnecting to {} (attempt {})",
+                    state.address(),
+                    state.attempt
+                ));
+                ui.colored_label(egui::Color32::RED, &state.reason);
+                if let Some(error) = &state.last_error {
+                    ui.label(format!("Last attempt failed: {error}"));
+                }
+                ui.button("Cancel").clicked()
+            })
+            .inner;
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            egui::ScrollArea::vertical()
+                .auto_shrink([false; 2])
+                .stick_to_bottom(true)
+                .show(ui, |ui| show_messages(ui, state.chat()));
+        });
+
+        if cancel {
+            return state.cancel();
+        }
+        ctx.request_repaint_after(state.next_tick_in());
+        state.tick(&self.tls_options)
+    }
+}
+
+// Messages of the active channel or conversation
+fn show_messages(ui: &mut egui::Ui, state: &LoggedInState) {
+    ui.vertical(|ui| {
+        if let Some(conversation) = state.active_conversation() {
+            for message in &conversation.messages {
+                ui.horizontal(|ui| {
+                    ui.colored_label(egui::Color32::LIGHT_BLUE, &message.from);
+                    ui.separator();
+                    ui.colored_label(egui::Color32::WHITE, &message.text);
+                });
+            }
+            return;
+        }
+
+        let view = match state.active_view() {
+            Some(view) => view,
+            None => return,
+        };
+        if view.history_requested {
+            ui.label("Loading older messages...");
+        }
+        for message in &view.messages {
+            ui.horizontal(|ui| {
+                ui.colored_label(egui::Color32::GREEN, &message.username);
+                ui.separator();
+                ui.colored_label(egui::Color32::WHITE, &message.text);
+            });
+        }
+    });
 }
 
 impl eframe::App for Application {
@@ -116,14 +328,44 @@ impl eframe::App for Application {
                             ui.text_edit_singleline(&mut state.login_info.user)
                                 .labelled_by(name_label.id);
                         });
-                        if ui.button("Login").clicked() {
-                            state.login()
-                        } else {
-                            Client::WaitingForLoginInfo(state)
-                        }
+                        ui.horizontal(|ui| {
+                            let password_label = ui.label("Password: ");
+                            egui::TextEdit::singleline(&mut state.password)
+                                .password(true)
+                                .hint_text("empty to log in as guest")
+                                .show(ui)
+                                .response
+                                .labelled_by(password_label.id);
+                        });
+
+                        let can_register = !state.password.is_empty();
+                        ui.horizontal(|ui| {
+                            if ui.button("Login").clicked() {
+                                state.login(false)
+                            } else if ui
+                                .add_enabled(can_register, egui::Button::new("Register"))
+                                .clicked()
+                            {
+                                state.login(true)
+                            } else {
+                                Client::WaitingForLoginInfo(state)
+                            }
+                        })
+                        .inner
                     })
                     .inner
             }
+            Client::WaitingForLoginResponse(state) => {
+                egui::CentralPanel::default()
+                    .show(ctx, |ui| {
+                        ui.heading("Logging in");
+                        ui.label(format!("Waiting for reply from {}", state.connection_info.address));
+                    });
+
+                // keep polling the socket until the server replies
+                ctx.request_repaint();
+                state.tick()
+            }
             Client::ConnectionFailed(state) => {
                 Client::WaitingForConnectionInfo(WaitingForConnectionInfoState {
                     address: state.connection_info.address.to_string(),
@@ -131,6 +373,7 @@ impl eframe::App for Application {
                 })
             }
             Client::LoggedIn(state) => self.chat_page(ctx, state),
+            Client::Reconnecting(state) => self.reconnecting_page(ctx, state),
             Client::LoginFailed(state) => {
                 egui::CentralPanel::default()
                     .show(ctx, |ui| {
@@ -150,8 +393,14 @@ impl eframe::App for Application {
             Client::Disconnected(state) => {
                 egui::CentralPanel::default()
                     .show(ctx, |ui| {
-                        ui.heading("Login failed");
+                        ui.heading("Disconnected");
                         ui.colored_label(egui::Color32::RED, state.reason.to_string());
+                        if let Some(after) = state.reconnect_after {
+                            ui.label(format!(
+                                "The server expects to be back in {} seconds",
+                                after.as_secs()
+                            ));
+                        }
                         if ui.button("To connection page").clicked() {
                             Client::WaitingForConnectionInfo(WaitingForConnectionInfoState {
                                 address: state.connection_info.address.to_string(),
@@ -1,22 +1,178 @@
-use std::{net::TcpStream, str::FromStr, mem::swap};
+use std::{collections::BTreeSet, net::TcpStream, str::FromStr, mem::swap};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
 
 use rust_chat::{
-    CommandType, ConnectionInfo, LoginInfo, MesasgeFromUser, PacketReceiver, PacketSender,
+    load_client_config, protocol, ChannelInfo, ChannelName, ClientMessage, ConnectionInfo,
+    Credentials, DirectMessage, DirectMessageFromUser, FetchHistory, HandshakeMessage,
+    HistoryPage, LoginAccepted, LoginInfo, MesasgeFromUser, PacketReceiver, PacketSender,
+    PeerAddress, RateLimitAction, ServerMessage, TlsTransport, TlsTrust, Transport,
 };
 
-pub fn try_connect(connection_info: ConnectionInfo) -> Client {
-    match TcpStream::connect(&connection_info.address) {
-        Ok(stream) => {
-            stream
-                .set_nonblocking(true)
-                .expect("Failed to make stream non-blocking");
-            Client::Connected(ConnectedState {
-                connection_info: connection_info,
-                stream: stream,
-            })
+// Messages requested at once when scrolling up the history
+const HISTORY_PAGE_SIZE: u32 = 50;
+
+// Server silent for this long is pinged
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Server silent for this long is considered dead, the connection is dropped
+const DEAD_SERVER_TIMEOUT: Duration = Duration::from_secs(45);
+
+// Delay before the first reconnect attempt, doubled with every failed one up to the maximum
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// Reconnect attempt is given up if the server does not reply to the handshake in time
+const RECONNECT_LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Connecting blocks the UI, so unreachable servers are given up quickly
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TrustMode {
+    PublicRoots,
+    CustomCa,
+    PinnedCertificate,
+}
+
+// TLS part of the connection page
+#[derive(Clone)]
+pub struct TlsOptions {
+    pub enabled: bool,
+    // Name the server certificate is issued for
+    pub server_name: String,
+    pub trust_mode: TrustMode,
+    // PEM file with CA or pinned certificate, not used for public roots
+    pub certificate_path: String,
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        TlsOptions {
+            enabled: false,
+            server_name: "localhost".to_string(),
+            trust_mode: TrustMode::PublicRoots,
+            certificate_path: String::new(),
+        }
+    }
+}
+
+impl TlsOptions {
+    fn trust(&self) -> TlsTrust {
+        let path = std::path::PathBuf::from(&self.certificate_path);
+        match self.trust_mode {
+            TrustMode::PublicRoots => TlsTrust::PublicRoots,
+            TrustMode::CustomCa => TlsTrust::CustomCa(path),
+            TrustMode::PinnedCertificate => TlsTrust::PinnedCertificate(path),
+        }
+    }
+}
+
+fn connect_tls(
+    stream: Box<dyn Transport>,
+    tls_options: &TlsOptions,
+) -> std::io::Result<Box<dyn Transport>> {
+    let to_io_error = |err: rust_chat::ChatError| std::io::Error::other(err.0);
+    let config = load_client_config(&tls_options.trust()).map_err(to_io_error)?;
+    let stream = TlsTransport::client(stream, config, &tls_options.server_name).map_err(to_io_error)?;
+    Ok(Box::new(stream))
+}
+
+fn connect_transport(address: &PeerAddress) -> std::io::Result<Box<dyn Transport>> {
+    match address {
+        PeerAddress::Tcp(address) => {
+            let stream = TcpStream::connect_timeout(address, CONNECT_TIMEOUT)?;
+            stream.set_nonblocking(true)?;
+            Ok(Box::new(stream))
+        }
+        #[cfg(unix)]
+        PeerAddress::Unix(path) => {
+            let stream = std::os::unix::net::UnixStream::connect(path)?;
+            stream.set_nonblocking(true)?;
+            Ok(Box::new(stream))
+        }
+        #[cfg(not(unix))]
+        PeerAddress::Unix(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Unix domain sockets are not supported on this platform",
+        )),
+        PeerAddress::Memory => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "In-memory transport can't be connected by address",
+        )),
+    }
+}
+
+fn open_stream(address: &PeerAddress, tls_options: &TlsOptions) -> std::io::Result<Box<dyn Transport>> {
+    let stream = connect_transport(address)?;
+    if tls_options.enabled {
+        connect_tls(stream, tls_options)
+    } else {
+        Ok(stream)
+    }
+}
+
+// Writes the whole handshake out, returns the reason if it fails
+fn send_handshake(stream: &mut Box<dyn Transport>, message: &HandshakeMessage) -> Result<(), String> {
+    let mut sender = PacketSender::new();
+    sender.add_to_send_queue(protocol::encode(message));
+    while !sender.empty() {
+        sender.advance(stream).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+struct LoginFailure {
+    reason: String,
+    // the same login may succeed later, i.e. the server still holds the username
+    // for a session it has not noticed is gone
+    transient: bool,
+}
+
+impl LoginFailure {
+    fn permanent(reason: String) -> LoginFailure {
+        LoginFailure {
+            reason,
+            transient: false,
         }
+    }
+}
+
+// Checks the reply to the handshake, returns why the login failed otherwise
+fn check_login_reply(data: &[u8]) -> Result<LoginAccepted, LoginFailure> {
+    match protocol::decode::<ServerMessage>(data) {
+        Ok(ServerMessage::LoginAccepted(login_accepted)) => {
+            if login_accepted.protocol_version != protocol::PROTOCOL_VERSION {
+                return Err(LoginFailure::permanent(format!(
+                    "Server protocol version {} is not supported (expected {})",
+                    login_accepted.protocol_version,
+                    protocol::PROTOCOL_VERSION
+                )));
+            }
+            Ok(login_accepted)
+        }
+        Ok(ServerMessage::LoginRejected(login_rejected)) => Err(LoginFailure {
+            reason: login_rejected.reason,
+            transient: login_rejected.retryable,
+        }),
+        Ok(message) => Err(LoginFailure::permanent(format!("Unexpected reply to login: {message:?}"))),
+        Err(err) => Err(LoginFailure::permanent(format!(
+            "Failed to parse reply to login: {}",
+            err.0
+        ))),
+    }
+}
+
+pub fn try_connect(connection_info: ConnectionInfo, tls_options: &TlsOptions) -> Client {
+    match open_stream(&connection_info.address, tls_options) {
+        Ok(stream) => Client::Connected(ConnectedState {
+            connection_info,
+            stream,
+        }),
         Err(err) => Client::ConnectionFailed(ConnectionFailedState {
-            connection_info: connection_info,
+            connection_info,
             reason: err.to_string(),
         }),
     }
@@ -30,10 +186,13 @@ pub struct WaitingForConnectionInfoState {
 }
 
 impl WaitingForConnectionInfoState {
-    pub fn connect(self) -> Client {
-        if let Ok(address) = std::net::SocketAddr::from_str(&self.address) {
-            let connection_info = ConnectionInfo { address: address };
-            try_connect(connection_info)
+    pub fn connect(self, tls_options: &TlsOptions) -> Client {
+        if let Ok(address) = PeerAddress::from_str(&self.address) {
+            let connection_info = ConnectionInfo {
+                address,
+                credentials: None,
+            };
+            try_connect(connection_info, tls_options)
         } else {
             Client::WaitingForConnectionInfo(self)
         }
@@ -58,7 +217,7 @@ pub struct ConnectionFailedState {
 
 pub struct ConnectedState {
     pub connection_info: ConnectionInfo,
-    pub stream: TcpStream,
+    pub stream: Box<dyn Transport>,
 }
 
 impl ConnectedState {
@@ -68,8 +227,8 @@ impl ConnectedState {
             login_info: LoginInfo {
                 user: "".to_string(),
             },
+            password: String::new(),
             stream: self.stream,
-            sender: PacketSender::new(),
         })
     }
 }
@@ -79,171 +238,677 @@ impl ConnectedState {
 pub struct WaitingForLoginInfoState {
     pub connection_info: ConnectionInfo,
     pub login_info: LoginInfo,
-    pub stream: TcpStream,
-    pub sender: PacketSender,
+    // Empty password logs in as guest
+    pub password: String,
+    pub stream: Box<dyn Transport>,
 }
 
 impl WaitingForLoginInfoState {
-    pub fn login(mut self) -> Client {
-        let login_message = &format!("{{ \"username\": \"{}\" }}", self.login_info.user);
-        let mut data = Vec::new();
-        data.extend_from_slice(&login_message.as_bytes());
-        self.sender.add_to_send_queue(data);
-
-        while !self.sender.empty() {
-            if let Err(err) = self.sender.advance(&mut self.stream) {
-                return Client::LoginFailed(LoginFailedState {
-                    connection_info: self.connection_info,
-                    login_info: self.login_info,
-                    reason: err.to_string(),
-                });
-            }
+    // Registers new account with the password first if register is set
+    pub fn login(mut self, register: bool) -> Client {
+        let password = std::mem::take(&mut self.password);
+        let credentials = if register {
+            Credentials::Register(password)
+        } else if password.is_empty() {
+            Credentials::Guest
+        } else {
+            Credentials::Password(password)
+        };
+
+        // the account exists once registered, reconnecting logs in to it
+        let reconnect_credentials = match &credentials {
+            Credentials::Register(password) => Credentials::Password(password.clone()),
+            credentials => credentials.clone(),
+        };
+        let login_message = HandshakeMessage {
+            username: self.login_info.user.clone(),
+            credentials,
+        };
+        if let Err(reason) = send_handshake(&mut self.stream, &login_message) {
+            return Client::LoginFailed(LoginFailedState {
+                connection_info: self.connection_info,
+                reason,
+            });
         }
 
-        Client::LoggedIn(LoggedInState {
+        Client::WaitingForLoginResponse(WaitingForLoginResponseState {
             connection_info: self.connection_info,
             login_info: self.login_info,
+            credentials: reconnect_credentials,
             stream: self.stream,
-            sender: PacketSender::new(),
             receiver: PacketReceiver::new(),
-            current_input: String::new(),
-            received_messages: Vec::new(),
         })
     }
 }
 
 //---------------------------------------------------------------------------------------------------
 
+pub struct WaitingForLoginResponseState {
+    pub connection_info: ConnectionInfo,
+    pub login_info: LoginInfo,
+    // sent again when reconnecting
+    credentials: Credentials,
+    pub stream: Box<dyn Transport>,
+    pub receiver: PacketReceiver,
+}
+
+impl WaitingForLoginResponseState {
+    fn fail(self, reason: String) -> Client {
+        Client::LoginFailed(LoginFailedState {
+            connection_info: self.connection_info,
+            reason,
+        })
+    }
+
+    pub fn tick(mut self) -> Client {
+        if let Err(err) = self.receiver.advance(&mut self.stream) {
+            return self.fail(err.to_string());
+        }
+
+        let data = match self.receiver.pop_packet() {
+            Some(data) => data,
+            None => return Client::WaitingForLoginResponse(self),
+        };
+
+        match check_login_reply(&data) {
+            Ok(login_accepted) => {
+                Client::LoggedIn(Box::new(LoggedInState {
+                    connection_info: self.connection_info,
+                    login_info: self.login_info,
+                    credentials: self.credentials,
+                    session: login_accepted,
+                    stream: self.stream,
+                    sender: PacketSender::new(),
+                    receiver: self.receiver,
+                    current_input: String::new(),
+                    channels: Vec::new(),
+                    conversations: Vec::new(),
+                    active: ChatTarget::Channel(protocol::DEFAULT_CHANNEL.to_string()),
+                    channel_input: String::new(),
+                    peer_input: String::new(),
+                    online_users: BTreeSet::new(),
+                    last_activity: Instant::now(),
+                    ping_sent: false,
+                    available_channels: None,
+                    last_error: None,
+                }))
+            }
+            Err(failure) => self.fail(failure.reason),
+        }
+    }
+}
+
+//---------------------------------------------------------------------------------------------------
+
+// Messages of one joined channel
+pub struct ChannelView {
+    pub name: String,
+    // oldest first, older history pages are prepended
+    pub messages: Vec<MesasgeFromUser>,
+    // FetchHistory was sent and the page has not arrived yet
+    pub history_requested: bool,
+    has_older_history: bool,
+}
+
+impl ChannelView {
+    fn new(name: String) -> ChannelView {
+        ChannelView {
+            name,
+            messages: Vec::new(),
+            history_requested: false,
+            has_older_history: true,
+        }
+    }
+
+    fn oldest_message_id(&self) -> Option<u64> {
+        self.messages.iter().find_map(|message| message.id)
+    }
+
+    fn newest_message_id(&self) -> Option<u64> {
+        self.messages.iter().rev().find_map(|message| message.id)
+    }
+
+    fn add_history(&mut self, page: HistoryPage) {
+        self.history_requested = false;
+        self.has_older_history = page.has_more;
+
+        // the page may overlap with messages received meanwhile, i.e. the backfill after joining.
+        // Messages newer than all received ones were missed while reconnecting
+        let (oldest_id, newest_id) = (self.oldest_message_id(), self.newest_message_id());
+        let (mut older, mut newer) = (Vec::new(), Vec::new());
+        for message in page.messages {
+            match (message.id, oldest_id, newest_id) {
+                (Some(id), Some(oldest_id), _) if id < oldest_id => older.push(message),
+                (Some(id), _, Some(newest_id)) if id > newest_id => newer.push(message),
+                (Some(_), Some(_), _) => {}
+                _ => older.push(message),
+            }
+        }
+        self.messages.splice(0..0, older);
+        self.messages.append(&mut newer);
+    }
+}
+
+// Direct messages exchanged with one user
+pub struct Conversation {
+    pub peer: String,
+    // in the order they arrived, direct messages have no history
+    pub messages: Vec<DirectMessageFromUser>,
+}
+
+// What the chat page shows and where typed messages go
+#[derive(Clone, PartialEq)]
+pub enum ChatTarget {
+    Channel(String),
+    User(String),
+}
+
 pub struct LoggedInState {
     connection_info: ConnectionInfo,
     login_info: LoginInfo,
-    stream: TcpStream,
+    credentials: Credentials,
+    pub session: LoginAccepted,
+    stream: Box<dyn Transport>,
     sender: PacketSender,
     receiver: PacketReceiver,
     pub current_input: String,
-    pub received_messages: Vec<MesasgeFromUser>,
+    // in the order they were joined, the server confirms the default channel after login
+    pub channels: Vec<ChannelView>,
+    // in the order they were started
+    pub conversations: Vec<Conversation>,
+    pub active: ChatTarget,
+    // name typed in to join or create a channel
+    pub channel_input: String,
+    // name typed in to start a conversation
+    pub peer_input: String,
+    // kept up to date by the presence updates from the server
+    pub online_users: BTreeSet<String>,
+    // when the last packet was received from the server
+    last_activity: Instant,
+    // Ping was sent after the server went idle and no packet arrived since
+    ping_sent: bool,
+    // reply to the last ListChannels, None until the list is requested
+    pub available_channels: Option<Vec<ChannelInfo>>,
+    // reason of the last failed command
+    pub last_error: Option<String>,
 }
 
 impl LoggedInState {
+    pub fn active_view(&self) -> Option<&ChannelView> {
+        match &self.active {
+            ChatTarget::Channel(channel) => self.channels.iter().find(|view| &view.name == channel),
+            ChatTarget::User(_) => None,
+        }
+    }
+
+    pub fn active_conversation(&self) -> Option<&Conversation> {
+        match &self.active {
+            ChatTarget::User(peer) => self
+                .conversations
+                .iter()
+                .find(|conversation| &conversation.peer == peer),
+            ChatTarget::Channel(_) => None,
+        }
+    }
+
+    // Switches to the conversation with the user named in the peer input
+    pub fn start_conversation(&mut self) {
+        let peer = std::mem::take(&mut self.peer_input);
+        if peer.is_empty() {
+            return;
+        }
+
+        // the stored name may differ in case from the input
+        let peer = self.conversation_mut(&peer).peer.clone();
+        self.active = ChatTarget::User(peer);
+    }
+
+    // Usernames are case insensitive, so is the lookup of the conversation
+    fn conversation_mut(&mut self, peer: &str) -> &mut Conversation {
+        let position = self
+            .conversations
+            .iter()
+            .position(|conversation| conversation.peer.to_lowercase() == peer.to_lowercase());
+        let index = match position {
+            Some(index) => index,
+            None => {
+                self.conversations.push(Conversation {
+                    peer: peer.to_string(),
+                    messages: Vec::new(),
+                });
+                self.conversations.len() - 1
+            }
+        };
+        &mut self.conversations[index]
+    }
+
+    fn add_direct_message(&mut self, message: DirectMessageFromUser) {
+        let peer = if message.from.to_lowercase() == self.login_info.user.to_lowercase() {
+            message.to.clone()
+        } else {
+            message.from.clone()
+        };
+        self.conversation_mut(&peer).messages.push(message);
+    }
+
+    fn view_mut(&mut self, channel: &str) -> Option<&mut ChannelView> {
+        self.channels.iter_mut().find(|view| view.name == channel)
+    }
+
+    fn send_command(&mut self, message: &ClientMessage) {
+        self.sender.add_to_send_queue(protocol::encode(message));
+    }
+
+    // Asks the server for the page of messages preceding the oldest received one in the active channel
+    pub fn fetch_older_history(&mut self) {
+        let channel = match &self.active {
+            ChatTarget::Channel(channel) => channel.clone(),
+            ChatTarget::User(_) => return,
+        };
+        let view = match self.view_mut(&channel) {
+            Some(view) if !view.history_requested && view.has_older_history => view,
+            _ => return,
+        };
+
+        view.history_requested = true;
+        let request = ClientMessage::FetchHistory(FetchHistory {
+            channel,
+            before_id: view.oldest_message_id(),
+            limit: HISTORY_PAGE_SIZE,
+        });
+        self.send_command(&request);
+    }
+
+    // Creates the channel named in the channel input if create is set, joins an existing one otherwise
+    pub fn join_channel(&mut self, create: bool) {
+        let channel = ChannelName {
+            channel: std::mem::take(&mut self.channel_input),
+        };
+        if channel.channel.is_empty() {
+            return;
+        }
+
+        let command = if create {
+            ClientMessage::CreateChannel(channel)
+        } else {
+            ClientMessage::JoinChannel(channel)
+        };
+        self.send_command(&command);
+    }
+
+    pub fn leave_channel(&mut self, channel: &str) {
+        self.send_command(&ClientMessage::LeaveChannel(ChannelName {
+            channel: channel.to_string(),
+        }));
+    }
+
+    pub fn list_channels(&mut self) {
+        self.send_command(&ClientMessage::ListChannels);
+    }
+
+    // Channels joined again after reconnecting keep their view and don't steal the focus
+    fn channel_joined(&mut self, channel: String) {
+        if self.view_mut(&channel).is_none() {
+            self.channels.push(ChannelView::new(channel.clone()));
+            self.active = ChatTarget::Channel(channel);
+        }
+    }
+
+    fn channel_left(&mut self, channel: &str) {
+        self.channels.retain(|view| view.name != channel);
+        if self.active == ChatTarget::Channel(channel.to_string()) {
+            if let Some(view) = self.channels.first() {
+                self.active = ChatTarget::Channel(view.name.clone());
+            }
+        }
+    }
+
     pub fn send_message(&mut self) {
         if self.current_input.is_empty() {
             return;
         }
 
-        let mut object = serde_json::value::Map::new();
-        object.insert(
-            "Type".to_string(),
-            serde_json::to_value(CommandType::MessageFromUser).unwrap(),
-        );
-
         let mut current_message = String::new();
         swap(&mut current_message, &mut self.current_input);
-        object.insert(
-            "Data".to_string(),
-            serde_json::to_value(MesasgeFromUser {
+        let message = match &self.active {
+            ChatTarget::Channel(channel) => ClientMessage::MessageFromUser(MesasgeFromUser {
                 username: self.login_info.user.clone(),
                 text: current_message,
-            })
-            .expect("fail"),
-        );
-
-        let buf = serde_json::to_string(&object).unwrap();
+                channel: channel.clone(),
+                id: None,
+                timestamp_ms: None,
+            }),
+            // shown once the server echoes it back
+            ChatTarget::User(peer) => ClientMessage::DirectMessage(DirectMessage {
+                to: peer.clone(),
+                text: current_message,
+            }),
+        };
 
-        self.sender.add_to_send_queue(Vec::from(buf.as_bytes()));
+        self.sender.add_to_send_queue(protocol::encode(&message));
         self.current_input.clear();
     }
 
-    fn take_message(&mut self) -> Option<String> {
-        if let Some(packet) = self.receiver.pop_packet() {
-            Some(String::from_utf8(packet).expect(""))
+    fn take_message(&mut self) -> Option<Vec<u8>> {
+        let message = self.receiver.pop_packet();
+        if message.is_some() {
+            self.last_activity = Instant::now();
+            self.ping_sent = false;
+        }
+        message
+    }
+
+    // How long the page may wait before calling tick to keep the heartbeat going
+    pub fn next_heartbeat_in(&self) -> Duration {
+        let timeout = if self.ping_sent {
+            DEAD_SERVER_TIMEOUT
         } else {
-            None
+            IDLE_TIMEOUT
+        };
+        timeout.saturating_sub(self.last_activity.elapsed())
+    }
+
+    // Returns the reason of disconnection if the server stopped responding
+    fn check_heartbeat(&mut self) -> Result<(), String> {
+        let silent_for = self.last_activity.elapsed();
+        if silent_for >= DEAD_SERVER_TIMEOUT {
+            return Err(format!(
+                "Server did not respond for {} seconds",
+                silent_for.as_secs()
+            ));
         }
+
+        if silent_for >= IDLE_TIMEOUT && !self.ping_sent {
+            self.send_command(&ClientMessage::Ping);
+            self.ping_sent = true;
+        }
+        Ok(())
     }
 
-    pub fn tick(mut self) -> Client {
-        if let Err(err) = self.sender.advance(&mut self.stream) {
-            return Client::Disconnected(DisconnectedState {
-                connection_info: self.connection_info,
-                login_info: self.login_info,
-                reason: err.to_string(),
-            });
+    // Lost connections are reconnected unless the user cancels it
+    fn disconnect(self: Box<Self>, reason: String) -> Client {
+        Client::Reconnecting(ReconnectingState::new(self, reason, None))
+    }
+
+    // Takes over the connection established by reconnecting, the views are kept
+    fn resume(&mut self, stream: Box<dyn Transport>, receiver: PacketReceiver, session: LoginAccepted) {
+        self.stream = stream;
+        self.receiver = receiver;
+        self.sender = PacketSender::new();
+        self.session = session;
+        self.last_activity = Instant::now();
+        self.ping_sent = false;
+        self.last_error = None;
+        // the server sends the roster again
+        self.online_users.clear();
+
+        // the server joins the default channel on login, the others are joined again
+        let channels: Vec<String> = self.channels.iter().map(|view| view.name.clone()).collect();
+        for channel in channels {
+            if let Some(view) = self.view_mut(&channel) {
+                // the reply was lost with the old connection
+                view.history_requested = false;
+            }
+            if channel != protocol::DEFAULT_CHANNEL {
+                self.send_command(&ClientMessage::JoinChannel(ChannelName { channel }));
+            }
         }
+    }
 
+    // Sending goes last so that replies to what was received leave in the same tick
+    pub fn tick(mut self: Box<Self>) -> Client {
         if let Err(err) = self.receiver.advance(&mut self.stream) {
-            return Client::Disconnected(DisconnectedState {
-                connection_info: self.connection_info,
-                login_info: self.login_info,
-                reason: err.to_string(),
-            });
+            return self.disconnect(err.to_string());
         }
 
         while let Some(data) = self.take_message() {
-            let mut cmd_json = match serde_json::from_slice::<serde_json::Value>(data.as_bytes()) {
-                Ok(cmd_json) => cmd_json,
-                Err(parse_err) => {
+            let message = match protocol::decode::<ServerMessage>(&data) {
+                Ok(message) => message,
+                Err(err) => {
                     println!(
-                        "Failed to parse command json: {}. Error: {}.",
-                        data, parse_err
+                        "Failed to parse message: {}. Error: {:?}.",
+                        String::from_utf8_lossy(&data),
+                        err
                     );
                     continue;
                 }
             };
 
-            let cmd_json = match cmd_json.as_object_mut() {
-                Some(map) => map,
-                None => {
-                    println!("Command json expected to be object");
-                    continue;
+            match message {
+                ServerMessage::Ping => self.send_command(&ClientMessage::Pong),
+                // taking the packet has already recorded the activity
+                ServerMessage::Pong => {}
+                ServerMessage::MessageFromUser(message_from_user) => {
+                    if let Some(view) = self.view_mut(&message_from_user.channel) {
+                        view.messages.push(message_from_user);
+                    }
                 }
-            };
-
-            let key_command_type = "Type";
-            let command_type = match cmd_json.remove(key_command_type) {
-                Some(cmd_type_str) => match serde_json::from_value::<CommandType>(cmd_type_str) {
-                    Ok(command_type) => command_type,
-                    Err(err) => {
-                        println!("Failed to parse command type: {}", err);
-                        continue;
+                ServerMessage::History(page) => {
+                    if let Some(view) = self.view_mut(&page.channel) {
+                        view.add_history(page);
                     }
-                },
-                None => {
-                    println!("Invalid json - {key_command_type} not found.");
-                    continue;
                 }
-            };
-
-            let key_commad_data = "Data";
-            let cmd_data_value = match cmd_json.remove(key_commad_data) {
-                Some(cmd_data_value) => cmd_data_value,
-                None => {
-                    println!("Invalid json - {key_commad_data} not found.");
-                    continue;
+                ServerMessage::DirectMessage(message) => self.add_direct_message(message),
+                ServerMessage::Roster(roster) => self.online_users = roster.users.into_iter().collect(),
+                ServerMessage::UserJoined(presence) => {
+                    self.online_users.insert(presence.username);
                 }
-            };
-
-            match command_type {
-                CommandType::MessageFromUser => {
-                    match serde_json::from_value::<MesasgeFromUser>(cmd_data_value) {
-                        Ok(message_from_user) => self.received_messages.push(message_from_user),
-                        Err(err) => {
-                            println!("Failed to parse message from user. {}", err);
-                            continue;
+                ServerMessage::UserLeft(presence) => {
+                    self.online_users.remove(&presence.username);
+                }
+                ServerMessage::ChannelJoined(joined) => self.channel_joined(joined.channel),
+                ServerMessage::ChannelLeft(left) => self.channel_left(&left.channel),
+                ServerMessage::ChannelList(list) => self.available_channels = Some(list.channels),
+                ServerMessage::CommandFailed(failed) => self.last_error = Some(failed.reason),
+                ServerMessage::RateLimited(limited) => {
+                    self.last_error = Some(match limited.action {
+                        RateLimitAction::Warning => {
+                            "You are sending too fast, slow down or you will be muted".to_string()
                         }
-                    }
+                        RateLimitAction::Muted => format!(
+                            "You are muted for {} seconds for sending too fast",
+                            limited.muted_for_ms.unwrap_or(0).div_ceil(1000)
+                        ),
+                        RateLimitAction::Disconnected => {
+                            "Disconnected for sending too fast".to_string()
+                        }
+                    })
+                }
+                // reconnecting makes sense only if the server is coming back
+                ServerMessage::ServerShutdown(shutdown) => {
+                    return match shutdown.reconnect_after_ms {
+                        Some(after) => Client::Reconnecting(ReconnectingState::new(
+                            self,
+                            shutdown.reason,
+                            Some(Duration::from_millis(after)),
+                        )),
+                        None => Client::Disconnected(DisconnectedState {
+                            connection_info: self.connection_info,
+                            reason: shutdown.reason,
+                            reconnect_after: None,
+                        }),
+                    };
+                }
+                ServerMessage::LoginAccepted(_) | ServerMessage::LoginRejected(_) => {
+                    println!("Unexpected login reply after login");
                 }
             }
         }
 
+        if let Err(reason) = self.check_heartbeat() {
+            return self.disconnect(reason);
+        }
+
+        if let Err(err) = self.sender.advance(&mut self.stream) {
+            return self.disconnect(err.to_string());
+        }
+
         Client::LoggedIn(self)
     }
 }
 
 //---------------------------------------------------------------------------------------------------
 
+/* Random delay before the given attempt of reconnecting, 1 for the first one.
+ * The upper bound doubles with every attempt, the delay is between its half and the whole so
+ * that clients that lost the connection at the same time don't come back at the same time
+ */
+fn reconnect_delay(attempt: u32) -> Duration {
+    let ceiling = RECONNECT_BASE_DELAY
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(RECONNECT_MAX_DELAY);
+    // hashers of RandomState are seeded randomly, which is enough for jitter
+    let random = RandomState::new().build_hasher().finish();
+    ceiling.mul_f64(0.5 + 0.5 * (random as f64 / u64::MAX as f64))
+}
+
+enum ReconnectPhase {
+    // waiting until the attempt is due
+    Waiting(Instant),
+    // handshake was sent, waiting for the reply until the deadline
+    LoggingIn {
+        stream: Box<dyn Transport>,
+        receiver: PacketReceiver,
+        deadline: Instant,
+    },
+}
+
+pub struct ReconnectingState {
+    // views of the lost session, shown while reconnecting and resumed afterwards
+    chat: Box<LoggedInState>,
+    // why the connection was lost
+    pub reason: String,
+    // number of the attempt in progress or waited for, starting at 1
+    pub attempt: u32,
+    // why the previous attempt failed
+    pub last_error: Option<String>,
+    // when the server said it expects to be back
+    reconnect_after: Option<Duration>,
+    phase: ReconnectPhase,
+}
+
+impl ReconnectingState {
+    fn new(chat: Box<LoggedInState>, reason: String, reconnect_after: Option<Duration>) -> ReconnectingState {
+        let delay = reconnect_after.unwrap_or_default() + reconnect_delay(1);
+        ReconnectingState {
+            chat,
+            reason,
+            attempt: 1,
+            last_error: None,
+            reconnect_after,
+            phase: ReconnectPhase::Waiting(Instant::now() + delay),
+        }
+    }
+
+    pub fn chat(&self) -> &LoggedInState {
+        &self.chat
+    }
+
+    pub fn address(&self) -> &PeerAddress {
+        &self.chat.connection_info.address
+    }
+
+    // How long the page may wait before calling tick
+    pub fn next_tick_in(&self) -> Duration {
+        match &self.phase {
+            ReconnectPhase::Waiting(at) => at.saturating_duration_since(Instant::now()),
+            ReconnectPhase::LoggingIn { .. } => Duration::ZERO,
+        }
+    }
+
+    // Stops reconnecting, the chat views are dropped
+    pub fn cancel(self) -> Client {
+        let chat = *self.chat;
+        Client::Disconnected(DisconnectedState {
+            connection_info: chat.connection_info,
+            reason: self.reason,
+            reconnect_after: self.reconnect_after,
+        })
+    }
+
+    // The server refused the login for good, i.e. the password was changed meanwhile
+    fn fail(self, reason: String) -> Client {
+        let chat = *self.chat;
+        Client::LoginFailed(LoginFailedState {
+            connection_info: chat.connection_info,
+            reason,
+        })
+    }
+
+    fn retry(mut self, error: String) -> Client {
+        self.last_error = Some(error);
+        self.attempt += 1;
+        self.phase = ReconnectPhase::Waiting(Instant::now() + reconnect_delay(self.attempt));
+        Client::Reconnecting(self)
+    }
+
+    pub fn tick(mut self, tls_options: &TlsOptions) -> Client {
+        match std::mem::replace(&mut self.phase, ReconnectPhase::Waiting(Instant::now())) {
+            ReconnectPhase::Waiting(at) if Instant::now() < at => {
+                self.phase = ReconnectPhase::Waiting(at);
+                Client::Reconnecting(self)
+            }
+            ReconnectPhase::Waiting(_) => {
+                let mut stream = match open_stream(self.address(), tls_options) {
+                    Ok(stream) => stream,
+                    Err(err) => return self.retry(err.to_string()),
+                };
+                let login_message = HandshakeMessage {
+                    username: self.chat.login_info.user.clone(),
+                    credentials: self.chat.credentials.clone(),
+                };
+                if let Err(reason) = send_handshake(&mut stream, &login_message) {
+                    return self.retry(reason);
+                }
+
+                self.phase = ReconnectPhase::LoggingIn {
+                    stream,
+                    receiver: PacketReceiver::new(),
+                    deadline: Instant::now() + RECONNECT_LOGIN_TIMEOUT,
+                };
+                Client::Reconnecting(self)
+            }
+            ReconnectPhase::LoggingIn {
+                mut stream,
+                mut receiver,
+                deadline,
+            } => {
+                if let Err(err) = receiver.advance(&mut stream) {
+                    return self.retry(err.to_string());
+                }
+
+                let data = match receiver.pop_packet() {
+                    Some(data) => data,
+                    None if Instant::now() >= deadline => {
+                        return self.retry("Server did not reply to login".to_string());
+                    }
+                    None => {
+                        self.phase = ReconnectPhase::LoggingIn {
+                            stream,
+                            receiver,
+                            deadline,
+                        };
+                        return Client::Reconnecting(self);
+                    }
+                };
+
+                match check_login_reply(&data) {
+                    Ok(session) => {
+                        self.chat.resume(stream, receiver, session);
+                        Client::LoggedIn(self.chat)
+                    }
+                    Err(failure) if failure.transient => self.retry(failure.reason),
+                    Err(failure) => self.fail(failure.reason),
+                }
+            }
+        }
+    }
+}
+
+//---------------------------------------------------------------------------------------------------
+
 pub struct LoginFailedState {
     pub connection_info: ConnectionInfo,
-    pub login_info: LoginInfo,
     pub reason: String,
 }
 
@@ -251,8 +916,9 @@ pub struct LoginFailedState {
 
 pub struct DisconnectedState {
     pub connection_info: ConnectionInfo,
-    pub login_info: LoginInfo,
     pub reason: String,
+    // when the server said it expects to be back
+    pub reconnect_after: Option<Duration>,
 }
 
 //---------------------------------------------------------------------------------------------------
@@ -261,8 +927,192 @@ pub enum Client {
     WaitingForConnectionInfo(WaitingForConnectionInfoState),
     Connected(ConnectedState),
     WaitingForLoginInfo(WaitingForLoginInfoState),
+    WaitingForLoginResponse(WaitingForLoginResponseState),
     ConnectionFailed(ConnectionFailedState),
-    LoggedIn(LoggedInState),
+    // boxed as it is much larger than the other states
+    LoggedIn(Box<LoggedInState>),
+    Reconnecting(ReconnectingState),
     LoginFailed(LoginFailedState),
     Disconnected(DisconnectedState),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_chat_server::{ChatServer, ServerConfig};
+
+    fn message(id: u64) -> MesasgeFromUser {
+        MesasgeFromUser {
+            username: "user".to_string(),
+            text: id.to_string(),
+            channel: protocol::DEFAULT_CHANNEL.to_string(),
+            id: Some(id),
+            timestamp_ms: None,
+        }
+    }
+
+    fn ids(view: &ChannelView) -> Vec<u64> {
+        view.messages.iter().filter_map(|message| message.id).collect()
+    }
+
+    fn history_page(ids: impl Iterator<Item = u64>) -> HistoryPage {
+        HistoryPage {
+            channel: protocol::DEFAULT_CHANNEL.to_string(),
+            messages: ids.map(message).collect(),
+            has_more: false,
+        }
+    }
+
+    #[test]
+    fn reconnect_delay_bounds() {
+        for attempt in 1..40 {
+            let ceiling = RECONNECT_BASE_DELAY
+                .saturating_mul(1 << (attempt - 1).min(16))
+                .min(RECONNECT_MAX_DELAY);
+            let delay = reconnect_delay(attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{attempt}: {delay:?}");
+        }
+        assert!(reconnect_delay(1) <= RECONNECT_BASE_DELAY);
+        assert!(reconnect_delay(100) >= RECONNECT_MAX_DELAY / 2);
+    }
+
+    #[test]
+    fn reconnect_delay_is_jittered() {
+        let delays: BTreeSet<Duration> = (0..20).map(|_| reconnect_delay(5)).collect();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn history_merges_without_duplicates() {
+        let mut view = ChannelView::new(protocol::DEFAULT_CHANNEL.to_string());
+        view.messages = vec![message(5), message(6)];
+
+        // the backfill after reconnecting overlaps and has messages missed meanwhile
+        view.add_history(history_page(4..=8));
+        assert_eq!(ids(&view), [4, 5, 6, 7, 8]);
+
+        // scrolling up prepends older messages
+        view.add_history(history_page(1..=4));
+        assert_eq!(ids(&view), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(!view.history_requested);
+    }
+
+    #[test]
+    fn classify_login_replies() {
+        let reply = |message: ServerMessage| check_login_reply(&protocol::encode(&message));
+        let accepted = |protocol_version| {
+            ServerMessage::LoginAccepted(LoginAccepted {
+                session_id: 1,
+                server_name: "server".to_string(),
+                protocol_version,
+                motd: None,
+            })
+        };
+        let rejected = |reason: &str, retryable| {
+            ServerMessage::LoginRejected(rust_chat::LoginRejected {
+                reason: reason.to_string(),
+                retryable,
+            })
+        };
+
+        assert!(reply(accepted(protocol::PROTOCOL_VERSION)).is_ok());
+        assert!(!reply(accepted(protocol::PROTOCOL_VERSION + 1)).unwrap_err().transient);
+        // decided by the server, whatever the reason says
+        for retryable in [true, false] {
+            let failure = reply(rejected("Too many failed logins", retryable)).unwrap_err();
+            assert_eq!(failure.transient, retryable);
+        }
+        assert!(!reply(ServerMessage::Pong).unwrap_err().transient);
+        assert!(!check_login_reply(b"garbage").unwrap_err().transient);
+    }
+
+    fn start_server(address: &str) -> ChatServer {
+        let config = ServerConfig {
+            tcp_address: Some(address.to_string()),
+            ..ServerConfig::default()
+        };
+        ChatServer::new(config).unwrap()
+    }
+
+    fn tick(client: Client) -> Client {
+        match client {
+            Client::WaitingForLoginResponse(state) => state.tick(),
+            Client::LoggedIn(state) => state.tick(),
+            Client::Reconnecting(state) => state.tick(&TlsOptions::default()),
+            client => client,
+        }
+    }
+
+    // Ticks the client and the server, if it is running, until the client is done
+    fn run_until(
+        mut client: Client,
+        mut server: Option<&mut ChatServer>,
+        done: impl Fn(&Client) -> bool,
+    ) -> Client {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done(&client) {
+            assert!(Instant::now() < deadline, "Client got stuck");
+            match server.as_deref_mut() {
+                Some(server) => {
+                    server.wait_for_events(Some(Duration::from_millis(10))).unwrap();
+                    server.tick();
+                }
+                None => std::thread::sleep(Duration::from_millis(10)),
+            }
+            client = tick(client);
+        }
+        client
+    }
+
+    #[test]
+    fn reconnects_to_restarted_server() {
+        let mut server = start_server("127.0.0.1:0");
+        let address = server.local_addr().unwrap().to_string();
+        let connection_info = ConnectionInfo {
+            address: PeerAddress::from_str(&address).unwrap(),
+            credentials: None,
+        };
+        let Client::Connected(connected) = try_connect(connection_info, &TlsOptions::default()) else {
+            panic!("Failed to connect");
+        };
+        let Client::WaitingForLoginInfo(mut login) = connected.begin_login() else {
+            unreachable!();
+        };
+        login.login_info.user = "alice".to_string();
+        let client = run_until(login.login(false), Some(&mut server), |client| {
+            matches!(client, Client::LoggedIn(chat) if !chat.channels.is_empty())
+        });
+
+        let Client::LoggedIn(mut chat) = client else {
+            unreachable!();
+        };
+        chat.current_input = "before restart".to_string();
+        chat.send_message();
+        let client = run_until(Client::LoggedIn(chat), Some(&mut server), |client| {
+            matches!(client, Client::LoggedIn(chat) if !chat.channels[0].messages.is_empty())
+        });
+
+        // nothing listens until the server is started again, so the first attempt fails
+        drop(server);
+        let client = run_until(client, None, |client| {
+            matches!(client, Client::Reconnecting(state) if state.attempt > 1)
+        });
+        let Client::Reconnecting(state) = &client else {
+            unreachable!();
+        };
+        assert!(state.last_error.is_some());
+        assert!(state.next_tick_in() > Duration::ZERO);
+        assert_eq!(state.chat().channels[0].messages.len(), 1);
+
+        let mut server = start_server(&address);
+        let client = run_until(client, Some(&mut server), |client| {
+            matches!(client, Client::LoggedIn(_))
+        });
+        let Client::LoggedIn(chat) = client else {
+            unreachable!();
+        };
+        let views: Vec<_> = chat.channels.iter().map(|view| view.name.as_str()).collect();
+        assert_eq!(views, [protocol::DEFAULT_CHANNEL]);
+        assert_eq!(chat.channels[0].messages[0].text, "before restart");
+    }
+}
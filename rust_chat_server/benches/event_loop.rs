@@ -0,0 +1,142 @@
+/* Measures how the server behaves when nothing happens and how fast it wakes up when
+ * something does:
+ *  - idle CPU usage of the process while the server waits for events
+ *  - latency between a client sending a message and another client receiving the broadcast
+ *
+ * Run with `cargo bench -p rust_chat_server --bench event_loop`
+ */
+use rust_chat::{
+    protocol, ClientMessage, Credentials, HandshakeMessage, MesasgeFromUser, PacketReceiver,
+    PacketSender, ServerMessage,
+};
+use rust_chat_server::{ChatServer, ServerConfig};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+const IDLE_MEASURE_TIME: Duration = Duration::from_secs(2);
+const LATENCY_SAMPLES: usize = 200;
+
+struct BenchClient {
+    stream: TcpStream,
+    sender: PacketSender,
+    receiver: PacketReceiver,
+}
+
+impl BenchClient {
+    fn login(address: SocketAddr, username: &str) -> BenchClient {
+        let stream = TcpStream::connect(address).expect("Failed to connect to server");
+        stream.set_nodelay(true).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut client = BenchClient {
+            stream,
+            sender: PacketSender::new(),
+            receiver: PacketReceiver::new(),
+        };
+
+        client.send(protocol::encode(&HandshakeMessage {
+            username: username.to_string(),
+            credentials: Credentials::Guest,
+        }));
+        match client.wait_for_message() {
+            ServerMessage::LoginAccepted(_) => client,
+            message => panic!("Login failed: {message:?}"),
+        }
+    }
+
+    fn send(&mut self, data: Vec<u8>) {
+        self.sender.add_to_send_queue(data);
+        while !self.sender.empty() {
+            self.sender.advance(&mut self.stream).expect("Failed to send");
+        }
+    }
+
+    // Spins on the non-blocking socket so that measured latency is not affected by the client
+    fn wait_for_message(&mut self) -> ServerMessage {
+        loop {
+            self.receiver.advance(&mut self.stream).expect("Failed to receive");
+            if let Some(data) = self.receiver.pop_packet() {
+                return protocol::decode(&data).expect("Failed to parse message");
+            }
+        }
+    }
+}
+
+fn process_cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    assert_eq!(result, 0, "getrusage failed");
+
+    let to_duration = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    to_duration(usage.ru_utime) + to_duration(usage.ru_stime)
+}
+
+fn measure_idle_cpu() {
+    let cpu_before = process_cpu_time();
+    std::thread::sleep(IDLE_MEASURE_TIME);
+    let cpu_used = process_cpu_time() - cpu_before;
+
+    println!(
+        "idle: {:.2?} of cpu time in {:.2?} ({:.2}% of one core)",
+        cpu_used,
+        IDLE_MEASURE_TIME,
+        100.0 * cpu_used.as_secs_f64() / IDLE_MEASURE_TIME.as_secs_f64()
+    );
+}
+
+fn measure_wakeup_latency(address: SocketAddr) {
+    let mut sender = BenchClient::login(address, "bench_sender");
+    let mut receiver = BenchClient::login(address, "bench_receiver");
+
+    let mut samples = Vec::with_capacity(LATENCY_SAMPLES);
+    for index in 0..LATENCY_SAMPLES {
+        // give the server time to fall asleep so every sample includes a wake up
+        std::thread::sleep(Duration::from_millis(5));
+
+        let message = ClientMessage::MessageFromUser(MesasgeFromUser {
+            username: "bench_sender".to_string(),
+            text: index.to_string(),
+            channel: protocol::DEFAULT_CHANNEL.to_string(),
+            id: None,
+            timestamp_ms: None,
+        });
+        let start = Instant::now();
+        sender.send(protocol::encode(&message));
+        loop {
+            if let ServerMessage::MessageFromUser(message) = receiver.wait_for_message() {
+                if message.text == index.to_string() {
+                    break;
+                }
+            }
+        }
+        samples.push(start.elapsed());
+    }
+
+    samples.sort();
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+    println!(
+        "wake up latency over {} messages: p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+        samples.len(),
+        percentile(50),
+        percentile(99),
+        samples[samples.len() - 1]
+    );
+}
+
+fn main() {
+    let config = ServerConfig {
+        tcp_address: Some("127.0.0.1:0".to_string()),
+        // samples are sent faster than any user would
+        rate_limits: None,
+        ..ServerConfig::default()
+    };
+    let mut server = ChatServer::new(config).expect("Failed to start server");
+    let address = server.local_addr().unwrap();
+
+    // the thread is dropped together with the process when measurements are done
+    std::thread::spawn(move || server.run().expect("Server failed"));
+
+    measure_idle_cpu();
+    measure_wakeup_latency(address);
+}
@@ -0,0 +1,179 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use log::error;
+use rust_chat::{ChatError, ChatResult, ConvertibleToChatResult};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::username_registry::normalize;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Argon2id hash in PHC string format with a random salt. Takes tens of milliseconds
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| format!("Failed to hash password: {err}"))
+}
+
+// Takes as long as hashing, hashes that can't be parsed never match
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/* Registered accounts with Argon2id password hashes in PHC string format.
+ * Accounts are kept in memory and, if the path is configured, appended to a file
+ * with one "<username>\t<hash>" line per account. Usernames never contain tabs or
+ * newlines as control characters are rejected by the username validation.
+ * Hashing is left to the caller so that it can run off the event loop
+ */
+pub struct AccountStore {
+    // keyed by normalized username
+    accounts: HashMap<String, String>,
+    path: Option<PathBuf>,
+    // verified for unknown users so that they take as long to reject as wrong passwords
+    dummy_hash: String,
+}
+
+impl AccountStore {
+    pub fn open(path: Option<&Path>) -> ChatResult<AccountStore> {
+        let mut store = AccountStore {
+            accounts: HashMap::new(),
+            path: path.map(|path| path.to_path_buf()),
+            dummy_hash: hash_password("no such account").map_err(ChatError)?,
+        };
+
+        let path = match path {
+            Some(path) if path.exists() => path,
+            _ => return Ok(store),
+        };
+
+        let content = std::fs::read_to_string(path).to_chat_result()?;
+        for (index, line) in content.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let (username, hash) = line.split_once('\t').ok_or_else(|| {
+                ChatError(format!("{}:{}: malformed account", path.display(), index + 1))
+            })?;
+            PasswordHash::new(hash).map_err(|err| {
+                ChatError(format!("{}:{}: invalid hash: {err}", path.display(), index + 1))
+            })?;
+            store.accounts.insert(normalize(username), hash.to_string());
+        }
+
+        Ok(store)
+    }
+
+    pub fn is_registered(&self, username: &str) -> bool {
+        self.accounts.contains_key(&normalize(username))
+    }
+
+    // Username is expected to be validated already. Returns the reason of rejection otherwise
+    pub fn check_new_account(&self, username: &str, password: &str) -> Result<(), String> {
+        if self.is_registered(username) {
+            return Err(format!("Username \"{username}\" is already registered"));
+        }
+
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(format!(
+                "Password must be at least {MIN_PASSWORD_LENGTH} characters long"
+            ));
+        }
+        Ok(())
+    }
+
+    // Stores the account checked by check_new_account with the hash of its password
+    pub fn add(&mut self, username: &str, hash: String) -> Result<(), String> {
+        if self.is_registered(username) {
+            return Err(format!("Username \"{username}\" is already registered"));
+        }
+
+        if let Some(path) = &self.path {
+            append_account(path, username, &hash).map_err(|err| {
+                error!("Failed to store account {username}: {err}");
+                "Failed to store account".to_string()
+            })?;
+        }
+
+        self.accounts.insert(normalize(username), hash);
+        Ok(())
+    }
+
+    /* Hash to verify the password of the user against. Unknown users get the dummy hash
+     * so that rejecting them takes as long as rejecting a wrong password
+     */
+    pub fn password_hash(&self, username: &str) -> &str {
+        self.accounts
+            .get(&normalize(username))
+            .unwrap_or(&self.dummy_hash)
+    }
+}
+
+fn append_account(path: &Path, username: &str, hash: &str) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{username}\t{hash}")?;
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(store: &mut AccountStore, username: &str, password: &str) -> Result<(), String> {
+        store.check_new_account(username, password)?;
+        store.add(username, hash_password(password)?)
+    }
+
+    #[test]
+    fn register_and_verify() {
+        let mut store = AccountStore::open(None).unwrap();
+        assert!(!store.is_registered("user"));
+
+        register(&mut store, "User", "correct horse").unwrap();
+        assert!(store.is_registered("user"));
+        assert!(verify_password(store.password_hash("user"), "correct horse"));
+        assert!(!verify_password(store.password_hash("user"), "wrong password"));
+        assert!(!verify_password(store.password_hash("other"), "correct horse"));
+
+        assert!(register(&mut store, "USER", "another password").is_err());
+        assert!(register(&mut store, "other", "short").is_err());
+    }
+
+    #[test]
+    fn hashes_are_argon2id() {
+        let mut store = AccountStore::open(None).unwrap();
+        register(&mut store, "user", "correct horse").unwrap();
+
+        let hash = &store.accounts["user"];
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(!hash.contains("correct horse"));
+    }
+
+    #[test]
+    fn persist_accounts() {
+        let path = std::env::temp_dir().join(format!("rust_chat_{}_accounts", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = AccountStore::open(Some(&path)).unwrap();
+        register(&mut store, "user", "correct horse").unwrap();
+        drop(store);
+
+        let store = AccountStore::open(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(verify_password(store.password_hash("user"), "correct horse"));
+    }
+}
@@ -0,0 +1,64 @@
+use mio::Waker;
+use rust_chat::{ChatResult, ConvertibleToChatResult};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+use crate::account_store::{hash_password, verify_password};
+
+pub enum AuthJob {
+    Verify { hash: String, password: String },
+    Hash { password: String },
+}
+
+pub enum AuthResult {
+    // whether the password matches the hash
+    Verified(bool),
+    // hash of the password or the reason hashing failed
+    Hashed(Result<String, String>),
+}
+
+/* Runs Argon2 on its own thread, hashing a password takes tens of milliseconds and would
+ * stall every connection if the event loop did it. The waker wakes the poll up once a
+ * result is ready. The thread ends after the worker is dropped
+ */
+pub struct AuthWorker {
+    jobs: Sender<(u64, AuthJob)>,
+    results: Receiver<(u64, AuthResult)>,
+}
+
+impl AuthWorker {
+    pub fn spawn(waker: Arc<Waker>) -> ChatResult<AuthWorker> {
+        let (jobs, pending_jobs) = mpsc::channel::<(u64, AuthJob)>();
+        let (finished, results) = mpsc::channel();
+
+        std::thread::Builder::new()
+            .name("auth".to_string())
+            .spawn(move || {
+                for (id, job) in pending_jobs {
+                    let result = match job {
+                        AuthJob::Verify { hash, password } => {
+                            AuthResult::Verified(verify_password(&hash, &password))
+                        }
+                        AuthJob::Hash { password } => AuthResult::Hashed(hash_password(&password)),
+                    };
+                    if finished.send((id, result)).is_err() {
+                        break;
+                    }
+                    let _ = waker.wake();
+                }
+            })
+            .to_chat_result()?;
+
+        Ok(AuthWorker { jobs, results })
+    }
+
+    // The result is returned by take_results under the same id
+    pub fn submit(&self, id: u64, job: AuthJob) {
+        // the thread only ends once the worker is dropped
+        let _ = self.jobs.send((id, job));
+    }
+
+    pub fn take_results(&self) -> Vec<(u64, AuthResult)> {
+        self.results.try_iter().collect()
+    }
+}
@@ -0,0 +1,193 @@
+use rust_chat::ChannelInfo;
+use std::collections::{BTreeMap, HashSet};
+
+pub const MAX_CHANNEL_NAME_LENGTH: usize = 32;
+// Including the permanent ones
+pub const MAX_CHANNELS: usize = 256;
+
+/* Named channels and sessions that joined them.
+ * Members are identified by session id as connection indices change when
+ * closed connections are removed. Channels are dropped when the last member
+ * leaves unless they are permanent, their history is reachable again once
+ * the channel is created anew.
+ */
+pub struct ChannelRegistry {
+    channels: BTreeMap<String, HashSet<u64>>,
+    permanent: HashSet<String>,
+}
+
+impl ChannelRegistry {
+    // Permanent channels, i.e. the default one, are created right away
+    pub fn new(permanent_channels: &[&str]) -> ChannelRegistry {
+        ChannelRegistry {
+            channels: permanent_channels
+                .iter()
+                .map(|name| (name.to_string(), HashSet::new()))
+                .collect(),
+            permanent: permanent_channels.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    // Creates the channel and joins it. Returns the reason of rejection otherwise
+    pub fn create(&mut self, channel: &str, session_id: u64) -> Result<(), String> {
+        validate_channel_name(channel)?;
+        if self.channels.contains_key(channel) {
+            return Err(format!("Channel \"{channel}\" already exists"));
+        }
+        if self.channels.len() >= MAX_CHANNELS {
+            return Err(format!("There are {MAX_CHANNELS} channels already, join one of them"));
+        }
+
+        self.channels
+            .insert(channel.to_string(), HashSet::from([session_id]));
+        Ok(())
+    }
+
+    pub fn join(&mut self, channel: &str, session_id: u64) -> Result<(), String> {
+        let members = self
+            .channels
+            .get_mut(channel)
+            .ok_or_else(|| format!("Channel \"{channel}\" does not exist"))?;
+
+        if !members.insert(session_id) {
+            return Err(format!("Already joined channel \"{channel}\""));
+        }
+        Ok(())
+    }
+
+    pub fn leave(&mut self, channel: &str, session_id: u64) -> Result<(), String> {
+        let removed = self
+            .channels
+            .get_mut(channel)
+            .is_some_and(|members| members.remove(&session_id));
+        if !removed {
+            return Err(format!("Not a member of channel \"{channel}\""));
+        }
+        self.drop_empty_channels();
+        Ok(())
+    }
+
+    // Removes closed session from all channels
+    pub fn leave_all(&mut self, session_id: u64) {
+        for members in self.channels.values_mut() {
+            members.remove(&session_id);
+        }
+        self.drop_empty_channels();
+    }
+
+    fn drop_empty_channels(&mut self) {
+        self.channels
+            .retain(|name, members| !members.is_empty() || self.permanent.contains(name));
+    }
+
+    pub fn is_member(&self, channel: &str, session_id: u64) -> bool {
+        self.channels
+            .get(channel)
+            .is_some_and(|members| members.contains(&session_id))
+    }
+
+    // Channels ordered by name
+    pub fn list(&self) -> Vec<ChannelInfo> {
+        self.channels
+            .iter()
+            .map(|(name, members)| ChannelInfo {
+                name: name.clone(),
+                members: members.len() as u32,
+            })
+            .collect()
+    }
+}
+
+// Channel names are lowercase so that lookups need no normalization
+fn validate_channel_name(channel: &str) -> Result<(), String> {
+    if channel.is_empty() || channel.len() > MAX_CHANNEL_NAME_LENGTH {
+        return Err(format!(
+            "Channel name must be 1 to {MAX_CHANNEL_NAME_LENGTH} characters long"
+        ));
+    }
+
+    let valid_character = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+    if !channel.chars().all(valid_character) {
+        return Err(
+            "Channel name may contain only lowercase letters, digits, '-' and '_'".to_string(),
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_join_leave() {
+        let mut channels = ChannelRegistry::new(&["general"]);
+        channels.join("general", 1).unwrap();
+        channels.create("random", 2).unwrap();
+        channels.join("random", 1).unwrap();
+
+        assert!(channels.is_member("random", 1));
+        assert!(channels.is_member("random", 2));
+        assert!(!channels.is_member("general", 2));
+
+        assert!(channels.join("random", 1).is_err());
+        assert!(channels.create("random", 3).is_err());
+        assert!(channels.join("missing", 1).is_err());
+
+        channels.leave("random", 1).unwrap();
+        assert!(!channels.is_member("random", 1));
+        assert!(channels.leave("random", 1).is_err());
+    }
+
+    #[test]
+    fn leave_all_drops_empty_channels() {
+        let mut channels = ChannelRegistry::new(&["general"]);
+        channels.join("general", 1).unwrap();
+        channels.create("random", 1).unwrap();
+        channels.create("shared", 1).unwrap();
+        channels.join("shared", 2).unwrap();
+        channels.leave_all(1);
+
+        let list = channels.list();
+        assert_eq!(
+            list,
+            vec![
+                ChannelInfo {
+                    name: "general".to_string(),
+                    members: 0
+                },
+                ChannelInfo {
+                    name: "shared".to_string(),
+                    members: 1
+                },
+            ]
+        );
+
+        // the name is free again
+        channels.leave("shared", 2).unwrap();
+        channels.create("shared", 3).unwrap();
+    }
+
+    #[test]
+    fn limit_channel_count() {
+        let mut channels = ChannelRegistry::new(&["general"]);
+        for index in 1..MAX_CHANNELS {
+            channels.create(&index.to_string(), 1).unwrap();
+        }
+        assert!(channels.create("one-too-many", 1).is_err());
+
+        channels.leave("1", 1).unwrap();
+        channels.create("one-too-many", 1).unwrap();
+    }
+
+    #[test]
+    fn validate_names() {
+        let mut channels = ChannelRegistry::new(&[]);
+        assert!(channels.create("", 1).is_err());
+        assert!(channels.create("Random", 1).is_err());
+        assert!(channels.create("with space", 1).is_err());
+        assert!(channels.create(&"a".repeat(MAX_CHANNEL_NAME_LENGTH + 1), 1).is_err());
+        assert!(channels.create("rust-chat_2", 1).is_ok());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::config::ServerConfig;
+use crate::config_file::ConfigFile;
+use clap::Parser;
+use log::LevelFilter;
+use rust_chat::ChatResult;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Options given on the command line override the config file
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "Chat server")]
+pub struct Options {
+    /// TOML config file, see --print-default-config for the available keys
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Print the default config file and exit
+    #[arg(long)]
+    pub print_default_config: bool,
+
+    /// Address of the TCP listener, i.e. 0.0.0.0:8787
+    #[arg(long, value_name = "ADDRESS")]
+    pub tcp_address: Option<String>,
+
+    /// Path of the unix domain socket to listen on
+    #[arg(long, value_name = "PATH")]
+    pub unix_socket_path: Option<PathBuf>,
+
+    /// Frames with payload of this many bytes or more are rejected
+    #[arg(long, value_name = "BYTES")]
+    pub max_packet_size: Option<u32>,
+
+    /// Bytes written to a connection at once
+    #[arg(long, value_name = "BYTES")]
+    pub max_write_size: Option<usize>,
+
+    /// Seconds a new connection has to send the handshake
+    #[arg(long, value_name = "SECONDS")]
+    pub handshake_timeout: Option<u64>,
+
+    /// Seconds of silence after which clients are pinged
+    #[arg(long, value_name = "SECONDS")]
+    pub idle_timeout: Option<u64>,
+
+    /// Seconds of silence after which clients are disconnected
+    #[arg(long, value_name = "SECONDS")]
+    pub dead_peer_timeout: Option<u64>,
+
+    /// Disable rate limits of messages sent by clients
+    #[arg(long)]
+    pub no_rate_limits: bool,
+
+    /// File with registered accounts
+    #[arg(long, value_name = "FILE")]
+    pub accounts_path: Option<PathBuf>,
+
+    /// SQLite database messages are recorded in
+    #[arg(long, value_name = "FILE")]
+    pub history_path: Option<PathBuf>,
+
+    /// off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL", value_parser = parse_level)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Message of the day shown to users after login
+    #[arg(long, value_name = "TEXT")]
+    pub motd: Option<String>,
+}
+
+fn parse_level(value: &str) -> Result<LevelFilter, String> {
+    value
+        .parse()
+        .map_err(|_| "expected one of off, error, warn, info, debug or trace".to_string())
+}
+
+impl Options {
+    // Config file if given, defaults otherwise, with the command line options applied
+    pub fn load_config(&self) -> ChatResult<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::from(ConfigFile::load(path)?),
+            None => ServerConfig::default(),
+        };
+
+        if let Some(address) = &self.tcp_address {
+            config.tcp_address = Some(address.clone());
+        }
+        if let Some(path) = &self.unix_socket_path {
+            config.unix_socket_path = Some(path.clone());
+        }
+        if let Some(size) = self.max_packet_size {
+            config.max_packet_size = size;
+        }
+        if let Some(size) = self.max_write_size {
+            config.max_write_size = size;
+        }
+        if let Some(secs) = self.handshake_timeout {
+            config.handshake_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.idle_timeout {
+            config.idle_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.dead_peer_timeout {
+            config.dead_peer_timeout = Duration::from_secs(secs);
+        }
+        if self.no_rate_limits {
+            config.rate_limits = None;
+        }
+        if let Some(path) = &self.accounts_path {
+            config.accounts_path = Some(path.clone());
+        }
+        if let Some(path) = &self.history_path {
+            config.history_path = Some(path.clone());
+        }
+        if let Some(level) = self.log_level {
+            config.log_level = level;
+        }
+        if let Some(motd) = &self.motd {
+            config.motd = Some(motd.clone());
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_chat::ChatError;
+
+    #[test]
+    fn options_override_config_file() {
+        let path = std::env::temp_dir().join(format!("rust_chat_cli_{}.toml", std::process::id()));
+        std::fs::write(&path, "motd = \"From file\"\n[timeouts]\nidle_secs = 5\n").unwrap();
+
+        let options = Options::try_parse_from([
+            "rust_chat_server",
+            "--config",
+            path.to_str().unwrap(),
+            "--tcp-address",
+            "0.0.0.0:9000",
+            "--motd",
+            "From command line",
+            "--log-level",
+            "warn",
+        ])
+        .unwrap();
+        let config = options.load_config().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.tcp_address.as_deref(), Some("0.0.0.0:9000"));
+        assert_eq!(config.motd.as_deref(), Some("From command line"));
+        assert_eq!(config.idle_timeout, Duration::from_secs(5));
+        assert_eq!(config.log_level, LevelFilter::Warn);
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        let options = Options {
+            idle_timeout: Some(100),
+            ..Options::default()
+        };
+        let Err(ChatError(err)) = options.load_config() else {
+            panic!("Invalid config was accepted");
+        };
+        assert!(err.contains("dead peer timeout"), "{err}");
+
+        let options = Options {
+            config: Some(PathBuf::from("/nonexistent/rust_chat.toml")),
+            ..Options::default()
+        };
+        let Err(ChatError(err)) = options.load_config() else {
+            panic!("Invalid config was accepted");
+        };
+        assert!(
+            err.starts_with("Failed to read config file /nonexistent/rust_chat.toml"),
+            "{err}"
+        );
+    }
+}
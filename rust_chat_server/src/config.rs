@@ -0,0 +1,274 @@
+use crate::rate_limit::RateLimits;
+use log::LevelFilter;
+use rust_chat::frame_header::MAX_PACKET_SIZE;
+use rust_chat::packet_sender::DEFAULT_MAX_WRITE_SIZE;
+use rust_chat::ChatError;
+use rust_chat::ChatResult;
+use rust_chat::MinReceiveRate;
+use rust_chat::OverflowPolicy;
+use rust_chat::SendQueueLimits;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// PEM files of the certificate chain and private key the server presents to clients
+pub struct TlsFiles {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+pub struct ServerConfig {
+    // TCP address to listen on, None disables the TCP listener
+    pub tcp_address: Option<String>,
+
+    // Enables TLS on the TCP listener.
+    // Unix socket stays plaintext as it never leaves the machine
+    pub tls: Option<TlsFiles>,
+
+    // Path of the unix domain socket to listen on, None disables the socket
+    pub unix_socket_path: Option<PathBuf>,
+
+    // Permissions of the socket file. Only users allowed to write to it can connect
+    pub unix_socket_mode: u32,
+
+    // Name reported to clients when login is accepted
+    pub server_name: String,
+
+    // Message of the day sent to clients when login is accepted
+    pub motd: Option<String>,
+
+    // Most detailed messages logged by run_app, the logger is global to the process
+    pub log_level: LevelFilter,
+
+    // Connections sending a frame with payload of this many bytes or more are closed.
+    // At most MAX_PACKET_SIZE, clients do not accept larger frames
+    pub max_packet_size: u32,
+
+    // Bytes written to a connection at once, the rest waits for the next round
+    pub max_write_size: usize,
+
+    // Usernames nobody can log in with
+    pub reserved_usernames: Vec<String>,
+
+    // Whether users can log in without registered account.
+    // Names of registered accounts always require the password
+    pub allow_guests: bool,
+
+    // File with registered accounts. None keeps accounts in memory only
+    pub accounts_path: Option<PathBuf>,
+
+    // SQLite database messages are recorded in. None disables the history
+    pub history_path: Option<PathBuf>,
+
+    // Messages older than this are deleted from the history. None keeps them forever
+    pub history_retention: Option<Duration>,
+
+    // Number of latest messages sent to users right after login
+    pub history_backfill: u32,
+
+    // Clients silent for this long are pinged
+    pub idle_timeout: Duration,
+
+    // Clients silent for this long are disconnected, even if the socket looks alive
+    pub dead_peer_timeout: Duration,
+
+    // Connections that have not sent the handshake within this time are closed
+    pub handshake_timeout: Duration,
+
+    // How long the server waits for clients to receive the shutdown notice
+    pub shutdown_timeout: Duration,
+
+    // Sent to clients on shutdown as the time after which the server is expected back.
+    // None if the server is not going to be restarted
+    pub shutdown_reconnect_after: Option<Duration>,
+
+    // Connections accepted while this many from the same peer are waiting for the handshake are dropped.
+    // Peers are told apart by IP address, or by user id for local sockets
+    pub max_pending_handshakes: usize,
+
+    // Connections receiving a frame slower than this are closed. None disables the check
+    pub min_receive_rate: Option<MinReceiveRate>,
+
+    // Limits on messages and bytes each client may send. None disables them
+    pub rate_limits: Option<RateLimits>,
+
+    // Bounds of the queue of messages waiting for each client to read them.
+    // Chat messages are the only ones dropped by DropNonEssential. None leaves the queues unbounded
+    pub send_queue_limits: Option<SendQueueLimits>,
+}
+
+// Limits enabled by default. The config file shows them for disabled limits too
+pub const DEFAULT_MIN_RECEIVE_RATE: MinReceiveRate = MinReceiveRate {
+    bytes_per_second: 1024,
+    grace_period: Duration::from_secs(10),
+};
+pub const DEFAULT_SEND_QUEUE_LIMITS: SendQueueLimits = SendQueueLimits {
+    max_packets: 1024,
+    max_bytes: 1024 * 1024,
+    policy: OverflowPolicy::DropNonEssential,
+};
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            tcp_address: Some("127.0.0.1:8787".to_string()),
+            tls: None,
+            unix_socket_path: None,
+            unix_socket_mode: 0o600,
+            server_name: "rust_chat_server".to_string(),
+            motd: None,
+            log_level: LevelFilter::Info,
+            max_packet_size: MAX_PACKET_SIZE,
+            max_write_size: DEFAULT_MAX_WRITE_SIZE,
+            reserved_usernames: ["admin", "administrator", "server", "system", "moderator"]
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            allow_guests: true,
+            accounts_path: None,
+            history_path: None,
+            history_retention: None,
+            history_backfill: 50,
+            idle_timeout: Duration::from_secs(30),
+            dead_peer_timeout: Duration::from_secs(90),
+            handshake_timeout: Duration::from_secs(10),
+            shutdown_timeout: Duration::from_secs(5),
+            shutdown_reconnect_after: None,
+            max_pending_handshakes: 128,
+            min_receive_rate: Some(DEFAULT_MIN_RECEIVE_RATE),
+            rate_limits: Some(RateLimits::default()),
+            send_queue_limits: Some(DEFAULT_SEND_QUEUE_LIMITS),
+        }
+    }
+}
+
+// Smallest max_packet_size accepted, the handshake and replies to it have to fit
+const MIN_PACKET_SIZE: u32 = 1024;
+
+impl ServerConfig {
+    // Reports every problem found, not only the first one
+    pub fn validate(&self) -> ChatResult<()> {
+        let mut problems = Vec::new();
+
+        if self.tcp_address.is_none() && self.unix_socket_path.is_none() {
+            problems.push("neither TCP address nor unix socket path is configured".to_string());
+        }
+        if self.tcp_address.as_deref().is_some_and(str::is_empty) {
+            problems.push("TCP address is empty".to_string());
+        }
+        if self.server_name.is_empty() {
+            problems.push("server name is empty".to_string());
+        }
+        if self.max_packet_size < MIN_PACKET_SIZE {
+            problems.push(format!(
+                "max packet size {} is smaller than {MIN_PACKET_SIZE} bytes",
+                self.max_packet_size
+            ));
+        }
+        // clients close the connection when they receive a larger frame
+        if self.max_packet_size > MAX_PACKET_SIZE {
+            problems.push(format!(
+                "max packet size {} is larger than {MAX_PACKET_SIZE} bytes clients accept",
+                self.max_packet_size
+            ));
+        }
+        if self.max_write_size == 0 {
+            problems.push("max write size is zero".to_string());
+        }
+        if let Some(motd) = &self.motd {
+            // the rest of LoginAccepted needs some room as well
+            if motd.len() + 256 > self.max_packet_size as usize {
+                problems.push("message of the day does not fit in a packet".to_string());
+            }
+        }
+
+        for (name, timeout) in [
+            ("idle timeout", self.idle_timeout),
+            ("dead peer timeout", self.dead_peer_timeout),
+            ("handshake timeout", self.handshake_timeout),
+        ] {
+            if timeout.is_zero() {
+                problems.push(format!("{name} is zero"));
+            }
+        }
+        if self.dead_peer_timeout <= self.idle_timeout {
+            problems.push(format!(
+                "dead peer timeout {:?} is not longer than idle timeout {:?}",
+                self.dead_peer_timeout, self.idle_timeout
+            ));
+        }
+        if self.history_retention.is_some_and(|retention| retention.is_zero()) {
+            problems.push("history retention is zero".to_string());
+        }
+        if self.max_pending_handshakes == 0 {
+            problems.push("max pending handshakes is zero".to_string());
+        }
+        if self.min_receive_rate.is_some_and(|rate| rate.bytes_per_second == 0) {
+            problems.push("minimum receive rate is zero".to_string());
+        }
+
+        if let Some(limits) = &self.rate_limits {
+            let rates = [limits.messages_per_second, limits.bytes_per_second];
+            if rates.iter().any(|rate| rate.is_nan() || *rate <= 0.0) {
+                problems.push("rate limits must allow more than nothing per second".to_string());
+            }
+            if limits.message_burst == 0 || limits.byte_burst == 0 {
+                problems.push("rate limit bursts are zero".to_string());
+            }
+            if limits.byte_burst < self.max_packet_size {
+                problems.push(format!(
+                    "rate limit byte burst {} is smaller than max packet size {}",
+                    limits.byte_burst, self.max_packet_size
+                ));
+            }
+        }
+
+        if let Some(limits) = &self.send_queue_limits {
+            if limits.max_packets == 0 || limits.max_bytes == 0 {
+                problems.push("send queue limits are zero".to_string());
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(ChatError(format!("Invalid configuration:\n  - {}", problems.join("\n  - "))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        assert!(ServerConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn report_all_problems() {
+        let config = ServerConfig {
+            tcp_address: None,
+            idle_timeout: Duration::from_secs(90),
+            max_packet_size: 10,
+            ..ServerConfig::default()
+        };
+        let ChatError(message) = config.validate().unwrap_err();
+        assert_eq!(
+            message,
+            "Invalid configuration:\n  \
+             - neither TCP address nor unix socket path is configured\n  \
+             - max packet size 10 is smaller than 1024 bytes\n  \
+             - dead peer timeout 90s is not longer than idle timeout 90s"
+        );
+    }
+
+    #[test]
+    fn packet_size_within_client_limit() {
+        let config = ServerConfig {
+            max_packet_size: MAX_PACKET_SIZE + 1,
+            ..ServerConfig::default()
+        };
+        let ChatError(message) = config.validate().unwrap_err();
+        assert!(message.contains("clients accept"), "{message}");
+    }
+}
@@ -0,0 +1,384 @@
+use crate::config::{ServerConfig, TlsFiles, DEFAULT_MIN_RECEIVE_RATE, DEFAULT_SEND_QUEUE_LIMITS};
+use crate::rate_limit::RateLimits;
+use log::LevelFilter;
+use rust_chat::{ChatError, ChatResult, MinReceiveRate, OverflowPolicy, SendQueueLimits};
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/* Layout of the TOML config file.
+ * Keys missing from the file keep their default values, except in [listen]:
+ * the listeners given there replace the default ones
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub server_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+    // off, error, warn, info, debug or trace
+    pub log_level: LevelFilter,
+    pub max_packet_size: u32,
+    pub max_write_size: usize,
+    pub max_pending_handshakes: usize,
+    pub reserved_usernames: Vec<String>,
+    pub allow_guests: bool,
+    pub listen: ListenSection,
+    pub storage: StorageSection,
+    pub timeouts: TimeoutsSection,
+    pub min_receive_rate: MinReceiveRateSection,
+    pub rate_limits: RateLimitsSection,
+    pub send_queue: SendQueueSection,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenSection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unix_socket_path: Option<PathBuf>,
+    #[serde(default = "default_unix_socket_mode")]
+    pub unix_socket_mode: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsSection>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accounts_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_retention_days: Option<u64>,
+    pub history_backfill: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsSection {
+    pub handshake_secs: u64,
+    pub idle_secs: u64,
+    pub dead_peer_secs: u64,
+    pub shutdown_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shutdown_reconnect_after_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MinReceiveRateSection {
+    pub enabled: bool,
+    pub bytes_per_second: u32,
+    pub grace_period_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitsSection {
+    pub enabled: bool,
+    pub messages_per_second: f64,
+    pub message_burst: u32,
+    pub bytes_per_second: f64,
+    pub byte_burst: u32,
+    pub mute_secs: u64,
+    pub violation_expiry_secs: u64,
+    pub escalation_cooldown_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SendQueueSection {
+    pub enabled: bool,
+    pub max_packets: usize,
+    pub max_bytes: usize,
+    #[serde(with = "OverflowPolicyName")]
+    pub policy: OverflowPolicy,
+}
+
+// OverflowPolicy is written in snake case, i.e. drop_non_essential
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "OverflowPolicy", rename_all = "snake_case")]
+enum OverflowPolicyName {
+    DropOldest,
+    DropNonEssential,
+    Disconnect,
+}
+
+fn default_unix_socket_mode() -> u32 {
+    ServerConfig::default().unix_socket_mode
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> ChatResult<ConfigFile> {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            ChatError(format!(
+                "Failed to read config file {}: {err}",
+                path.display()
+            ))
+        })?;
+        ConfigFile::parse(&text).map_err(|ChatError(err)| {
+            ChatError(format!("Invalid config file {}: {err}", path.display()))
+        })
+    }
+
+    pub fn parse(text: &str) -> ChatResult<ConfigFile> {
+        toml::from_str(text).map_err(|err| ChatError(err.to_string()))
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("Config file is always serializable")
+    }
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        ConfigFile::from(&ServerConfig::default())
+    }
+}
+
+impl Default for ListenSection {
+    fn default() -> Self {
+        ConfigFile::default().listen
+    }
+}
+
+impl Default for StorageSection {
+    fn default() -> Self {
+        ConfigFile::default().storage
+    }
+}
+
+impl Default for TimeoutsSection {
+    fn default() -> Self {
+        ConfigFile::default().timeouts
+    }
+}
+
+impl Default for MinReceiveRateSection {
+    fn default() -> Self {
+        ConfigFile::default().min_receive_rate
+    }
+}
+
+impl Default for RateLimitsSection {
+    fn default() -> Self {
+        ConfigFile::default().rate_limits
+    }
+}
+
+impl Default for SendQueueSection {
+    fn default() -> Self {
+        ConfigFile::default().send_queue
+    }
+}
+
+impl From<&ServerConfig> for ConfigFile {
+    fn from(config: &ServerConfig) -> Self {
+        // disabled sections still show the default values, so they are easy to enable
+        let min_receive_rate = config.min_receive_rate.unwrap_or(DEFAULT_MIN_RECEIVE_RATE);
+        let rate_limits = config.rate_limits.clone().unwrap_or_default();
+        let send_queue = config.send_queue_limits.unwrap_or(DEFAULT_SEND_QUEUE_LIMITS);
+
+        ConfigFile {
+            server_name: config.server_name.clone(),
+            motd: config.motd.clone(),
+            log_level: config.log_level,
+            max_packet_size: config.max_packet_size,
+            max_write_size: config.max_write_size,
+            max_pending_handshakes: config.max_pending_handshakes,
+            reserved_usernames: config.reserved_usernames.clone(),
+            allow_guests: config.allow_guests,
+            listen: ListenSection {
+                tcp_address: config.tcp_address.clone(),
+                unix_socket_path: config.unix_socket_path.clone(),
+                unix_socket_mode: config.unix_socket_mode,
+                tls: config.tls.as_ref().map(|files| TlsSection {
+                    cert_path: files.cert_path.clone(),
+                    key_path: files.key_path.clone(),
+                }),
+            },
+            storage: StorageSection {
+                accounts_path: config.accounts_path.clone(),
+                history_path: config.history_path.clone(),
+                history_retention_days: config
+                    .history_retention
+                    .map(|retention| retention.as_secs() / SECONDS_PER_DAY),
+                history_backfill: config.history_backfill,
+            },
+            timeouts: TimeoutsSection {
+                handshake_secs: config.handshake_timeout.as_secs(),
+                idle_secs: config.idle_timeout.as_secs(),
+                dead_peer_secs: config.dead_peer_timeout.as_secs(),
+                shutdown_secs: config.shutdown_timeout.as_secs(),
+                shutdown_reconnect_after_secs: config
+                    .shutdown_reconnect_after
+                    .map(|after| after.as_secs()),
+            },
+            min_receive_rate: MinReceiveRateSection {
+                enabled: config.min_receive_rate.is_some(),
+                bytes_per_second: min_receive_rate.bytes_per_second,
+                grace_period_secs: min_receive_rate.grace_period.as_secs(),
+            },
+            rate_limits: RateLimitsSection {
+                enabled: config.rate_limits.is_some(),
+                messages_per_second: rate_limits.messages_per_second,
+                message_burst: rate_limits.message_burst,
+                bytes_per_second: rate_limits.bytes_per_second,
+                byte_burst: rate_limits.byte_burst,
+                mute_secs: rate_limits.mute_duration.as_secs(),
+                violation_expiry_secs: rate_limits.violation_expiry.as_secs(),
+                escalation_cooldown_secs: rate_limits.escalation_cooldown.as_secs(),
+            },
+            send_queue: SendQueueSection {
+                enabled: config.send_queue_limits.is_some(),
+                max_packets: send_queue.max_packets,
+                max_bytes: send_queue.max_bytes,
+                policy: send_queue.policy,
+            },
+        }
+    }
+}
+
+impl From<ConfigFile> for ServerConfig {
+    fn from(file: ConfigFile) -> Self {
+        let rate_limits = file.rate_limits;
+        ServerConfig {
+            tcp_address: file.listen.tcp_address,
+            tls: file.listen.tls.map(|tls| TlsFiles {
+                cert_path: tls.cert_path,
+                key_path: tls.key_path,
+            }),
+            unix_socket_path: file.listen.unix_socket_path,
+            unix_socket_mode: file.listen.unix_socket_mode,
+            server_name: file.server_name,
+            motd: file.motd,
+            log_level: file.log_level,
+            max_packet_size: file.max_packet_size,
+            max_write_size: file.max_write_size,
+            reserved_usernames: file.reserved_usernames,
+            allow_guests: file.allow_guests,
+            accounts_path: file.storage.accounts_path,
+            history_path: file.storage.history_path,
+            history_retention: file
+                .storage
+                .history_retention_days
+                .map(|days| Duration::from_secs(days * SECONDS_PER_DAY)),
+            history_backfill: file.storage.history_backfill,
+            idle_timeout: Duration::from_secs(file.timeouts.idle_secs),
+            dead_peer_timeout: Duration::from_secs(file.timeouts.dead_peer_secs),
+            handshake_timeout: Duration::from_secs(file.timeouts.handshake_secs),
+            shutdown_timeout: Duration::from_secs(file.timeouts.shutdown_secs),
+            shutdown_reconnect_after: file
+                .timeouts
+                .shutdown_reconnect_after_secs
+                .map(Duration::from_secs),
+            max_pending_handshakes: file.max_pending_handshakes,
+            min_receive_rate: file.min_receive_rate.enabled.then(|| MinReceiveRate {
+                bytes_per_second: file.min_receive_rate.bytes_per_second,
+                grace_period: Duration::from_secs(file.min_receive_rate.grace_period_secs),
+            }),
+            rate_limits: rate_limits.enabled.then(|| RateLimits {
+                messages_per_second: rate_limits.messages_per_second,
+                message_burst: rate_limits.message_burst,
+                bytes_per_second: rate_limits.bytes_per_second,
+                byte_burst: rate_limits.byte_burst,
+                mute_duration: Duration::from_secs(rate_limits.mute_secs),
+                violation_expiry: Duration::from_secs(rate_limits.violation_expiry_secs),
+                escalation_cooldown: Duration::from_secs(rate_limits.escalation_cooldown_secs),
+            }),
+            send_queue_limits: file.send_queue.enabled.then_some(SendQueueLimits {
+                max_packets: file.send_queue.max_packets,
+                max_bytes: file.send_queue.max_bytes,
+                policy: file.send_queue.policy,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_round_trip() {
+        let text = ConfigFile::default().to_toml();
+        assert_eq!(ConfigFile::parse(&text).unwrap(), ConfigFile::default());
+        assert!(ServerConfig::from(ConfigFile::parse("").unwrap())
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn missing_keys_keep_defaults() {
+        let file = ConfigFile::parse(
+            r#"
+            motd = "Welcome"
+            log_level = "debug"
+
+            [timeouts]
+            idle_secs = 5
+
+            [rate_limits]
+            enabled = false
+
+            [send_queue]
+            policy = "disconnect"
+            "#,
+        )
+        .unwrap();
+        let config = ServerConfig::from(file);
+        let default = ServerConfig::default();
+
+        assert_eq!(config.motd.as_deref(), Some("Welcome"));
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(config.idle_timeout, Duration::from_secs(5));
+        assert_eq!(config.dead_peer_timeout, default.dead_peer_timeout);
+        assert_eq!(config.tcp_address, default.tcp_address);
+        assert_eq!(config.rate_limits, None);
+        assert_eq!(
+            config.send_queue_limits.unwrap().policy,
+            OverflowPolicy::Disconnect
+        );
+        assert_eq!(config.send_queue_limits.unwrap().max_packets, 1024);
+    }
+
+    #[test]
+    fn listen_section_replaces_listeners() {
+        let file = ConfigFile::parse(
+            r#"
+            [listen]
+            unix_socket_path = "/tmp/chat.sock"
+            "#,
+        )
+        .unwrap();
+        let config = ServerConfig::from(file);
+        assert_eq!(config.tcp_address, None);
+        assert_eq!(
+            config.unix_socket_path,
+            Some(PathBuf::from("/tmp/chat.sock"))
+        );
+        assert_eq!(config.unix_socket_mode, 0o600);
+    }
+
+    #[test]
+    fn readable_errors() {
+        let ChatError(err) = ConfigFile::parse("max_packet_sise = 100").unwrap_err();
+        assert!(err.contains("unknown field `max_packet_sise`"), "{err}");
+
+        let ChatError(err) = ConfigFile::parse("[send_queue]\npolicy = \"drop\"").unwrap_err();
+        assert!(err.contains("line 2"), "{err}");
+        assert!(err.contains("drop_non_essential"), "{err}");
+    }
+}
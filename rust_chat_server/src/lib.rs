@@ -1,55 +1,398 @@
-use rust_chat;
+mod account_store;
+mod auth_worker;
+mod channels;
+mod cli;
+mod config;
+mod config_file;
+mod listener;
+mod logger;
+mod message_history;
+mod rate_limit;
+mod shutdown;
+mod username_registry;
+
+use rust_chat::ChatError;
 use rust_chat::ChatResult;
 use rust_chat::Connection;
+use rust_chat::HandshakeState;
+use rust_chat::ConnectionClosedReason;
 use rust_chat::ConvertibleToChatResult;
+use rust_chat::frame_header::MAX_PACKET_SIZE;
+use rust_chat::load_server_config;
+use rust_chat::protocol;
+use rust_chat::protocol::DEFAULT_CHANNEL;
+use rust_chat::ChannelList;
+use rust_chat::ChannelName;
+use rust_chat::ClientMessage;
+use rust_chat::CommandFailed;
+use rust_chat::Credentials;
+use rust_chat::DirectMessage;
+use rust_chat::DirectMessageFromUser;
+use rust_chat::FetchHistory;
+use rust_chat::HandshakeMessage;
+use rust_chat::HistoryPage;
+use rust_chat::LoginAccepted;
+use rust_chat::LoginRejected;
 use rust_chat::MesasgeFromUser;
-use rust_chat::CommandType;
-use std::net::TcpListener;
+use rust_chat::RateLimitAction;
+use rust_chat::RateLimited;
+use rust_chat::Roster;
+use rust_chat::SendQueueStats;
+use rust_chat::ServerMessage;
+use rust_chat::ServerShutdown;
+use rust_chat::Transport;
+use rust_chat::UserPresence;
+use clap::Parser;
+use log::{error, info, warn};
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub use cli::Options;
+pub use config::ServerConfig;
+pub use config_file::ConfigFile;
+pub use config::TlsFiles;
+pub use rate_limit::RateLimits;
+pub use shutdown::ShutdownTrigger;
+use account_store::AccountStore;
+use auth_worker::{AuthJob, AuthResult, AuthWorker};
+use channels::ChannelRegistry;
+use listener::Listener;
+use message_history::{now_ms, MessageHistory};
+use rate_limit::{FloodGuard, LoginThrottle, PeerId, Verdict};
+use username_registry::{normalize, UsernameRegistry};
+
+// How often messages past the retention period are deleted from the history
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How often clients whose send queues dropped packets are logged
+const QUEUE_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+// Accepting stops for this tick after so many failures in a row. Errors of the listener
+// itself, i.e. running out of file descriptors, would otherwise repeat forever
+const MAX_ACCEPT_ERRORS: usize = 16;
+
+// Upper bound of messages in one reply to FetchHistory
+const MAX_HISTORY_PAGE: u32 = 200;
+
+// Peers failing to log in with password this many times are refused for the rest of the window
+const MAX_FAILED_LOGINS: u32 = 5;
+const FAILED_LOGIN_WINDOW: Duration = Duration::from_secs(60);
+
+// Wakes the poll up when shutdown is triggered or a password is hashed,
+// far from tokens of listeners and connections
+const WAKER_TOKEN: Token = Token(usize::MAX);
 
-struct ChatServer {
-    connection_listener: TcpListener,
-    connections: Vec<Option<Connection>>,
+type ServerConnection = Connection<Box<dyn Transport>>;
+type ServerHandshake = HandshakeState<Box<dyn Transport>>;
+
+// Handshake waiting for the password to be hashed or verified
+struct PendingLogin {
+    state: ServerHandshake,
+    peer: PeerId,
+    // false if the password is verified against the dummy hash of an unknown user
+    registered: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionQueueStats {
+    pub session_id: u64,
+    pub username: String,
+    pub queue: SendQueueStats,
+}
+
+pub struct ChatServer {
+    config: ServerConfig,
+    poll: Poll,
+    events: Events,
+    // tokens of listeners are their indices, connections get tokens after them
+    listeners: Vec<Listener>,
+    connections: Vec<Option<ServerConnection>>,
+    next_token: usize,
     messages_from_user: Vec<MesasgeFromUser>,
+    next_session_id: u64,
+    usernames: UsernameRegistry,
+    channels: ChannelRegistry,
+    // rate limits of established connections by session id
+    flood_guards: HashMap<u64, FloodGuard>,
+    accounts: AccountStore,
+    auth_worker: AuthWorker,
+    // handshakes are taken out of the connections while the worker has their passwords
+    pending_logins: HashMap<u64, PendingLogin>,
+    next_login_id: u64,
+    login_throttle: LoginThrottle,
+    history: Option<MessageHistory>,
+    last_history_prune: Instant,
+    last_queue_report: Instant,
+    // dropped packets of each session as of the last report
+    reported_drops: HashMap<u64, u64>,
+    shutdown: ShutdownTrigger,
 }
 
 impl ChatServer {
-    pub fn new<'a>(address: &str) -> ChatResult<ChatServer> {
-        let tcp_listener = TcpListener::bind(address).to_chat_result()?;
-        tcp_listener.set_nonblocking(true).to_chat_result()?;
+    // Listens on every address enabled in the config
+    pub fn new(config: ServerConfig) -> ChatResult<ChatServer> {
+        config.validate()?;
+
+        let mut listeners = Vec::new();
+        if let Some(address) = &config.tcp_address {
+            let tls = match &config.tls {
+                Some(files) => Some(load_server_config(&files.cert_path, &files.key_path)?),
+                None => None,
+            };
+            listeners.push(Listener::bind_tcp(address, tls)?);
+        }
+        if let Some(path) = &config.unix_socket_path {
+            listeners.push(Listener::bind_unix(path, config.unix_socket_mode)?);
+        }
+        let poll = Poll::new().to_chat_result()?;
+        for (index, listener) in listeners.iter_mut().enumerate() {
+            listener.register(poll.registry(), Token(index))?;
+        }
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN).to_chat_result()?);
+
         Ok(ChatServer {
-            connection_listener: tcp_listener,
+            usernames: UsernameRegistry::new(&config.reserved_usernames),
+            channels: ChannelRegistry::new(&[DEFAULT_CHANNEL]),
+            flood_guards: HashMap::new(),
+            accounts: AccountStore::open(config.accounts_path.as_deref())?,
+            auth_worker: AuthWorker::spawn(waker.clone())?,
+            pending_logins: HashMap::new(),
+            next_login_id: 1,
+            login_throttle: LoginThrottle::new(MAX_FAILED_LOGINS, FAILED_LOGIN_WINDOW),
+            history: match &config.history_path {
+                Some(path) => Some(MessageHistory::open(path, config.history_retention)?),
+                None => None,
+            },
+            last_history_prune: Instant::now(),
+            last_queue_report: Instant::now(),
+            reported_drops: HashMap::new(),
+            shutdown: ShutdownTrigger::new(waker),
+            config,
+            poll,
+            events: Events::with_capacity(1024),
             connections: Vec::new(),
+            next_token: listeners.len(),
+            listeners,
             messages_from_user: Vec::new(),
+            next_session_id: 1,
         })
     }
 
+    // Address of the TCP listener
+    pub fn local_addr(&self) -> ChatResult<std::net::SocketAddr> {
+        for listener in &self.listeners {
+            if let Listener::Tcp { listener, .. } = listener {
+                return listener.local_addr().to_chat_result();
+            }
+        }
+        Err(ChatError("Server does not listen on TCP".to_string()))
+    }
+
+    // Triggering it from any thread makes run() shut the server down
+    pub fn shutdown_trigger(&self) -> ShutdownTrigger {
+        self.shutdown.clone()
+    }
+
+    // Serves clients until shutdown is triggered or an unrecoverable error happens
+    pub fn run(&mut self) -> ChatResult<()> {
+        while !self.shutdown.is_triggered() {
+            self.wait_for_events(self.poll_timeout())?;
+            self.tick();
+        }
+        self.shutdown("Server is shutting down")
+    }
+
+    /* Stops accepting connections, tells logged in users why they are disconnected and
+     * waits up to shutdown_timeout for them to receive it. Connections still sending after
+     * that and those without a handshake are dropped
+     */
+    pub fn shutdown(&mut self, reason: &str) -> ChatResult<()> {
+        info!("Shutting down: {reason}");
+        self.listeners.clear();
+        // logins still waiting for the worker are dropped like the other handshakes
+        for (_, login) in self.pending_logins.drain() {
+            self.usernames.release(&login.state.handshake_message().unwrap().username);
+            self.connections.push(Some(Connection::HandShake(login.state)));
+        }
+
+        let frame = protocol::encode_frame(&ServerMessage::ServerShutdown(ServerShutdown {
+            reason: reason.to_string(),
+            reconnect_after_ms: self
+                .config
+                .shutdown_reconnect_after
+                .map(|after| after.as_millis() as u64),
+        }))
+        .expect("Shutdown message fits in a frame");
+        for opt_connection in &mut self.connections {
+            *opt_connection = Some(match opt_connection.take().unwrap() {
+                Connection::Established(mut state) => {
+                    state.enqueue_frame(frame.clone());
+                    state.close(ConnectionClosedReason::ServerShutdown)
+                }
+                connection @ Connection::HandShake(_) => {
+                    connection.abort(ConnectionClosedReason::ServerShutdown)
+                }
+                connection => connection,
+            });
+        }
+
+        let deadline = Instant::now() + self.config.shutdown_timeout;
+        loop {
+            self.send_data();
+            self.remove_closed_connections();
+            if self.connections.is_empty() {
+                break;
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                warn!(
+                    "Dropping {} connections that did not finish receiving in time",
+                    self.connections.len()
+                );
+                for opt_connection in &mut self.connections {
+                    let connection = opt_connection.take().unwrap();
+                    *opt_connection = Some(connection.abort(ConnectionClosedReason::ServerShutdown));
+                }
+                self.remove_closed_connections();
+                break;
+            }
+            self.wait_for_events(Some(remaining))?;
+        }
+
+        if let Some(history) = self.history.take() {
+            history.close()?;
+        }
+        info!("Shutdown complete");
+        Ok(())
+    }
+
+    // How long the server may sleep if no socket becomes ready.
+    // None means there is no pending timer and the server sleeps until the next event
+    fn poll_timeout(&self) -> Option<Duration> {
+        let mut deadline = match (&self.history, self.config.history_retention) {
+            (Some(_), Some(_)) => Some(self.last_history_prune + HISTORY_PRUNE_INTERVAL),
+            _ => None,
+        };
+
+        let mut wake_up_at = |instant: Instant| {
+            deadline = Some(deadline.map_or(instant, |deadline: Instant| deadline.min(instant)));
+        };
+        // packets are dropped only from limited queues
+        if self.config.send_queue_limits.is_some() {
+            wake_up_at(self.last_queue_report + QUEUE_REPORT_INTERVAL);
+        }
+        for connection in &self.connections {
+            let connection = connection.as_ref().unwrap();
+            match connection {
+                Connection::HandShake(state) if state.handshake_message().is_none() => {
+                    wake_up_at(state.handshake_deadline(self.config.handshake_timeout))
+                }
+                Connection::Established(state) => wake_up_at(
+                    state.next_heartbeat(self.config.idle_timeout, self.config.dead_peer_timeout),
+                ),
+                _ => {}
+            }
+            if let Some(min_rate) = &self.config.min_receive_rate {
+                if let Some(instant) = connection.receive_rate_deadline(min_rate) {
+                    wake_up_at(instant);
+                }
+            }
+        }
+
+        deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    // Blocks until one of the sockets is ready or timeout expires.
+    // Every tick processes all connections so events are only used to wake up
+    pub fn wait_for_events(&mut self, timeout: Option<Duration>) -> ChatResult<()> {
+        match self.poll.poll(&mut self.events, timeout) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => Ok(()),
+            Err(err) => Err(err).to_chat_result(),
+        }
+    }
+
     pub fn tick(&mut self) {
         self.accept_connections();
         self.receive_data();
+        self.process_handshakes();
         let commands = self.gather_commands();
         self.handle_commands(commands);
         self.send_messages();
+        self.check_timeouts();
         self.send_data();
+        self.report_send_queues();
         self.remove_closed_connections();
+        self.prune_history();
     }
 
     pub fn accept_connections(&mut self) {
-        loop {
-            match self.connection_listener.accept() {
-                Ok((stream, _)) => {
-                    stream
-                        .set_nonblocking(true)
-                        .expect("Failed to make tcp stream non-blocking");
-                    let new_connection = Connection::new(stream);
-                    self.connections.push(Some(new_connection));
-                }
-                Err(error) => {
-                    if error.kind() == std::io::ErrorKind::WouldBlock {
-                        break;
+        let mut accepted = Vec::new();
+        for listener in &self.listeners {
+            // edge-triggered, so the backlog has to be drained even if some connections fail
+            let mut errors = 0;
+            while errors < MAX_ACCEPT_ERRORS {
+                match listener.accept() {
+                    Ok(transport) => {
+                        accepted.push(transport);
+                        errors = 0;
+                    }
+                    Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(error) => {
+                        warn!("Failed to accept connection: {error}");
+                        errors += 1;
                     }
                 }
             }
         }
+
+        for transport in accepted {
+            self.add_connection(transport);
+        }
+    }
+
+    pub fn add_connection(&mut self, mut transport: Box<dyn Transport>) {
+        // refused before anything is allocated for it, the peer sees the socket closed.
+        // Counted per peer so that one peer opening connections does not lock out the others
+        let peer = PeerId::of_transport(transport.as_ref());
+        let pending_handshakes = self
+            .connections
+            .iter()
+            .filter(|connection| match connection {
+                Some(Connection::HandShake(state)) => PeerId::of(state.connection_info()) == peer,
+                _ => false,
+            })
+            .count()
+            + self
+                .pending_logins
+                .values()
+                .filter(|login| login.peer == peer)
+                .count();
+        if pending_handshakes >= self.config.max_pending_handshakes {
+            warn!("Too many pending handshakes from {peer:?}, connection refused");
+            transport.close();
+            return;
+        }
+
+        // edge-triggered, so both interests stay registered for the whole lifetime
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        if let Err(err) = transport.register(
+            self.poll.registry(),
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            warn!("Failed to register connection: {err}");
+            return;
+        }
+
+        self.connections.push(Some(Connection::with_max_packet_size(
+            transport,
+            self.config.max_packet_size,
+        )));
     }
 
     pub fn receive_data(&mut self) {
@@ -59,101 +402,581 @@ impl ChatServer {
         }
     }
 
-    pub fn handle_commands(&mut self, commands: Vec<Vec<u8>>) {
-        for command in commands {
-            self.handle_command(command);
+    /* Logs in or rejects handshakes received so far. Handshakes with password are taken out
+     * of the connections until the worker has hashed or verified it, they are put back
+     * in a later tick
+     */
+    pub fn process_handshakes(&mut self) {
+        let mut received = Vec::new();
+        let mut connection_index = 0;
+        while connection_index < self.connections.len() {
+            match &self.connections[connection_index] {
+                Some(Connection::HandShake(state)) if state.handshake_message().is_some() => {
+                    if let Some(Connection::HandShake(state)) = self.connections.remove(connection_index) {
+                        received.push(state);
+                    }
+                }
+                _ => connection_index += 1,
+            }
+        }
+
+        let mut decided: Vec<_> = received
+            .into_iter()
+            .filter_map(|state| self.start_login(state))
+            .collect();
+        decided.extend(self.finish_logins());
+
+        let mut logged_in = Vec::new();
+        for (state, result) in decided {
+            let connection = match result {
+                Ok(()) => self.accept_login(state),
+                Err(rejection) => state.reject(rejection),
+            };
+            if let Connection::Established(state) = &connection {
+                logged_in.push((self.connections.len(), state.login_info().user.clone()));
+            }
+            self.connections.push(Some(connection));
+        }
+
+        // presence is sent once all connections are back in place.
+        // Users logged in during the same tick may see each other in both roster and UserJoined
+        for (connection_index, username) in logged_in {
+            let roster = Roster {
+                users: self.online_users(),
+            };
+            self.send_to_connection(connection_index, &ServerMessage::Roster(roster));
+            let joined = ServerMessage::UserJoined(UserPresence { username });
+            self.broadcast(&joined, Some(connection_index));
         }
     }
 
-    pub fn send_messages(&mut self) {
-        for message_from_user in &self.messages_from_user {
-            let message_str = {
-                let mut object = serde_json::value::Map::new();
-                object.insert(
-                    "Type".to_string(),
-                    serde_json::to_value(CommandType::MessageFromUser).unwrap(),
-                );
-                object.insert(
-                    "Data".to_string(),
-                    serde_json::to_value(message_from_user)
-                    .expect("fail"),
+    /* Claims the username for the connection and checks what can be checked without hashing.
+     * Returns the result if the login is decided already, passwords go to the worker otherwise
+     */
+    fn start_login(
+        &mut self,
+        state: ServerHandshake,
+    ) -> Option<(ServerHandshake, Result<(), LoginRejected>)> {
+        let handshake: HandshakeMessage = state.handshake_message().unwrap().clone();
+        let username = &handshake.username;
+        if let Err(rejection) = self.usernames.claim(username) {
+            return Some((state, Err(rejection)));
+        }
+
+        let peer = PeerId::of(state.connection_info());
+        let started = match handshake.credentials {
+            Credentials::Guest if !self.config.allow_guests => {
+                Err(LoginRejected::permanent(
+                    "Guest logins are disabled, register or log in with password".to_string(),
+                ))
+            }
+            Credentials::Guest if self.accounts.is_registered(username) => {
+                Err(LoginRejected::permanent(format!(
+                    "Username \"{username}\" is registered, password is required"
+                )))
+            }
+            Credentials::Guest => Ok(None),
+            _ if self.login_throttle.is_blocked(&peer) => {
+                warn!("Login of {username} refused, too many failed logins from {peer:?}");
+                Err(LoginRejected::temporary(
+                    "Too many failed logins, try again later".to_string(),
+                ))
+            }
+            Credentials::Password(password) => Ok(Some((
+                self.accounts.is_registered(username),
+                AuthJob::Verify {
+                    hash: self.accounts.password_hash(username).to_string(),
+                    password,
+                },
+            ))),
+            Credentials::Register(password) => self
+                .accounts
+                .check_new_account(username, &password)
+                .map(|()| Some((true, AuthJob::Hash { password })))
+                .map_err(LoginRejected::permanent),
+        };
+
+        match started {
+            Ok(Some((registered, job))) => {
+                let id = self.next_login_id;
+                self.next_login_id += 1;
+                self.auth_worker.submit(id, job);
+                self.pending_logins.insert(
+                    id,
+                    PendingLogin {
+                        state,
+                        peer,
+                        registered,
+                    },
                 );
+                None
+            }
+            Ok(None) => Some((state, Ok(()))),
+            Err(rejection) => {
+                self.usernames.release(username);
+                Some((state, Err(rejection)))
+            }
+        }
+    }
+
+    // Handshakes which passwords the worker is done with
+    fn finish_logins(&mut self) -> Vec<(ServerHandshake, Result<(), LoginRejected>)> {
+        let mut finished = Vec::new();
+        for (id, result) in self.auth_worker.take_results() {
+            // dropped by shutdown meanwhile
+            let Some(login) = self.pending_logins.remove(&id) else {
+                continue;
+            };
+            let username = login.state.handshake_message().unwrap().username.clone();
+            let result = match result {
+                AuthResult::Verified(true) if login.registered => Ok(()),
+                AuthResult::Verified(_) => {
+                    self.login_throttle.record_failure(login.peer);
+                    Err("Invalid username or password".to_string())
+                }
+                AuthResult::Hashed(hash) => hash.and_then(|hash| self.accounts.add(&username, hash)),
+            }
+            .map_err(LoginRejected::permanent);
+
+            if result.is_err() {
+                self.usernames.release(&username);
+            }
+            finished.push((login.state, result));
+        }
+        finished
+    }
+
+    fn accept_login(&mut self, state: ServerHandshake) -> ServerConnection {
+        let session_id = self.next_session_id;
+        self.next_session_id += 1;
+        let mut connection = state.accept(LoginAccepted {
+            session_id,
+            server_name: self.config.server_name.clone(),
+            protocol_version: protocol::PROTOCOL_VERSION,
+            motd: self.config.motd.clone(),
+        });
+
+        if let Connection::Established(state) = &mut connection {
+            state.limit_send_queue(self.config.send_queue_limits);
+            state.limit_write_size(self.config.max_write_size);
+            // cannot fail, the session is new and the default channel always exists
+            let _ = self.channels.join(DEFAULT_CHANNEL, session_id);
+            if let Some(limits) = &self.config.rate_limits {
+                self.flood_guards.insert(session_id, FloodGuard::new(limits));
+            }
+            for message in self.channel_joined_messages(DEFAULT_CHANNEL) {
+                state.enqueue_message(protocol::encode(&message));
+            }
+        }
+
+        // the client may have sent more packets right after the handshake.
+        // Read them now as there will be no new readiness event for them
+        connection.receive()
+    }
+
+    pub fn handle_commands(&mut self, commands: Vec<(usize, Vec<u8>)>) {
+        for (connection_index, command) in commands {
+            if self.check_rate_limit(connection_index, command.len()) {
+                self.handle_command(connection_index, command);
+            }
+        }
+    }
+
+    /* Charges the command to the rate limits of its connection and responds to violations.
+     * Returns whether the command should be handled
+     */
+    fn check_rate_limit(&mut self, connection_index: usize, size: usize) -> bool {
+        let state = match self.connections[connection_index].as_ref().unwrap() {
+            Connection::Established(state) => state,
+            // disconnected for flooding earlier in this tick
+            _ => return false,
+        };
+        let verdict = match self.flood_guards.get_mut(&state.session_id()) {
+            Some(guard) => guard.check(size),
+            None => return true,
+        };
+        if verdict == Verdict::Allow {
+            return true;
+        }
+        // the connection has been told already
+        if verdict == Verdict::Drop {
+            return false;
+        }
+
+        warn!(
+            "Rate limit exceeded by {} {:?}: {verdict:?}",
+            state.login_info().user,
+            state.connection_info()
+        );
+        let (action, muted_for) = match verdict {
+            Verdict::Allow | Verdict::Drop => unreachable!(),
+            Verdict::Warn => (RateLimitAction::Warning, None),
+            Verdict::Mute(duration) => (RateLimitAction::Muted, Some(duration)),
+            Verdict::Disconnect => (RateLimitAction::Disconnected, None),
+        };
+        self.send_rate_limited(connection_index, action, muted_for);
+
+        if verdict == Verdict::Disconnect {
+            let connection = self.connections[connection_index].take().unwrap();
+            self.connections[connection_index] = Some(match connection {
+                Connection::Established(state) => state.close(ConnectionClosedReason::RateLimitExceeded),
+                connection => connection,
+            });
+        }
+        false
+    }
+
+    fn send_rate_limited(&mut self, connection_index: usize, action: RateLimitAction, muted_for: Option<Duration>) {
+        let message = ServerMessage::RateLimited(RateLimited {
+            action,
+            muted_for_ms: muted_for.map(|duration| duration.as_millis() as u64),
+        });
+        self.send_to_connection(connection_index, &message);
+    }
 
-                serde_json::to_string(&object).unwrap()
+    // Replies to chat messages of muted connections instead of delivering them
+    fn reject_if_muted(&mut self, connection_index: usize, session_id: u64) -> bool {
+        let muted_for = self
+            .flood_guards
+            .get(&session_id)
+            .and_then(FloodGuard::muted_for);
+        if muted_for.is_some() {
+            self.send_rate_limited(connection_index, RateLimitAction::Muted, muted_for);
+        }
+        muted_for.is_some()
+    }
+
+    pub fn send_messages(&mut self) {
+        for mut message_from_user in self.messages_from_user.drain(..) {
+            message_from_user.timestamp_ms = Some(now_ms());
+            if let Some(history) = &mut self.history {
+                // losing history is better than losing the live message
+                match history.append(
+                    &message_from_user.username,
+                    &message_from_user.channel,
+                    &message_from_user.text,
+                ) {
+                    Ok(stored) => message_from_user = stored.into(),
+                    Err(err) => error!("Failed to store message in history: {err:?}"),
+                }
+            }
+
+            let channel = message_from_user.channel.clone();
+            let username = message_from_user.username.clone();
+            // framed once, every member queues the same buffer
+            let message = ServerMessage::MessageFromUser(message_from_user);
+            let frame = match protocol::encode_frame(&message) {
+                Ok(frame) => frame,
+                Err(err) => {
+                    warn!("Message from {username} can't be sent: {err}");
+                    continue;
+                }
             };
 
             for opt_connection in &mut self.connections {
                 let mut connection = opt_connection.take().unwrap();
                 if let Connection::Established(state) = &mut connection {
-                    state.enqueue_message(message_str.clone());
+                    if self.channels.is_member(&channel, state.session_id()) {
+                        // a client falling behind rather misses chat than the replies to its commands
+                        state.enqueue_non_essential(frame.clone());
+                    }
                 }
 
                 *opt_connection = Some(connection);
             }
         }
+    }
 
-        self.messages_from_user.clear();
+    // Send queue of every established connection, for monitoring slow clients
+    pub fn send_queue_stats(&self) -> Vec<ConnectionQueueStats> {
+        self.connections
+            .iter()
+            .filter_map(|connection| match connection.as_ref().unwrap() {
+                Connection::Established(state) => Some(ConnectionQueueStats {
+                    session_id: state.session_id(),
+                    username: state.login_info().user.clone(),
+                    queue: state.send_queue_stats(),
+                }),
+                _ => None,
+            })
+            .collect()
     }
 
-    pub fn handle_command(&mut self, command: Vec<u8>) {
-        type JsonValue = serde_json::Value;
-        let mut cmd_json = match serde_json::from_slice::<JsonValue>(&command) {
-            Ok(cmd_json) => cmd_json,
-            Err(parse_err) => {
-                println!(
-                    "Failed to parse command json: {}. Error: {}.",
-                    String::from_utf8_lossy(&command),
-                    parse_err
-                );
+    // Users of all established connections in the order they logged in
+    fn online_users(&self) -> Vec<String> {
+        self.connections
+            .iter()
+            .filter_map(|connection| match connection.as_ref().unwrap() {
+                Connection::Established(state) => Some(state.login_info().user.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Queues message for all established connections, except the one with excluded index
+    fn broadcast(&mut self, message: &ServerMessage, excluded_index: Option<usize>) {
+        let frame = match protocol::encode_frame(message) {
+            Ok(frame) => frame,
+            Err(err) => {
+                error!("Failed to broadcast message: {err}");
                 return;
             }
         };
+        for (connection_index, connection) in self.connections.iter_mut().enumerate() {
+            if Some(connection_index) == excluded_index {
+                continue;
+            }
+            if let Some(Connection::Established(state)) = connection.as_mut() {
+                state.enqueue_frame(frame.clone());
+            }
+        }
+    }
 
-        let cmd_json = match cmd_json.as_object_mut() {
-            Some(map) => map,
-            None => {
-                println!("Command json expected to be object");
-                return;
+    // Queues message for the connection with given index if it is established
+    fn send_to_connection(&mut self, connection_index: usize, message: &ServerMessage) {
+        if let Some(Connection::Established(state)) = self.connections[connection_index].as_mut() {
+            state.enqueue_message(protocol::encode(message));
+        }
+    }
+
+    fn send_command_failed(&mut self, connection_index: usize, reason: String) {
+        self.send_to_connection(
+            connection_index,
+            &ServerMessage::CommandFailed(CommandFailed { reason }),
+        );
+    }
+
+    fn reply_to_join(&mut self, connection_index: usize, channel: &str, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                for message in self.channel_joined_messages(channel) {
+                    self.send_to_connection(connection_index, &message);
+                }
             }
+            Err(reason) => self.send_command_failed(connection_index, reason),
+        }
+    }
+
+    // Confirmation of joining the channel followed by its latest messages if the history is enabled
+    fn channel_joined_messages(&self, channel: &str) -> Vec<ServerMessage> {
+        let mut messages = vec![ServerMessage::ChannelJoined(ChannelName {
+            channel: channel.to_string(),
+        })];
+        if self.history.is_some() && self.config.history_backfill > 0 {
+            messages.push(ServerMessage::History(self.history_page(&FetchHistory {
+                channel: channel.to_string(),
+                before_id: None,
+                limit: self.config.history_backfill,
+            })));
+        }
+        messages
+    }
+
+    // Empty page if the history is disabled
+    fn history_page(&self, request: &FetchHistory) -> HistoryPage {
+        let empty_page = || HistoryPage {
+            channel: request.channel.clone(),
+            messages: Vec::new(),
+            has_more: false,
+        };
+        let history = match &self.history {
+            Some(history) => history,
+            None => return empty_page(),
         };
 
-        let key_command_type = "Type";
-        let command_type = match cmd_json.remove(key_command_type) {
-            Some(cmd_type_str) => match serde_json::from_value::<CommandType>(cmd_type_str) {
-                Ok(command_type) => command_type,
-                Err(err) => {
-                    println!("Failed to parse command type: {}", err);
-                    return;
+        // one extra message tells whether there is more to fetch
+        let limit = request.limit.min(MAX_HISTORY_PAGE) as usize;
+        let before_id = request.before_id.map(|id| id.min(i64::MAX as u64) as i64);
+        match history.fetch(&request.channel, before_id, limit + 1) {
+            Ok(messages) => {
+                let mut has_more = messages.len() > limit;
+                let mut messages: Vec<MesasgeFromUser> = messages
+                    .into_iter()
+                    .skip(usize::from(has_more))
+                    .map(MesasgeFromUser::from)
+                    .collect();
+
+                // clients drop the connection on frames they can't accept, the oldest
+                // messages are left for the next page
+                let fitting = messages_fitting_in_frame(&request.channel, &messages);
+                if fitting < messages.len() {
+                    messages.drain(..messages.len() - fitting);
+                    has_more = true;
                 }
-            },
+                HistoryPage {
+                    channel: request.channel.clone(),
+                    messages,
+                    has_more,
+                }
+            }
+            Err(err) => {
+                error!("Failed to fetch history: {err:?}");
+                empty_page()
+            }
+        }
+    }
+
+    // Index of the established connection the user is logged in on
+    fn find_user_connection(&self, username: &str) -> Option<usize> {
+        let username = normalize(username);
+        self.connections.iter().position(|connection| match connection.as_ref().unwrap() {
+            Connection::Established(state) => normalize(&state.login_info().user) == username,
+            _ => false,
+        })
+    }
+
+    /* Delivers the message to the recipient only and echoes it back to the sender,
+     * so that both see the same timestamp
+     */
+    fn send_direct_message(&mut self, connection_index: usize, from: String, message: DirectMessage) {
+        let recipient_index = match self.find_user_connection(&message.to) {
+            Some(index) => index,
             None => {
-                println!("Invalid json - {key_command_type} not found.");
+                let reason = if self.accounts.is_registered(&message.to) {
+                    format!("User \"{}\" is offline", message.to)
+                } else {
+                    format!("User \"{}\" is unknown", message.to)
+                };
+                self.send_command_failed(connection_index, reason);
                 return;
             }
         };
 
-        let key_commad_data = "Data";
-        let cmd_data_value = match cmd_json.remove(key_commad_data) {
-            Some(cmd_data_value) => cmd_data_value,
+        let direct_message = ServerMessage::DirectMessage(DirectMessageFromUser {
+            from,
+            to: message.to,
+            text: message.text,
+            timestamp_ms: now_ms(),
+        });
+        self.send_to_connection(recipient_index, &direct_message);
+        if recipient_index != connection_index {
+            self.send_to_connection(connection_index, &direct_message);
+        }
+    }
+
+    // Identity of the user logged in on the connection with given index and its session id
+    fn connection_session(&self, connection_index: usize) -> Option<(String, u64)> {
+        match self.connections[connection_index].as_ref().unwrap() {
+            Connection::Established(state) => {
+                Some((state.login_info().user.clone(), state.session_id()))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn handle_command(&mut self, connection_index: usize, command: Vec<u8>) {
+        let (username, session_id) = match self.connection_session(connection_index) {
+            Some(session) => session,
             None => {
-                println!("Invalid json - {key_commad_data} not found.");
+                warn!("Command from connection that is not established");
                 return;
             }
         };
 
-        match command_type {
-            CommandType::MessageFromUser => {
-                match serde_json::from_value::<MesasgeFromUser>(cmd_data_value) {
-                    Ok(message_from_user) => self.messages_from_user.push(message_from_user),
-                    Err(err) => {
-                        println!("Failed to parse message from user. {}", err);
-                        return;
-                    }
+        let command = match protocol::decode::<ClientMessage>(&command) {
+            Ok(command) => command,
+            Err(err) => {
+                warn!(
+                    "Failed to parse command: {}. Error: {:?}.",
+                    String::from_utf8_lossy(&command),
+                    err
+                );
+                return;
+            }
+        };
+
+        match command {
+            ClientMessage::MessageFromUser(message_from_user) => {
+                if message_from_user.username != username {
+                    warn!(
+                        "User {username} claimed to be {}. Claimed name is ignored.",
+                        message_from_user.username
+                    );
+                }
+
+                if self.reject_if_muted(connection_index, session_id) {
+                    return;
+                }
+
+                if !self.channels.is_member(&message_from_user.channel, session_id) {
+                    let reason = format!("Not a member of channel \"{}\"", message_from_user.channel);
+                    self.send_command_failed(connection_index, reason);
+                    return;
+                }
+
+                // the sender is always the user bound to the connection
+                self.messages_from_user.push(MesasgeFromUser {
+                    username,
+                    text: message_from_user.text,
+                    channel: message_from_user.channel,
+                    id: None,
+                    timestamp_ms: None,
+                })
+            }
+            ClientMessage::FetchHistory(request) => {
+                // history is only readable by members, like the live messages
+                if !self.channels.is_member(&request.channel, session_id) {
+                    let reason = format!("Not a member of channel \"{}\"", request.channel);
+                    self.send_command_failed(connection_index, reason);
+                    return;
                 }
+
+                let page = self.history_page(&request);
+                self.send_to_connection(connection_index, &ServerMessage::History(page));
+            }
+            ClientMessage::Ping => self.send_to_connection(connection_index, &ServerMessage::Pong),
+            // taking the packet has already recorded the activity
+            ClientMessage::Pong => {}
+            ClientMessage::DirectMessage(message) => {
+                if !self.reject_if_muted(connection_index, session_id) {
+                    self.send_direct_message(connection_index, username, message)
+                }
+            }
+            ClientMessage::CreateChannel(ChannelName { channel }) => {
+                let result = self.channels.create(&channel, session_id);
+                self.reply_to_join(connection_index, &channel, result);
+            }
+            ClientMessage::JoinChannel(ChannelName { channel }) => {
+                let result = self.channels.join(&channel, session_id);
+                self.reply_to_join(connection_index, &channel, result);
+            }
+            ClientMessage::LeaveChannel(ChannelName { channel }) => {
+                match self.channels.leave(&channel, session_id) {
+                    Ok(()) => self.send_to_connection(
+                        connection_index,
+                        &ServerMessage::ChannelLeft(ChannelName { channel }),
+                    ),
+                    Err(reason) => self.send_command_failed(connection_index, reason),
+                }
+            }
+            ClientMessage::ListChannels => {
+                let list = ChannelList {
+                    channels: self.channels.list(),
+                };
+                self.send_to_connection(connection_index, &ServerMessage::ChannelList(list));
             }
         }
     }
 
+    // Closes connections that missed their handshake deadline, stalled or went silent
+    pub fn check_timeouts(&mut self) {
+        for opt_connection in &mut self.connections {
+            let mut connection = opt_connection.take().unwrap();
+            if let Some(min_rate) = &self.config.min_receive_rate {
+                connection = connection.check_receive_rate(min_rate);
+            }
+
+            *opt_connection = Some(match connection {
+                Connection::HandShake(state) => state.check_deadline(self.config.handshake_timeout),
+                Connection::Established(state) => {
+                    state.check_heartbeat(self.config.idle_timeout, self.config.dead_peer_timeout)
+                }
+                connection => connection,
+            });
+        }
+        self.login_throttle.prune();
+    }
+
     pub fn send_data(&mut self) {
         for opt_connection in &mut self.connections {
             let connection = opt_connection.take().unwrap();
@@ -161,13 +984,14 @@ impl ChatServer {
         }
     }
 
-    pub fn gather_commands(&mut self) -> Vec<Vec<u8>> {
+    // Returns commands paired with index of the connection they came from
+    pub fn gather_commands(&mut self) -> Vec<(usize, Vec<u8>)> {
         let mut messages = Vec::new();
 
-        for connection in &mut self.connections {
+        for (connection_index, connection) in self.connections.iter_mut().enumerate() {
             if let Connection::Established(state) = connection.as_mut().unwrap() {
-                if let Some(message) = state.take_message() {
-                    messages.push(message);
+                while let Some(message) = state.take_message() {
+                    messages.push((connection_index, message));
                 }
             }
         }
@@ -175,22 +999,1066 @@ impl ChatServer {
         messages
     }
 
+    fn prune_history(&mut self) {
+        if self.last_history_prune.elapsed() < HISTORY_PRUNE_INTERVAL {
+            return;
+        }
+
+        self.last_history_prune = Instant::now();
+        if let Some(history) = &mut self.history {
+            match history.prune() {
+                Ok(0) => {}
+                Ok(count) => info!("Deleted {count} messages past retention period"),
+                Err(err) => error!("Failed to prune history: {err:?}"),
+            }
+        }
+    }
+
+    // Logs clients that had packets dropped since the last report, they do not keep up
+    fn report_send_queues(&mut self) {
+        if self.last_queue_report.elapsed() < QUEUE_REPORT_INTERVAL {
+            return;
+        }
+
+        self.last_queue_report = Instant::now();
+        let mut reported_drops = HashMap::new();
+        for stats in self.send_queue_stats() {
+            let previously = self.reported_drops.get(&stats.session_id).copied().unwrap_or(0);
+            if stats.queue.dropped_packets > previously {
+                warn!(
+                    "{} does not keep up, {} packets dropped since last report, {} packets ({} bytes) queued",
+                    stats.username,
+                    stats.queue.dropped_packets - previously,
+                    stats.queue.queued_packets,
+                    stats.queue.queued_bytes
+                );
+            }
+            reported_drops.insert(stats.session_id, stats.queue.dropped_packets);
+        }
+        self.reported_drops = reported_drops;
+    }
+
     fn remove_closed_connections(&mut self) {
         // remove closed connections
+        let usernames = &mut self.usernames;
+        let channels = &mut self.channels;
+        let flood_guards = &mut self.flood_guards;
+        let mut logged_out = Vec::new();
         self.connections.retain(|opt_connection| {
-            if let Connection::Closed(_) = opt_connection.as_ref().unwrap() {
-                println!("Connection closed");
+            if let Connection::Closed(state) = opt_connection.as_ref().unwrap() {
+                info!("Connection closed: {:?}", state.reason());
+                if let Some(login_info) = state.login_info() {
+                    usernames.release(&login_info.user);
+                    logged_out.push(login_info.user.clone());
+                }
+                if let Some(session_id) = state.session_id() {
+                    channels.leave_all(session_id);
+                    flood_guards.remove(&session_id);
+                }
                 return false;
             }
 
-            return true;
-        })
+            true
+        });
+
+        if logged_out.is_empty() {
+            return;
+        }
+        for username in logged_out {
+            self.broadcast(&ServerMessage::UserLeft(UserPresence { username }), None);
+        }
+        // data was already sent this tick and there may be no other event waking the server up
+        self.send_data();
+    }
+}
+
+// Number of the newest messages that fit in one frame together with the rest of the page
+fn messages_fitting_in_frame(channel: &str, messages: &[MesasgeFromUser]) -> usize {
+    let mut size = protocol::encode(&ServerMessage::History(HistoryPage {
+        channel: channel.to_string(),
+        messages: Vec::new(),
+        has_more: false,
+    }))
+    .len();
+    for (count, message) in messages.iter().rev().enumerate() {
+        // including the comma separating it from the others
+        size += protocol::encode(message).len() + 1;
+        if size >= MAX_PACKET_SIZE as usize {
+            return count;
+        }
     }
+    messages.len()
 }
 
 pub fn run_app() -> ChatResult<()> {
-    let mut server = ChatServer::new("127.0.0.1:8787")?;
-    loop {
-        server.tick();
+    let options = Options::parse();
+    if options.print_default_config {
+        print!("{}", ConfigFile::default().to_toml());
+        return Ok(());
+    }
+
+    let config = options.load_config()?;
+    logger::init(config.log_level);
+    let mut server = ChatServer::new(config)?;
+    shutdown::handle_signals(server.shutdown_trigger())?;
+    server.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_chat::{
+        MemoryTransport, OverflowPolicy, PacketReceiver, PacketSender, PeerAddress, SendQueueLimits,
+    };
+    use std::io::{Read, Write};
+    use std::net::SocketAddr;
+
+    // Memory transport that looks like a TCP connection from the given address
+    struct RemoteTransport {
+        inner: MemoryTransport,
+        address: SocketAddr,
+    }
+
+    impl Read for RemoteTransport {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for RemoteTransport {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl Transport for RemoteTransport {
+        fn peer_address(&self) -> std::io::Result<PeerAddress> {
+            Ok(PeerAddress::Tcp(self.address))
+        }
+
+        fn close(&mut self) {
+            self.inner.close()
+        }
+    }
+
+    struct TestClient {
+        transport: MemoryTransport,
+        sender: PacketSender,
+        receiver: PacketReceiver,
+    }
+
+    impl TestClient {
+        fn connect(server: &mut ChatServer) -> TestClient {
+            Self::attach(server, MemoryTransport::pair())
+        }
+
+        // Client whose socket buffers fill up once it stops reading
+        fn connect_slow(server: &mut ChatServer, capacity: usize) -> TestClient {
+            Self::attach(server, MemoryTransport::pair_with_capacity(capacity))
+        }
+
+        fn connect_from(server: &mut ChatServer, ip: &str) -> TestClient {
+            let (transport, server_transport) = MemoryTransport::pair();
+            let server_transport = RemoteTransport {
+                inner: server_transport,
+                address: SocketAddr::new(ip.parse().unwrap(), 40000),
+            };
+            Self::attach(server, (transport, server_transport))
+        }
+
+        fn attach<T: Transport + 'static>(server: &mut ChatServer, pair: (MemoryTransport, T)) -> TestClient {
+            let (transport, server_transport) = pair;
+            server.add_connection(Box::new(server_transport));
+            TestClient {
+                transport,
+                sender: PacketSender::new(),
+                receiver: PacketReceiver::new(),
+            }
+        }
+
+        fn send_handshake(&mut self, username: &str, credentials: Credentials) {
+            self.sender.add_to_send_queue(protocol::encode(&HandshakeMessage {
+                username: username.to_string(),
+                credentials,
+            }));
+            self.sender.advance(&mut self.transport).unwrap();
+        }
+
+        fn send(&mut self, message: &ClientMessage) {
+            self.sender.add_to_send_queue(protocol::encode(message));
+            self.sender.advance(&mut self.transport).unwrap();
+        }
+
+        fn receive_any(&mut self) -> Option<ServerMessage> {
+            let _ = self.receiver.advance(&mut self.transport);
+            self.receiver
+                .pop_packet()
+                .map(|data| protocol::decode(&data).unwrap())
+        }
+
+        // Skips presence updates and pings, only the tests of presence look at them
+        fn receive(&mut self) -> Option<ServerMessage> {
+            loop {
+                match self.receive_any() {
+                    Some(ServerMessage::Roster(_))
+                    | Some(ServerMessage::UserJoined(_))
+                    | Some(ServerMessage::UserLeft(_))
+                    | Some(ServerMessage::Ping) => continue,
+                    message => return message,
+                }
+            }
+        }
+
+        // Next presence update, skipping everything else
+        fn receive_presence(&mut self) -> Option<ServerMessage> {
+            loop {
+                match self.receive_any() {
+                    Some(
+                        message @ (ServerMessage::Roster(_)
+                        | ServerMessage::UserJoined(_)
+                        | ServerMessage::UserLeft(_)),
+                    ) => return Some(message),
+                    Some(_) => continue,
+                    None => return None,
+                }
+            }
+        }
+    }
+
+    fn make_server(config: ServerConfig) -> ChatServer {
+        ChatServer::new(ServerConfig {
+            tcp_address: Some("127.0.0.1:0".to_string()),
+            ..config
+        })
+        .unwrap()
+    }
+
+    fn login_guest(server: &mut ChatServer, username: &str) -> TestClient {
+        let mut client = TestClient::connect(server);
+        client.send_handshake(username, Credentials::Guest);
+        server.tick();
+        match client.receive() {
+            Some(ServerMessage::LoginAccepted(_)) => {}
+            message => panic!("Unexpected reply to handshake: {message:?}"),
+        }
+        assert_eq!(expect_joined(&mut client), DEFAULT_CHANNEL);
+        client
+    }
+
+    fn expect_joined(client: &mut TestClient) -> String {
+        match client.receive() {
+            Some(ServerMessage::ChannelJoined(joined)) => joined.channel,
+            message => panic!("Expected channel joined, got {message:?}"),
+        }
+    }
+
+    fn expect_failure(client: &mut TestClient) -> String {
+        match client.receive() {
+            Some(ServerMessage::CommandFailed(failed)) => failed.reason,
+            message => panic!("Expected command failure, got {message:?}"),
+        }
+    }
+
+    fn send_text(client: &mut TestClient, channel: &str, text: &str) {
+        client.send(&ClientMessage::MessageFromUser(MesasgeFromUser {
+            username: String::new(),
+            text: text.to_string(),
+            channel: channel.to_string(),
+            id: None,
+            timestamp_ms: None,
+        }));
+    }
+
+    fn channel_command(command: fn(ChannelName) -> ClientMessage, channel: &str) -> ClientMessage {
+        command(ChannelName {
+            channel: channel.to_string(),
+        })
+    }
+
+    fn expect_history(client: &mut TestClient) -> HistoryPage {
+        match client.receive() {
+            Some(ServerMessage::History(page)) => page,
+            message => panic!("Expected history, got {message:?}"),
+        }
+    }
+
+    fn texts(page: &HistoryPage) -> Vec<&str> {
+        page.messages.iter().map(|message| message.text.as_str()).collect()
+    }
+
+    // Returns the rejection if login failed
+    fn login(
+        server: &mut ChatServer,
+        username: &str,
+        credentials: Credentials,
+    ) -> Result<(), LoginRejected> {
+        let mut client = TestClient::connect(server);
+        client.send_handshake(username, credentials);
+        server.tick();
+        wait_for_logins(server);
+
+        match client.receive() {
+            Some(ServerMessage::LoginAccepted(_)) => Ok(()),
+            Some(ServerMessage::LoginRejected(rejected)) => Err(rejected),
+            message => panic!("Unexpected reply to handshake: {message:?}"),
+        }
+    }
+
+    // Ticks until the worker is done with all passwords
+    fn wait_for_logins(server: &mut ChatServer) {
+        while !server.pending_logins.is_empty() {
+            server.wait_for_events(Some(Duration::from_secs(1))).unwrap();
+            server.tick();
+        }
+    }
+
+    #[test]
+    fn password_hashing_does_not_block_guests() {
+        let mut server = make_server(ServerConfig::default());
+        let mut member = TestClient::connect(&mut server);
+        member.send_handshake("member", Credentials::Register("correct horse".to_string()));
+        server.tick();
+        assert_eq!(server.pending_logins.len(), 1);
+        assert!(member.receive().is_none());
+
+        let mut guest = TestClient::connect(&mut server);
+        guest.send_handshake("guest", Credentials::Guest);
+        server.tick();
+        assert!(matches!(guest.receive(), Some(ServerMessage::LoginAccepted(_))));
+
+        wait_for_logins(&mut server);
+        assert!(matches!(member.receive(), Some(ServerMessage::LoginAccepted(_))));
+    }
+
+    #[test]
+    fn failed_logins_are_throttled() {
+        let mut server = make_server(ServerConfig::default());
+        let password = || "correct horse".to_string();
+        assert_eq!(login(&mut server, "member", Credentials::Register(password())), Ok(()));
+        server.tick();
+
+        for _ in 0..MAX_FAILED_LOGINS {
+            let result = login(&mut server, "member", Credentials::Password("wrong".to_string()));
+            let rejected = LoginRejected::permanent("Invalid username or password".to_string());
+            assert_eq!(result, Err(rejected));
+        }
+        // worth retrying once the failures expire
+        let result = login(&mut server, "member", Credentials::Password(password()));
+        let rejected = LoginRejected::temporary("Too many failed logins, try again later".to_string());
+        assert_eq!(result, Err(rejected));
+        // guests are not affected as they have no password to guess
+        assert_eq!(login(&mut server, "guest", Credentials::Guest), Ok(()));
+    }
+
+    #[test]
+    fn register_and_login_with_password() {
+        let mut server = make_server(ServerConfig::default());
+        let password = || "correct horse".to_string();
+
+        assert_eq!(login(&mut server, "user", Credentials::Register(password())), Ok(()));
+        // first session was closed when its client was dropped
+        server.tick();
+
+        assert!(login(&mut server, "user", Credentials::Guest).is_err());
+        assert!(login(&mut server, "user", Credentials::Password("wrong".to_string())).is_err());
+        assert!(login(&mut server, "user", Credentials::Register(password())).is_err());
+        assert_eq!(login(&mut server, "USER", Credentials::Password(password())), Ok(()));
+    }
+
+    #[test]
+    fn guest_logins() {
+        let mut server = make_server(ServerConfig::default());
+        assert_eq!(login(&mut server, "guest", Credentials::Guest), Ok(()));
+
+        let mut server = make_server(ServerConfig {
+            allow_guests: false,
+            ..ServerConfig::default()
+        });
+        assert!(login(&mut server, "guest", Credentials::Guest).is_err());
+        assert_eq!(
+            login(&mut server, "member", Credentials::Register("correct horse".to_string())),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn failed_login_releases_username() {
+        let mut server = make_server(ServerConfig::default());
+        assert!(login(&mut server, "user", Credentials::Password("password".to_string())).is_err());
+        server.tick();
+        assert_eq!(login(&mut server, "user", Credentials::Guest), Ok(()));
+    }
+
+    #[test]
+    fn history_backfill_and_pages() {
+        let mut server = make_server(ServerConfig {
+            history_path: Some(":memory:".into()),
+            history_backfill: 3,
+            ..ServerConfig::default()
+        });
+
+        let mut writer = login_guest(&mut server, "writer");
+        assert!(expect_history(&mut writer).messages.is_empty());
+        for index in 0..5 {
+            send_text(&mut writer, DEFAULT_CHANNEL, &index.to_string());
+        }
+        server.tick();
+
+        let mut reader = login_guest(&mut server, "reader");
+        let page = expect_history(&mut reader);
+        assert_eq!(texts(&page), ["2", "3", "4"]);
+        assert!(page.has_more);
+        assert!(page.messages.iter().all(|message| message.username == "writer"));
+
+        reader.send(&ClientMessage::FetchHistory(FetchHistory {
+            channel: DEFAULT_CHANNEL.to_string(),
+            before_id: page.messages[0].id,
+            limit: 10,
+        }));
+        server.tick();
+        let page = expect_history(&mut reader);
+        assert_eq!(texts(&page), ["0", "1"]);
+        assert!(!page.has_more);
+    }
+
+    #[test]
+    fn history_pages_fit_in_frame() {
+        let mut server = make_server(ServerConfig {
+            history_path: Some(":memory:".into()),
+            rate_limits: None,
+            ..ServerConfig::default()
+        });
+
+        let mut writer = login_guest(&mut server, "writer");
+        expect_history(&mut writer);
+        let text = |index: usize| format!("{index:04}{}", "x".repeat(4000));
+        let count = server.config.history_backfill as usize;
+        for index in 0..count {
+            send_text(&mut writer, DEFAULT_CHANNEL, &text(index));
+            server.tick();
+        }
+
+        let mut reader = login_guest(&mut server, "reader");
+        let page = expect_history(&mut reader);
+        let frame_size = protocol::encode(&ServerMessage::History(page.clone())).len();
+        assert!(frame_size < MAX_PACKET_SIZE as usize, "{frame_size}");
+        assert!(page.has_more);
+        assert!(page.messages.len() < count);
+        assert_eq!(page.messages.last().unwrap().text, text(count - 1));
+
+        // the messages left out are on the next page
+        reader.send(&ClientMessage::FetchHistory(FetchHistory {
+            channel: DEFAULT_CHANNEL.to_string(),
+            before_id: page.messages[0].id,
+            limit: MAX_HISTORY_PAGE,
+        }));
+        server.tick();
+        let older = expect_history(&mut reader);
+        assert_eq!(
+            older.messages.last().unwrap().text,
+            text(count - page.messages.len() - 1)
+        );
+    }
+
+    #[test]
+    fn fetch_history_when_disabled() {
+        let mut server = make_server(ServerConfig::default());
+        let mut client = login_guest(&mut server, "user");
+        client.send(&ClientMessage::FetchHistory(FetchHistory {
+            channel: DEFAULT_CHANNEL.to_string(),
+            before_id: None,
+            limit: 10,
+        }));
+        server.tick();
+
+        let page = expect_history(&mut client);
+        assert!(page.messages.is_empty());
+        assert!(!page.has_more);
+    }
+
+    #[test]
+    fn messages_reach_channel_members_only() {
+        let mut server = make_server(ServerConfig::default());
+        let mut first = login_guest(&mut server, "first");
+        let mut second = login_guest(&mut server, "second");
+
+        first.send(&channel_command(ClientMessage::CreateChannel, "random"));
+        server.tick();
+        assert_eq!(expect_joined(&mut first), "random");
+
+        send_text(&mut first, "random", "only first");
+        server.tick();
+        match first.receive() {
+            Some(ServerMessage::MessageFromUser(message)) => {
+                assert_eq!(message.channel, "random");
+                assert_eq!(message.username, "first");
+            }
+            message => panic!("Unexpected message: {message:?}"),
+        }
+        assert_eq!(second.receive(), None);
+
+        // not a member of the channel
+        send_text(&mut second, "random", "rejected");
+        server.tick();
+        assert!(expect_failure(&mut second).contains("random"));
+        assert_eq!(first.receive(), None);
+
+        second.send(&channel_command(ClientMessage::JoinChannel, "random"));
+        send_text(&mut second, "random", "both");
+        server.tick();
+        assert_eq!(expect_joined(&mut second), "random");
+        for client in [&mut first, &mut second] {
+            match client.receive() {
+                Some(ServerMessage::MessageFromUser(message)) => assert_eq!(message.text, "both"),
+                message => panic!("Unexpected message: {message:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn messages_carry_sender_name() {
+        let mut server = make_server(ServerConfig::default());
+        let mut alice = login_guest(&mut server, "alice");
+        let mut bob = login_guest(&mut server, "bob");
+        let mut carol = login_guest(&mut server, "carol");
+
+        alice.send(&ClientMessage::MessageFromUser(MesasgeFromUser {
+            username: "bob".to_string(),
+            text: "sent by bob".to_string(),
+            channel: DEFAULT_CHANNEL.to_string(),
+            id: None,
+            timestamp_ms: None,
+        }));
+        server.tick();
+        for client in [&mut alice, &mut bob, &mut carol] {
+            match client.receive() {
+                Some(ServerMessage::MessageFromUser(message)) => {
+                    assert_eq!(message.username, "alice");
+                    assert_eq!(message.text, "sent by bob");
+                }
+                message => panic!("Unexpected message: {message:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn join_leave_and_list_channels() {
+        let mut server = make_server(ServerConfig::default());
+        let mut client = login_guest(&mut server, "user");
+
+        client.send(&channel_command(ClientMessage::JoinChannel, "missing"));
+        client.send(&channel_command(ClientMessage::CreateChannel, "Invalid Name"));
+        client.send(&channel_command(ClientMessage::CreateChannel, DEFAULT_CHANNEL));
+        server.tick();
+        expect_failure(&mut client);
+        expect_failure(&mut client);
+        expect_failure(&mut client);
+
+        client.send(&channel_command(ClientMessage::CreateChannel, "random"));
+        client.send(&channel_command(ClientMessage::LeaveChannel, DEFAULT_CHANNEL));
+        client.send(&ClientMessage::ListChannels);
+        server.tick();
+        assert_eq!(expect_joined(&mut client), "random");
+        match client.receive() {
+            Some(ServerMessage::ChannelLeft(left)) => assert_eq!(left.channel, DEFAULT_CHANNEL),
+            message => panic!("Unexpected message: {message:?}"),
+        }
+        let members = |list: &ChannelList| -> Vec<(String, u32)> {
+            list.channels
+                .iter()
+                .map(|info| (info.name.clone(), info.members))
+                .collect()
+        };
+        match client.receive() {
+            Some(ServerMessage::ChannelList(list)) => assert_eq!(
+                members(&list),
+                [(DEFAULT_CHANNEL.to_string(), 0), ("random".to_string(), 1)]
+            ),
+            message => panic!("Unexpected message: {message:?}"),
+        }
+
+        send_text(&mut client, DEFAULT_CHANNEL, "after leaving");
+        server.tick();
+        expect_failure(&mut client);
+
+        // closed sessions leave their channels, which are dropped once empty
+        drop(client);
+        server.tick();
+        let mut client = login_guest(&mut server, "other");
+        client.send(&ClientMessage::ListChannels);
+        server.tick();
+        match client.receive() {
+            Some(ServerMessage::ChannelList(list)) => {
+                assert_eq!(members(&list), [(DEFAULT_CHANNEL.to_string(), 1)])
+            }
+            message => panic!("Unexpected message: {message:?}"),
+        }
+    }
+
+    #[test]
+    fn history_is_per_channel() {
+        let mut server = make_server(ServerConfig {
+            history_path: Some(":memory:".into()),
+            ..ServerConfig::default()
+        });
+        let mut writer = login_guest(&mut server, "writer");
+        expect_history(&mut writer);
+        writer.send(&channel_command(ClientMessage::CreateChannel, "random"));
+        send_text(&mut writer, "random", "in random");
+        send_text(&mut writer, DEFAULT_CHANNEL, "in general");
+        server.tick();
+
+        let mut reader = login_guest(&mut server, "reader");
+        let page = expect_history(&mut reader);
+        assert_eq!(page.channel, DEFAULT_CHANNEL);
+        assert_eq!(texts(&page), ["in general"]);
+
+        reader.send(&ClientMessage::FetchHistory(FetchHistory {
+            channel: "random".to_string(),
+            before_id: None,
+            limit: 10,
+        }));
+        reader.send(&channel_command(ClientMessage::JoinChannel, "random"));
+        server.tick();
+        expect_failure(&mut reader);
+        assert_eq!(expect_joined(&mut reader), "random");
+        let page = expect_history(&mut reader);
+        assert_eq!(page.channel, "random");
+        assert_eq!(texts(&page), ["in random"]);
+    }
+
+    #[test]
+    fn direct_messages() {
+        let mut server = make_server(ServerConfig::default());
+        let mut alice = login_guest(&mut server, "alice");
+        let mut bob = login_guest(&mut server, "bob");
+        let mut carol = login_guest(&mut server, "carol");
+
+        let direct_message = |to: &str| {
+            ClientMessage::DirectMessage(DirectMessage {
+                to: to.to_string(),
+                text: "psst".to_string(),
+            })
+        };
+        alice.send(&direct_message("BOB"));
+        server.tick();
+
+        for client in [&mut alice, &mut bob] {
+            match client.receive() {
+                Some(ServerMessage::DirectMessage(message)) => {
+                    assert_eq!(message.from, "alice");
+                    assert_eq!(message.to, "BOB");
+                    assert_eq!(message.text, "psst");
+                }
+                message => panic!("Unexpected message: {message:?}"),
+            }
+        }
+        assert_eq!(carol.receive(), None);
+
+        alice.send(&direct_message("nobody"));
+        server.tick();
+        assert!(expect_failure(&mut alice).contains("unknown"));
+    }
+
+    #[test]
+    fn direct_message_to_offline_user() {
+        let mut server = make_server(ServerConfig::default());
+        assert_eq!(
+            login(&mut server, "member", Credentials::Register("correct horse".to_string())),
+            Ok(())
+        );
+        server.tick();
+
+        let mut client = login_guest(&mut server, "user");
+        client.send(&ClientMessage::DirectMessage(DirectMessage {
+            to: "member".to_string(),
+            text: "are you there?".to_string(),
+        }));
+        server.tick();
+        assert!(expect_failure(&mut client).contains("offline"));
+    }
+
+    #[test]
+    fn presence_updates() {
+        let mut server = make_server(ServerConfig::default());
+        let mut alice = login_guest(&mut server, "alice");
+        let presence = |username: &str| UserPresence {
+            username: username.to_string(),
+        };
+        assert_eq!(
+            alice.receive_presence(),
+            Some(ServerMessage::Roster(Roster {
+                users: vec!["alice".to_string()]
+            }))
+        );
+
+        let mut bob = login_guest(&mut server, "bob");
+        assert_eq!(
+            bob.receive_presence(),
+            Some(ServerMessage::Roster(Roster {
+                users: vec!["alice".to_string(), "bob".to_string()]
+            }))
+        );
+        assert_eq!(
+            alice.receive_presence(),
+            Some(ServerMessage::UserJoined(presence("bob")))
+        );
+        assert_eq!(bob.receive_presence(), None);
+
+        drop(bob);
+        server.tick();
+        assert_eq!(
+            alice.receive_presence(),
+            Some(ServerMessage::UserLeft(presence("bob")))
+        );
+    }
+
+    #[test]
+    fn rejected_login_is_not_announced() {
+        let mut server = make_server(ServerConfig::default());
+        let mut alice = login_guest(&mut server, "alice");
+        alice.receive_presence();
+
+        assert!(login(&mut server, "alice", Credentials::Guest).is_err());
+        server.tick();
+        assert_eq!(alice.receive_presence(), None);
+    }
+
+    #[test]
+    fn disconnect_silent_clients() {
+        let mut server = make_server(ServerConfig {
+            idle_timeout: Duration::from_millis(50),
+            dead_peer_timeout: Duration::from_millis(150),
+            ..ServerConfig::default()
+        });
+        let mut alive = login_guest(&mut server, "alive");
+        let mut silent = login_guest(&mut server, "silent");
+        assert!(server.poll_timeout().unwrap() <= Duration::from_millis(50));
+
+        std::thread::sleep(Duration::from_millis(60));
+        server.tick();
+        for client in [&mut alive, &mut silent] {
+            let mut messages = std::iter::from_fn(|| client.receive_any());
+            assert!(messages.any(|message| message == ServerMessage::Ping));
+        }
+        alive.send(&ClientMessage::Pong);
+        alive.send(&ClientMessage::Ping);
+        server.tick();
+        assert_eq!(alive.receive(), Some(ServerMessage::Pong));
+
+        std::thread::sleep(Duration::from_millis(100));
+        server.tick();
+        assert_eq!(server.online_users(), ["alive"]);
+        drop(silent);
+    }
+
+    #[test]
+    fn limit_pending_handshakes() {
+        let mut server = make_server(ServerConfig {
+            max_pending_handshakes: 2,
+            ..ServerConfig::default()
+        });
+        let mut first = TestClient::connect(&mut server);
+        let _second = TestClient::connect(&mut server);
+        let _refused = TestClient::connect(&mut server);
+        assert_eq!(server.connections.len(), 2);
+
+        // other peers are not affected
+        let _remote = TestClient::connect_from(&mut server, "192.0.2.1");
+        let _other_remote = TestClient::connect_from(&mut server, "192.0.2.2");
+        assert_eq!(server.connections.len(), 4);
+
+        // completed handshake frees the slot
+        first.send_handshake("first", Credentials::Guest);
+        server.tick();
+        let _third = TestClient::connect(&mut server);
+        assert_eq!(server.connections.len(), 5);
+    }
+
+    #[test]
+    fn close_connections_without_handshake() {
+        let mut server = make_server(ServerConfig {
+            handshake_timeout: Duration::from_millis(50),
+            ..ServerConfig::default()
+        });
+        let _silent = TestClient::connect(&mut server);
+        let _user = login_guest(&mut server, "user");
+        assert!(server.poll_timeout().unwrap() <= Duration::from_millis(50));
+
+        std::thread::sleep(Duration::from_millis(50));
+        server.tick();
+        assert_eq!(server.connections.len(), 1);
+        assert_eq!(server.online_users(), ["user"]);
+    }
+
+    #[test]
+    fn escalate_flooding() {
+        let mut server = make_server(ServerConfig {
+            rate_limits: Some(RateLimits {
+                messages_per_second: 0.001,
+                message_burst: 2,
+                mute_duration: Duration::from_millis(20),
+                escalation_cooldown: Duration::ZERO,
+                ..RateLimits::default()
+            }),
+            ..ServerConfig::default()
+        });
+        let mut flooder = login_guest(&mut server, "flooder");
+        let mut listener = login_guest(&mut server, "listener");
+
+        let expect_action = |client: &mut TestClient, action: RateLimitAction| match client.receive() {
+            Some(ServerMessage::RateLimited(limited)) => assert_eq!(limited.action, action),
+            message => panic!("Expected rate limit {action:?}, got {message:?}"),
+        };
+
+        for index in 0..3 {
+            send_text(&mut flooder, DEFAULT_CHANNEL, &index.to_string());
+        }
+        server.tick();
+        for _ in 0..2 {
+            assert!(matches!(listener.receive(), Some(ServerMessage::MessageFromUser(_))));
+        }
+        assert_eq!(listener.receive(), None);
+        // the warning is queued while handling commands, before the messages are broadcast
+        expect_action(&mut flooder, RateLimitAction::Warning);
+        for _ in 0..2 {
+            assert!(matches!(flooder.receive(), Some(ServerMessage::MessageFromUser(_))));
+        }
+
+        send_text(&mut flooder, DEFAULT_CHANNEL, "muted");
+        server.tick();
+        expect_action(&mut flooder, RateLimitAction::Muted);
+
+        std::thread::sleep(Duration::from_millis(20));
+        send_text(&mut flooder, DEFAULT_CHANNEL, "disconnected");
+        server.tick();
+        expect_action(&mut flooder, RateLimitAction::Disconnected);
+        server.tick();
+        assert_eq!(server.online_users(), ["listener"]);
+        assert_eq!(listener.receive(), None);
+    }
+
+    #[test]
+    fn burst_only_warns() {
+        let limits = RateLimits::default();
+        let mut server = make_server(ServerConfig {
+            rate_limits: Some(limits.clone()),
+            ..ServerConfig::default()
+        });
+        let mut flooder = login_guest(&mut server, "flooder");
+
+        for index in 0..limits.message_burst + 3 {
+            send_text(&mut flooder, DEFAULT_CHANNEL, &index.to_string());
+        }
+        server.tick();
+        server.tick();
+        let actions: Vec<_> = std::iter::from_fn(|| flooder.receive())
+            .filter_map(|message| match message {
+                ServerMessage::RateLimited(limited) => Some(limited.action),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(actions, [RateLimitAction::Warning]);
+        assert_eq!(server.online_users(), ["flooder"]);
+    }
+
+    #[test]
+    fn muted_user_cannot_chat() {
+        let mut server = make_server(ServerConfig {
+            rate_limits: Some(RateLimits {
+                escalation_cooldown: Duration::ZERO,
+                ..RateLimits::default()
+            }),
+            ..ServerConfig::default()
+        });
+        let mut client = login_guest(&mut server, "user");
+        let session_id = match server.connections[0].as_ref().unwrap() {
+            Connection::Established(state) => state.session_id(),
+            _ => panic!("Unexpected connection state"),
+        };
+        let guard = server.flood_guards.get_mut(&session_id).unwrap();
+        guard.check(usize::MAX);
+        assert!(matches!(guard.check(usize::MAX), Verdict::Mute(_)));
+
+        send_text(&mut client, DEFAULT_CHANNEL, "text");
+        client.send(&ClientMessage::ListChannels);
+        server.tick();
+        match client.receive() {
+            Some(ServerMessage::RateLimited(limited)) => {
+                assert_eq!(limited.action, RateLimitAction::Muted);
+                assert!(limited.muted_for_ms.unwrap() > 0);
+            }
+            message => panic!("Unexpected message: {message:?}"),
+        }
+        // commands other than chat messages still work
+        assert!(matches!(client.receive(), Some(ServerMessage::ChannelList(_))));
+    }
+
+    fn login_slow(server: &mut ChatServer, username: &str) -> TestClient {
+        let mut client = TestClient::connect_slow(server, 1024);
+        client.send_handshake(username, Credentials::Guest);
+        server.tick();
+        assert!(matches!(client.receive(), Some(ServerMessage::LoginAccepted(_))));
+        assert_eq!(expect_joined(&mut client), DEFAULT_CHANNEL);
+        client
+    }
+
+    fn flood_config(policy: OverflowPolicy) -> ServerConfig {
+        ServerConfig {
+            rate_limits: None,
+            send_queue_limits: Some(SendQueueLimits {
+                max_packets: 8,
+                max_bytes: 1024 * 1024,
+                policy,
+            }),
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn slow_consumer_misses_chat() {
+        let mut server = make_server(flood_config(OverflowPolicy::DropNonEssential));
+        let mut slow = login_slow(&mut server, "slow");
+        let mut talker = login_guest(&mut server, "talker");
+
+        // bursts the queues of clients that keep up have room for
+        for burst in 0..10 {
+            for index in 0..4 {
+                send_text(&mut talker, DEFAULT_CHANNEL, &format!("{burst}.{index}"));
+            }
+            server.tick();
+        }
+        talker.send(&ClientMessage::DirectMessage(DirectMessage {
+            to: "slow".to_string(),
+            text: "still there?".to_string(),
+        }));
+        server.tick();
+
+        let stats = server.send_queue_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].username, "slow");
+        assert!(stats[0].queue.queued_packets <= 8);
+        assert!(stats[0].queue.dropped_packets > 0);
+        assert_eq!(stats[1].queue.dropped_packets, 0);
+
+        // drops are logged by the next report
+        server.last_queue_report -= QUEUE_REPORT_INTERVAL;
+        server.tick();
+        assert_eq!(
+            server.reported_drops.get(&stats[0].session_id),
+            Some(&stats[0].queue.dropped_packets)
+        );
+
+        // the talker is not held back by the slow client
+        for _ in 0..40 {
+            assert!(matches!(talker.receive(), Some(ServerMessage::MessageFromUser(_))));
+        }
+
+        let mut chat_messages = 0;
+        let mut direct_messages = 0;
+        loop {
+            server.tick();
+            match slow.receive() {
+                Some(ServerMessage::MessageFromUser(_)) => chat_messages += 1,
+                Some(ServerMessage::DirectMessage(_)) => direct_messages += 1,
+                Some(message) => panic!("Unexpected message {message:?}"),
+                None => break,
+            }
+        }
+        assert!(chat_messages < 40);
+        assert_eq!(chat_messages as u64 + stats[0].queue.dropped_packets, 40);
+        // essential messages are never dropped
+        assert_eq!(direct_messages, 1);
+        assert_eq!(server.online_users(), ["slow", "talker"]);
+    }
+
+    #[test]
+    fn disconnect_slow_consumer() {
+        let mut server = make_server(flood_config(OverflowPolicy::Disconnect));
+        let _slow = login_slow(&mut server, "slow");
+        let mut talker = login_guest(&mut server, "talker");
+
+        // bursts the queues of clients that keep up have room for
+        for burst in 0..10 {
+            for index in 0..4 {
+                send_text(&mut talker, DEFAULT_CHANNEL, &format!("{burst}.{index}"));
+            }
+            server.tick();
+        }
+
+        assert_eq!(server.online_users(), ["talker"]);
+        assert_eq!(server.send_queue_stats().len(), 1);
+        loop {
+            match talker.receive_presence() {
+                Some(ServerMessage::UserLeft(left)) => {
+                    assert_eq!(left.username, "slow");
+                    break;
+                }
+                Some(_) => continue,
+                None => panic!("Slow client was not announced as gone"),
+            }
+        }
+    }
+
+    #[test]
+    fn shutdown_notifies_clients() {
+        let mut server = make_server(ServerConfig {
+            shutdown_reconnect_after: Some(Duration::from_secs(30)),
+            ..ServerConfig::default()
+        });
+        let mut alice = login_guest(&mut server, "alice");
+        let mut pending = TestClient::connect(&mut server);
+
+        server.shutdown_trigger().trigger();
+        server.run().unwrap();
+
+        assert!(server.connections.is_empty());
+        assert!(server.local_addr().is_err());
+        assert_eq!(
+            alice.receive(),
+            Some(ServerMessage::ServerShutdown(ServerShutdown {
+                reason: "Server is shutting down".to_string(),
+                reconnect_after_ms: Some(30_000),
+            }))
+        );
+        assert_eq!(alice.transport.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(pending.transport.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn shutdown_drops_clients_after_timeout() {
+        let mut server = make_server(ServerConfig {
+            shutdown_timeout: Duration::from_millis(50),
+            rate_limits: None,
+            send_queue_limits: None,
+            ..ServerConfig::default()
+        });
+        let _slow = login_slow(&mut server, "slow");
+        let mut talker = login_guest(&mut server, "talker");
+        for index in 0..100 {
+            send_text(&mut talker, DEFAULT_CHANNEL, &format!("message {index}"));
+        }
+        server.tick();
+
+        let started = Instant::now();
+        server.shutdown("Maintenance").unwrap();
+
+        assert!(server.connections.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        loop {
+            match talker.receive() {
+                Some(ServerMessage::ServerShutdown(shutdown)) => {
+                    assert_eq!(shutdown.reason, "Maintenance");
+                    assert_eq!(shutdown.reconnect_after_ms, None);
+                    break;
+                }
+                Some(_) => continue,
+                None => panic!("Client that keeps up did not get the shutdown notice"),
+            }
+        }
     }
 }
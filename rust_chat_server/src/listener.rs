@@ -0,0 +1,307 @@
+use mio::{Interest, Registry, Token};
+use rust_chat::{ChatError, ChatResult, ConvertibleToChatResult, TlsTransport, Transport};
+use std::sync::Arc;
+
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+// Socket the server accepts connections on
+pub enum Listener {
+    Tcp {
+        listener: mio::net::TcpListener,
+        // accepted connections are wrapped in TLS when set
+        tls: Option<Arc<rustls::ServerConfig>>,
+    },
+    #[cfg(unix)]
+    Unix {
+        listener: mio::net::UnixListener,
+        // removed when the listener is dropped
+        path: PathBuf,
+    },
+}
+
+impl Listener {
+    pub fn bind_tcp(address: &str, tls: Option<Arc<rustls::ServerConfig>>) -> ChatResult<Listener> {
+        let listener = std::net::TcpListener::bind(address).to_chat_result()?;
+        listener.set_nonblocking(true).to_chat_result()?;
+        Ok(Listener::Tcp {
+            listener: mio::net::TcpListener::from_std(listener),
+            tls,
+        })
+    }
+
+    // Access to the socket is controlled by permissions of the socket file
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path, mode: u32) -> ChatResult<Listener> {
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt};
+
+        // socket file left behind by a server that was not stopped cleanly.
+        // Anything else at the path, including the socket of a running server, is not ours to remove
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(ChatError(format!(
+                    "{} exists and is not a socket",
+                    path.display()
+                )));
+            }
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(ChatError(format!(
+                        "{} is in use by another server",
+                        path.display()
+                    )))
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path).to_chat_result()?
+                }
+                Err(err) => {
+                    return Err(ChatError(format!(
+                        "Failed to check socket {}: {err}",
+                        path.display()
+                    )))
+                }
+            }
+        }
+
+        // a socket bound at the path could be connected to before its permissions are set.
+        // It is bound in a directory only the owner can enter instead and moved to the path
+        // once restricted. Unlike setting the umask, this does not affect other threads
+        let private_dir = private_dir_for(path);
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&private_dir)
+            .to_chat_result()?;
+        let private_path = private_dir.join("socket");
+        let listener = bind_restricted(&private_path, path, mode);
+        let _ = std::fs::remove_file(&private_path);
+        let _ = std::fs::remove_dir(&private_dir);
+
+        Ok(Listener::Unix {
+            listener: listener?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind_unix(_path: &std::path::Path, _mode: u32) -> ChatResult<Listener> {
+        Err(ChatError(
+            "Unix domain sockets are not supported on this platform".to_string(),
+        ))
+    }
+
+    pub fn accept(&self) -> std::io::Result<Box<dyn Transport>> {
+        match self {
+            Listener::Tcp { listener, tls } => {
+                let (stream, _) = listener.accept()?;
+                match tls {
+                    Some(config) => TlsTransport::server(stream, config.clone())
+                        .map(|transport| Box::new(transport) as Box<dyn Transport>)
+                        .map_err(|err| std::io::Error::other(err.0)),
+                    None => Ok(Box::new(stream)),
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => {
+                let (stream, _) = listener.accept()?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+
+    pub fn register(&mut self, registry: &Registry, token: Token) -> ChatResult<()> {
+        match self {
+            Listener::Tcp { listener, .. } => {
+                registry.register(listener, token, Interest::READABLE)
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => {
+                registry.register(listener, token, Interest::READABLE)
+            }
+        }
+        .to_chat_result()
+    }
+}
+
+// Next to the socket path so that the socket can be renamed to it
+#[cfg(unix)]
+fn private_dir_for(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.{}", std::process::id()))
+}
+
+#[cfg(unix)]
+fn bind_restricted(
+    private_path: &Path,
+    path: &Path,
+    mode: u32,
+) -> ChatResult<mio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let listener = mio::net::UnixListener::bind(private_path).to_chat_result()?;
+    std::fs::set_permissions(private_path, std::fs::Permissions::from_mode(mode))
+        .to_chat_result()?;
+    std::fs::rename(private_path, path).to_chat_result()?;
+    Ok(listener)
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix { path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_chat::{
+        load_client_config, protocol, Credentials, HandshakeMessage, PacketReceiver, PacketSender,
+        ServerMessage, TlsTrust,
+    };
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    #[cfg(unix)]
+    use std::path::PathBuf;
+
+    fn accept(listener: &Listener) -> Box<dyn Transport> {
+        loop {
+            match listener.accept() {
+                Ok(transport) => return transport,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(err) => panic!("Failed to accept: {err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn tls_login() {
+        let certified_key =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let directory = std::env::temp_dir();
+        let cert_path = directory.join(format!("rust_chat_{}_tls_cert.pem", std::process::id()));
+        let key_path = directory.join(format!("rust_chat_{}_tls_key.pem", std::process::id()));
+        std::fs::write(&cert_path, certified_key.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified_key.signing_key.serialize_pem()).unwrap();
+
+        let server_config = rust_chat::load_server_config(&cert_path, &key_path).unwrap();
+        let client_config = load_client_config(&TlsTrust::CustomCa(cert_path.clone())).unwrap();
+        std::fs::remove_file(&cert_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+
+        let listener = Listener::bind_tcp("127.0.0.1:0", Some(server_config)).unwrap();
+        let address = match &listener {
+            Listener::Tcp { listener, .. } => listener.local_addr().unwrap(),
+            #[cfg(unix)]
+            Listener::Unix { .. } => unreachable!(),
+        };
+
+        let stream = std::net::TcpStream::connect(address).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut client = TlsTransport::client(stream, client_config, "localhost").unwrap();
+        let mut server = rust_chat::Connection::new(accept(&listener));
+
+        let mut sender = PacketSender::new();
+        let mut receiver = PacketReceiver::new();
+        sender.add_to_send_queue(protocol::encode(&HandshakeMessage {
+            username: "user".to_string(),
+            credentials: Credentials::Guest,
+        }));
+
+        for _ in 0..1000 {
+            sender.advance(&mut client).unwrap();
+            receiver.advance(&mut client).unwrap();
+            if let Some(data) = receiver.pop_packet() {
+                match protocol::decode::<ServerMessage>(&data).unwrap() {
+                    ServerMessage::LoginAccepted(login_accepted) => {
+                        assert_eq!(login_accepted.session_id, 7);
+                        return;
+                    }
+                    message => panic!("Unexpected message: {message:?}"),
+                }
+            }
+
+            server = match server.receive() {
+                rust_chat::Connection::HandShake(state) if state.handshake_message().is_some() => {
+                    state.accept(rust_chat::LoginAccepted {
+                        session_id: 7,
+                        server_name: "test".to_string(),
+                        protocol_version: protocol::PROTOCOL_VERSION,
+                        motd: None,
+                    })
+                }
+                connection => connection,
+            }
+            .send();
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        panic!("Login was not accepted");
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_chat_{}_{name}.sock", std::process::id()))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_permissions_and_cleanup() {
+        let path = socket_path("permissions");
+        for expected_mode in [0o600, 0o660] {
+            let listener = Listener::bind_unix(&path, expected_mode).unwrap();
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, expected_mode);
+            assert!(!private_dir_for(&path).exists());
+            let _client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+            accept(&listener);
+
+            drop(listener);
+            assert!(!path.exists());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replace_stale_socket() {
+        let path = socket_path("stale");
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        assert!(path.exists());
+
+        let listener = Listener::bind_unix(&path, 0o600).unwrap();
+        let _client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        let transport = accept(&listener);
+
+        let credentials = transport.peer_credentials().unwrap();
+        assert_eq!(credentials.uid, unsafe { libc::getuid() });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keep_socket_of_running_server() {
+        let path = socket_path("running");
+        let running = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let Err(ChatError(err)) = Listener::bind_unix(&path, 0o600) else {
+            panic!("Socket of running server was replaced");
+        };
+        assert!(err.contains("in use"), "{err}");
+        assert!(path.exists());
+
+        drop(running);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keep_file_that_is_not_socket() {
+        let path = socket_path("regular_file");
+        std::fs::write(&path, b"data").unwrap();
+
+        assert!(Listener::bind_unix(&path, 0o600).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,3 +1,6 @@
 fn main() {
-    rust_chat_server::run_app().expect("Some server error");
+    if let Err(rust_chat::ChatError(message)) = rust_chat_server::run_app() {
+        eprintln!("{message}");
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,289 @@
+use rusqlite::{params, Connection};
+use rust_chat::{ChatError, ChatResult, ConvertibleToChatResult, MesasgeFromUser};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/* Schema changes, applied in order. PRAGMA user_version holds the number of
+ * migrations the database has gone through, so only the missing ones run on open.
+ * Applied migrations must never be edited, append a new one instead.
+ */
+const MIGRATIONS: &[&str] = &["CREATE TABLE messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        sender TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        timestamp_ms INTEGER NOT NULL,
+        text TEXT NOT NULL
+    );
+    CREATE INDEX messages_channel_id ON messages (channel, id);
+    CREATE INDEX messages_timestamp ON messages (timestamp_ms);"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub sender: String,
+    pub channel: String,
+    // milliseconds since unix epoch, set by the server
+    pub timestamp_ms: i64,
+    pub text: String,
+}
+
+impl From<StoredMessage> for MesasgeFromUser {
+    fn from(message: StoredMessage) -> Self {
+        MesasgeFromUser {
+            username: message.sender,
+            text: message.text,
+            channel: message.channel,
+            id: Some(message.id as u64),
+            timestamp_ms: Some(message.timestamp_ms),
+        }
+    }
+}
+
+// Messages that outlive the server process
+pub struct MessageHistory {
+    connection: Connection,
+    // messages older than this are deleted by prune
+    retention: Option<Duration>,
+}
+
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+impl MessageHistory {
+    pub fn open(path: &Path, retention: Option<Duration>) -> ChatResult<MessageHistory> {
+        Self::with_connection(Connection::open(path).to_chat_result()?, retention)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory(retention: Option<Duration>) -> ChatResult<MessageHistory> {
+        Self::with_connection(Connection::open_in_memory().to_chat_result()?, retention)
+    }
+
+    fn with_connection(mut connection: Connection, retention: Option<Duration>) -> ChatResult<MessageHistory> {
+        migrate(&mut connection)?;
+        let mut history = MessageHistory {
+            connection,
+            retention,
+        };
+        history.prune()?;
+        Ok(history)
+    }
+
+    // Reports errors that dropping the history would ignore
+    pub fn close(self) -> ChatResult<()> {
+        self.connection.close().map_err(|(_, err)| err).to_chat_result()
+    }
+
+    // Returns the stored message with id assigned by the database
+    pub fn append(&mut self, sender: &str, channel: &str, text: &str) -> ChatResult<StoredMessage> {
+        let timestamp_ms = now_ms();
+        self.connection
+            .execute(
+                "INSERT INTO messages (sender, channel, timestamp_ms, text) VALUES (?1, ?2, ?3, ?4)",
+                params![sender, channel, timestamp_ms, text],
+            )
+            .to_chat_result()?;
+
+        Ok(StoredMessage {
+            id: self.connection.last_insert_rowid(),
+            sender: sender.to_string(),
+            channel: channel.to_string(),
+            timestamp_ms,
+            text: text.to_string(),
+        })
+    }
+
+    // Up to limit newest messages of the channel with id less than before_id, oldest first
+    pub fn fetch(
+        &self,
+        channel: &str,
+        before_id: Option<i64>,
+        limit: usize,
+    ) -> ChatResult<Vec<StoredMessage>> {
+        let mut statement = self
+            .connection
+            .prepare_cached(
+                "SELECT id, sender, channel, timestamp_ms, text FROM messages
+                 WHERE channel = ?1 AND id < ?2
+                 ORDER BY id DESC LIMIT ?3",
+            )
+            .to_chat_result()?;
+
+        let rows = statement
+            .query_map(
+                params![channel, before_id.unwrap_or(i64::MAX), limit as i64],
+                |row| {
+                    Ok(StoredMessage {
+                        id: row.get(0)?,
+                        sender: row.get(1)?,
+                        channel: row.get(2)?,
+                        timestamp_ms: row.get(3)?,
+                        text: row.get(4)?,
+                    })
+                },
+            )
+            .to_chat_result()?;
+
+        let mut messages = rows.collect::<Result<Vec<_>, _>>().to_chat_result()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    // Deletes messages older than the retention period. Returns number of deleted messages
+    pub fn prune(&mut self) -> ChatResult<usize> {
+        let retention = match self.retention {
+            Some(retention) => retention,
+            None => return Ok(0),
+        };
+
+        let oldest_kept = now_ms() - retention.as_millis() as i64;
+        self.connection
+            .execute("DELETE FROM messages WHERE timestamp_ms < ?1", params![oldest_kept])
+            .to_chat_result()
+    }
+}
+
+fn migrate(connection: &mut Connection) -> ChatResult<()> {
+    let applied: usize = connection
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .to_chat_result()?;
+
+    if applied > MIGRATIONS.len() {
+        return Err(ChatError(format!(
+            "History database has schema version {applied}, newer than supported {}",
+            MIGRATIONS.len()
+        )));
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let transaction = connection.transaction().to_chat_result()?;
+        transaction.execute_batch(migration).to_chat_result()?;
+        transaction
+            .pragma_update(None, "user_version", version + 1)
+            .to_chat_result()?;
+        transaction.commit().to_chat_result()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(history: &MessageHistory) -> Vec<StoredMessage> {
+        let mut statement = history
+            .connection
+            .prepare("SELECT id, sender, channel, timestamp_ms, text FROM messages ORDER BY id")
+            .unwrap();
+        let rows = statement
+            .query_map([], |row| {
+                Ok(StoredMessage {
+                    id: row.get(0)?,
+                    sender: row.get(1)?,
+                    channel: row.get(2)?,
+                    timestamp_ms: row.get(3)?,
+                    text: row.get(4)?,
+                })
+            })
+            .unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn append_assigns_increasing_ids() {
+        let mut history = MessageHistory::open_in_memory(None).unwrap();
+        let first = history.append("user", "general", "first").unwrap();
+        let second = history.append("other", "random", "second").unwrap();
+        assert!(first.id < second.id);
+        assert!(first.timestamp_ms <= second.timestamp_ms);
+        assert_eq!(messages(&history), vec![first, second]);
+    }
+
+    #[test]
+    fn fetch_pages() {
+        let mut history = MessageHistory::open_in_memory(None).unwrap();
+        let ids: Vec<i64> = (0..5)
+            .map(|index| {
+                history
+                    .append("user", "general", &index.to_string())
+                    .unwrap()
+                    .id
+            })
+            .collect();
+        history.append("user", "other", "elsewhere").unwrap();
+
+        let texts = |messages: Vec<StoredMessage>| -> Vec<String> {
+            messages.into_iter().map(|message| message.text).collect()
+        };
+        assert_eq!(
+            texts(history.fetch("general", None, 3).unwrap()),
+            ["2", "3", "4"]
+        );
+        assert_eq!(
+            texts(history.fetch("general", Some(ids[2]), 10).unwrap()),
+            ["0", "1"]
+        );
+        assert!(history
+            .fetch("general", Some(ids[0]), 10)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            texts(history.fetch("other", None, 10).unwrap()),
+            ["elsewhere"]
+        );
+    }
+
+    #[test]
+    fn prune_old_messages() {
+        let mut history = MessageHistory::open_in_memory(Some(Duration::from_secs(60))).unwrap();
+        history.append("user", "general", "new").unwrap();
+        history
+            .connection
+            .execute(
+                "INSERT INTO messages (sender, channel, timestamp_ms, text) VALUES ('user', 'general', ?1, 'old')",
+                params![now_ms() - 120_000],
+            )
+            .unwrap();
+
+        assert_eq!(history.prune().unwrap(), 1);
+        let messages = messages(&history);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].text, "new");
+    }
+
+    #[test]
+    fn survives_reopen() {
+        let path = std::env::temp_dir().join(format!("rust_chat_{}_history.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let stored = MessageHistory::open(&path, None)
+            .unwrap()
+            .append("user", "general", "persisted")
+            .unwrap();
+
+        let history = MessageHistory::open(&path, None).unwrap();
+        assert_eq!(messages(&history), vec![stored]);
+        let version: usize = history
+            .connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        drop(history);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reject_newer_schema() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(migrate(&mut connection).is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use rust_chat::{ConnectionInfo, PeerAddress, Transport};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Limits on what one connection may send, see FloodGuard for the escalation
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    pub messages_per_second: f64,
+    // messages that can be sent at once after being quiet
+    pub message_burst: u32,
+    pub bytes_per_second: f64,
+    pub byte_burst: u32,
+    // how long a connection stays muted after its second violation
+    pub mute_duration: Duration,
+    // violations are forgotten after this long without a new one
+    pub violation_expiry: Duration,
+    // violations within this long after a warning do not escalate further
+    pub escalation_cooldown: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            messages_per_second: 5.0,
+            message_burst: 20,
+            bytes_per_second: 16.0 * 1024.0,
+            byte_burst: 128 * 1024,
+            mute_duration: Duration::from_secs(30),
+            violation_expiry: Duration::from_secs(5 * 60),
+            escalation_cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+/* Holds up to capacity tokens and refills continuously at the given rate.
+ * Taking more tokens than there are fails without taking any
+ */
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_second: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Starts full so that a new connection can send its burst right away
+    pub fn new(capacity: f64, refill_per_second: f64) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            refill_per_second,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self, amount: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Allow,
+    // first violation
+    Warn,
+    // second violation, the connection is muted for this long
+    Mute(Duration),
+    // third violation
+    Disconnect,
+    // over the limit before the last response had a chance to work, dropped without escalating
+    Drop,
+}
+
+/* Rate limits of one connection.
+ * Violations escalate the response: a warning first, then a temporary mute
+ * and finally disconnection. The next step is taken only after the cooldown following
+ * a warning or after the mute ends, so that one burst sent before the client saw the
+ * warning is not punished three times. Violations expire if the connection behaves long enough
+ */
+pub struct FloodGuard {
+    limits: RateLimits,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    violations: u32,
+    last_violation: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl FloodGuard {
+    pub fn new(limits: &RateLimits) -> FloodGuard {
+        FloodGuard {
+            messages: TokenBucket::new(limits.message_burst as f64, limits.messages_per_second),
+            bytes: TokenBucket::new(limits.byte_burst as f64, limits.bytes_per_second),
+            limits: limits.clone(),
+            violations: 0,
+            last_violation: None,
+            muted_until: None,
+        }
+    }
+
+    // Accounts one received message of given size
+    pub fn check(&mut self, size: usize) -> Verdict {
+        let now = Instant::now();
+        if let Some(last_violation) = self.last_violation {
+            if now.duration_since(last_violation) >= self.limits.violation_expiry {
+                self.violations = 0;
+                self.last_violation = None;
+            }
+        }
+
+        // both buckets are charged, a message may not skip the byte limit by failing the other one
+        let message_allowed = self.messages.try_take(1.0);
+        let bytes_allowed = self.bytes.try_take(size as f64);
+        if !(message_allowed && bytes_allowed) {
+            return self.violation(now);
+        }
+        Verdict::Allow
+    }
+
+    // Remaining time of the mute, chat messages are dropped meanwhile
+    pub fn muted_for(&self) -> Option<Duration> {
+        self.muted_until
+            .and_then(|muted_until| muted_until.checked_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn violation(&mut self, now: Instant) -> Verdict {
+        let last_step_ends = match self.violations {
+            0 => None,
+            1 => self.last_violation.map(|warned| warned + self.limits.escalation_cooldown),
+            _ => self.muted_until,
+        };
+        if last_step_ends.is_some_and(|ends| now < ends) {
+            return Verdict::Drop;
+        }
+
+        self.violations += 1;
+        self.last_violation = Some(now);
+        match self.violations {
+            1 => Verdict::Warn,
+            2 => {
+                self.muted_until = Some(now + self.limits.mute_duration);
+                Verdict::Mute(self.limits.mute_duration)
+            }
+            _ => Verdict::Disconnect,
+        }
+    }
+}
+
+// What limits per peer are counted for: the host of TCP peers and the user of local ones
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerId {
+    Host(IpAddr),
+    User(u32),
+    // local peers without credentials, and peers which address could not be read
+    Local,
+}
+
+impl PeerId {
+    pub fn of(connection_info: Option<&ConnectionInfo>) -> PeerId {
+        match connection_info {
+            Some(ConnectionInfo {
+                address: PeerAddress::Tcp(address),
+                ..
+            }) => PeerId::Host(address.ip()),
+            Some(ConnectionInfo {
+                credentials: Some(credentials),
+                ..
+            }) => PeerId::User(credentials.uid),
+            _ => PeerId::Local,
+        }
+    }
+
+    // For connections that have no handshake state yet
+    pub fn of_transport(transport: &dyn Transport) -> PeerId {
+        let connection_info = transport.peer_address().ok().map(|address| ConnectionInfo {
+            address,
+            credentials: transport.peer_credentials(),
+        });
+        PeerId::of(connection_info.as_ref())
+    }
+}
+
+/* Failed password logins of each peer. Once a peer fails max_failures times within the
+ * window, its logins with password are refused without hashing until the window passes.
+ * A rejected login closes the connection, so counting per connection would not stop anyone
+ */
+pub struct LoginThrottle {
+    max_failures: u32,
+    window: Duration,
+    // number of failures and when the first of them happened
+    failures: HashMap<PeerId, (u32, Instant)>,
+}
+
+impl LoginThrottle {
+    pub fn new(max_failures: u32, window: Duration) -> LoginThrottle {
+        LoginThrottle {
+            max_failures,
+            window,
+            failures: HashMap::new(),
+        }
+    }
+
+    pub fn is_blocked(&self, peer: &PeerId) -> bool {
+        match self.failures.get(peer) {
+            Some((count, since)) => *count >= self.max_failures && since.elapsed() < self.window,
+            None => false,
+        }
+    }
+
+    pub fn record_failure(&mut self, peer: PeerId) {
+        let (count, since) = self.failures.entry(peer).or_insert((0, Instant::now()));
+        if since.elapsed() >= self.window {
+            (*count, *since) = (0, Instant::now());
+        }
+        *count += 1;
+    }
+
+    // Forgets peers which window has passed
+    pub fn prune(&mut self) {
+        let window = self.window;
+        self.failures.retain(|_, (_, since)| since.elapsed() < window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            messages_per_second: 0.001,
+            message_burst: 2,
+            bytes_per_second: 0.001,
+            byte_burst: 100,
+            mute_duration: Duration::from_millis(20),
+            violation_expiry: Duration::from_secs(60),
+            escalation_cooldown: Duration::from_millis(20),
+        }
+    }
+
+    #[test]
+    fn token_bucket_refills() {
+        let mut bucket = TokenBucket::new(2.0, 1000.0);
+        assert!(bucket.try_take(2.0));
+        assert!(!bucket.try_take(2.0));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(bucket.try_take(2.0));
+        assert!(!bucket.try_take(3.0));
+    }
+
+    #[test]
+    fn escalate_violations() {
+        let mut guard = FloodGuard::new(&limits());
+        assert_eq!(guard.check(10), Verdict::Allow);
+        assert_eq!(guard.check(10), Verdict::Allow);
+        assert_eq!(guard.check(10), Verdict::Warn);
+        assert_eq!(guard.muted_for(), None);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(matches!(guard.check(10), Verdict::Mute(_)));
+        assert!(guard.muted_for().is_some());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(guard.check(10), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn burst_only_warns() {
+        let mut guard = FloodGuard::new(&RateLimits {
+            escalation_cooldown: Duration::from_secs(60),
+            ..limits()
+        });
+        guard.check(10);
+        guard.check(10);
+        assert_eq!(guard.check(10), Verdict::Warn);
+        for _ in 0..20 {
+            assert_eq!(guard.check(10), Verdict::Drop);
+        }
+        assert_eq!(guard.muted_for(), None);
+    }
+
+    #[test]
+    fn no_escalation_while_muted() {
+        let mut guard = FloodGuard::new(&RateLimits {
+            mute_duration: Duration::from_secs(60),
+            escalation_cooldown: Duration::ZERO,
+            ..limits()
+        });
+        guard.check(10);
+        guard.check(10);
+        assert_eq!(guard.check(10), Verdict::Warn);
+        assert!(matches!(guard.check(10), Verdict::Mute(_)));
+        assert_eq!(guard.check(10), Verdict::Drop);
+    }
+
+    #[test]
+    fn byte_limit() {
+        let mut guard = FloodGuard::new(&limits());
+        assert_eq!(guard.check(101), Verdict::Warn);
+    }
+
+    #[test]
+    fn throttle_failed_logins() {
+        let mut throttle = LoginThrottle::new(2, Duration::from_millis(20));
+        throttle.record_failure(PeerId::Local);
+        assert!(!throttle.is_blocked(&PeerId::Local));
+        throttle.record_failure(PeerId::Local);
+        assert!(throttle.is_blocked(&PeerId::Local));
+        assert!(!throttle.is_blocked(&PeerId::User(1000)));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!throttle.is_blocked(&PeerId::Local));
+        throttle.prune();
+        assert!(throttle.failures.is_empty());
+    }
+
+    #[test]
+    fn violations_expire() {
+        let mut guard = FloodGuard::new(&RateLimits {
+            violation_expiry: Duration::from_millis(10),
+            ..limits()
+        });
+        guard.check(10);
+        guard.check(10);
+        assert_eq!(guard.check(10), Verdict::Warn);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(guard.check(10), Verdict::Warn);
+    }
+}
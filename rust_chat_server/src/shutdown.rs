@@ -0,0 +1,79 @@
+use mio::Waker;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/* Asks the server to shut down, from another thread or from a signal handler.
+ * Triggering only sets a flag and wakes the poll up, both are safe in a signal handler
+ */
+#[derive(Clone)]
+pub struct ShutdownTrigger {
+    triggered: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownTrigger {
+    pub(crate) fn new(waker: Arc<Waker>) -> ShutdownTrigger {
+        ShutdownTrigger {
+            triggered: Arc::new(AtomicBool::new(false)),
+            waker,
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(unix)]
+mod signals {
+    use super::ShutdownTrigger;
+    use rust_chat::{ChatError, ChatResult};
+    use std::sync::OnceLock;
+
+    static TRIGGER: OnceLock<ShutdownTrigger> = OnceLock::new();
+
+    extern "C" fn handle_signal(signal: libc::c_int) {
+        if let Some(trigger) = TRIGGER.get() {
+            // the second signal means the graceful shutdown is taking too long
+            if trigger.is_triggered() {
+                unsafe { libc::_exit(128 + signal) };
+            }
+            trigger.trigger();
+        }
+    }
+
+    // SIGINT and SIGTERM trigger the shutdown, only one server per process can have them
+    pub fn handle_signals(trigger: ShutdownTrigger) -> ChatResult<()> {
+        if TRIGGER.set(trigger).is_err() {
+            return Err(ChatError(
+                "Signals are handled by another server already".to_string(),
+            ));
+        }
+
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            // the handler only touches an atomic flag and writes to the waker
+            let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                return Err(ChatError(format!(
+                    "Failed to handle signal {signal}: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub use signals::handle_signals;
+
+// Without unix signals the process keeps the default handling of Ctrl+C
+#[cfg(not(unix))]
+pub fn handle_signals(_trigger: ShutdownTrigger) -> rust_chat::ChatResult<()> {
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use rust_chat::LoginRejected;
+use std::collections::HashSet;
+
+// Names are sent with every message and the roster lists all of them
+const MAX_USERNAME_LENGTH: usize = 32;
+
+/* Usernames of currently logged in users.
+ * Names are compared case-insensitively so "Admin" and "admin" are the same user.
+ */
+pub struct UsernameRegistry {
+    active: HashSet<String>,
+    reserved: HashSet<String>,
+}
+
+impl UsernameRegistry {
+    pub fn new(reserved_usernames: &[String]) -> UsernameRegistry {
+        UsernameRegistry {
+            active: HashSet::new(),
+            reserved: reserved_usernames
+                .iter()
+                .map(|name| normalize(name))
+                .collect(),
+        }
+    }
+
+    // Validates the name and marks it as taken. Returns the rejection otherwise
+    pub fn claim(&mut self, username: &str) -> Result<(), LoginRejected> {
+        validate_username(username).map_err(LoginRejected::permanent)?;
+
+        let key = normalize(username);
+        if self.reserved.contains(&key) {
+            return Err(LoginRejected::permanent(format!(
+                "Username \"{username}\" is reserved"
+            )));
+        }
+
+        // the user may be logging in again before the old connection is noticed to be gone
+        if !self.active.insert(key) {
+            return Err(LoginRejected::temporary(format!(
+                "Username \"{username}\" is already taken"
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn release(&mut self, username: &str) {
+        self.active.remove(&normalize(username));
+    }
+}
+
+// Key under which the name is stored, names equal up to case share it
+pub fn normalize(username: &str) -> String {
+    username.to_lowercase()
+}
+
+fn validate_username(username: &str) -> Result<(), String> {
+    if username.trim().is_empty() {
+        return Err("Username must not be empty".to_string());
+    }
+
+    if username.chars().count() > MAX_USERNAME_LENGTH {
+        return Err(format!(
+            "Username must be at most {MAX_USERNAME_LENGTH} characters long"
+        ));
+    }
+
+    if username.trim() != username {
+        return Err("Username must not start or end with whitespace".to_string());
+    }
+
+    if username.chars().any(char::is_control) {
+        return Err("Username must not contain control characters".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_registry() -> UsernameRegistry {
+        UsernameRegistry::new(&["admin".to_string()])
+    }
+
+    #[test]
+    fn claim_and_release() {
+        let mut registry = make_registry();
+        assert!(registry.claim("user").is_ok());
+        assert!(registry.claim("user").unwrap_err().retryable);
+        assert!(registry.claim("USER").is_err());
+
+        registry.release("user");
+        assert!(registry.claim("user").is_ok());
+    }
+
+    #[test]
+    fn reject_invalid_names() {
+        let mut registry = make_registry();
+        assert!(registry.claim("").is_err());
+        assert!(registry.claim("   ").is_err());
+        assert!(registry.claim(" user").is_err());
+        assert!(registry.claim("us\ner").is_err());
+        assert!(registry.claim("us\u{7}er").is_err());
+    }
+
+    #[test]
+    fn reject_long_names() {
+        let mut registry = make_registry();
+        assert!(registry.claim(&"a".repeat(MAX_USERNAME_LENGTH)).is_ok());
+        assert!(registry.claim(&"b".repeat(MAX_USERNAME_LENGTH + 1)).is_err());
+        // counted in characters, not bytes
+        assert!(registry.claim(&"ž".repeat(MAX_USERNAME_LENGTH)).is_ok());
+        assert!(registry.claim(&"x".repeat(65_000)).is_err());
+    }
+
+    #[test]
+    fn reject_reserved_names() {
+        let mut registry = make_registry();
+        assert!(!registry.claim("admin").unwrap_err().retryable);
+        assert!(registry.claim("Admin").is_err());
+    }
+}
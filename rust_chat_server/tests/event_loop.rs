@@ -0,0 +1,159 @@
+/* Drives the server loop by hand to check that it wakes up for everything it has to handle:
+ * new connections, data from clients and the timeout it was given. A missed readiness event
+ * shows up as wait_for_events sleeping for the whole timeout
+ */
+use rust_chat::{
+    protocol, Credentials, HandshakeMessage, PacketReceiver, PacketSender, ServerMessage,
+};
+use rust_chat_server::{ChatServer, ServerConfig};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+// long enough to never expire when an event arrives, even on a loaded machine
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+const IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+struct TestClient {
+    stream: TcpStream,
+    sender: PacketSender,
+    receiver: PacketReceiver,
+}
+
+impl TestClient {
+    fn connect(server: &ChatServer) -> TestClient {
+        let stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        TestClient {
+            stream,
+            sender: PacketSender::new(),
+            receiver: PacketReceiver::new(),
+        }
+    }
+
+    fn send_handshake(&mut self, username: &str) {
+        self.sender
+            .add_to_send_queue(protocol::encode(&HandshakeMessage {
+                username: username.to_string(),
+                credentials: Credentials::Guest,
+            }));
+        while !self.sender.empty() {
+            self.sender.advance(&mut self.stream).unwrap();
+        }
+    }
+
+    fn is_logged_in(&mut self) -> bool {
+        self.receiver.advance(&mut self.stream).unwrap();
+        while let Some(data) = self.receiver.pop_packet() {
+            match protocol::decode(&data).unwrap() {
+                ServerMessage::LoginAccepted(_) => return true,
+                ServerMessage::LoginRejected(rejected) => panic!("Login rejected: {rejected:?}"),
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+fn start_server() -> ChatServer {
+    let config = ServerConfig {
+        tcp_address: Some("127.0.0.1:0".to_string()),
+        ..ServerConfig::default()
+    };
+    ChatServer::new(config).unwrap()
+}
+
+// Returns how long the server slept
+fn wait_for_events(server: &mut ChatServer, timeout: Duration) -> Duration {
+    let start = Instant::now();
+    server.wait_for_events(Some(timeout)).unwrap();
+    start.elapsed()
+}
+
+fn assert_woken_by_event(server: &mut ChatServer) {
+    let slept = wait_for_events(server, EVENT_TIMEOUT);
+    assert!(
+        slept < EVENT_TIMEOUT,
+        "Server slept {slept:?} despite a ready socket"
+    );
+}
+
+// Ticks until the server sleeps for the whole timeout, e.g. after sockets became writable
+fn tick_until_idle(server: &mut ChatServer) {
+    for _ in 0..10 {
+        server.tick();
+        if wait_for_events(server, IDLE_TIMEOUT) >= IDLE_TIMEOUT {
+            return;
+        }
+    }
+    panic!("Server keeps waking up without anything to do");
+}
+
+#[test]
+fn sleeps_until_timeout_without_events() {
+    let mut server = start_server();
+
+    let slept = wait_for_events(&mut server, IDLE_TIMEOUT);
+    assert!(
+        slept >= IDLE_TIMEOUT,
+        "Woke up after {slept:?} without events"
+    );
+    assert!(slept < EVENT_TIMEOUT, "Overslept the timeout: {slept:?}");
+}
+
+#[test]
+fn wakes_up_for_new_connection() {
+    let mut server = start_server();
+    let mut client = TestClient::connect(&server);
+
+    assert_woken_by_event(&mut server);
+    server.tick();
+
+    client.send_handshake("alice");
+    assert_woken_by_event(&mut server);
+    server.tick();
+    assert!(client.is_logged_in());
+}
+
+#[test]
+fn wakes_up_for_data_on_idle_connection() {
+    let mut server = start_server();
+    let mut client = TestClient::connect(&server);
+    assert_woken_by_event(&mut server);
+    tick_until_idle(&mut server);
+
+    client.send_handshake("alice");
+    assert_woken_by_event(&mut server);
+    server.tick();
+    assert!(client.is_logged_in());
+}
+
+#[test]
+fn accepts_whole_backlog_at_once() {
+    let mut server = start_server();
+    let mut clients: Vec<_> = (0..3).map(|_| TestClient::connect(&server)).collect();
+    // all connections have to be in the backlog before the server looks at it
+    std::thread::sleep(IDLE_TIMEOUT);
+
+    // the listener is edge-triggered, connections left in the backlog get no further event
+    assert_woken_by_event(&mut server);
+    server.accept_connections();
+
+    for (index, client) in clients.iter_mut().enumerate() {
+        client.send_handshake(&format!("user{index}"));
+    }
+    let deadline = Instant::now() + EVENT_TIMEOUT;
+    let mut logged_in = vec![false; clients.len()];
+    while logged_in.contains(&false) {
+        assert!(
+            Instant::now() < deadline,
+            "Not all connections were accepted"
+        );
+        server.receive_data();
+        server.process_handshakes();
+        server.send_data();
+        std::thread::sleep(Duration::from_millis(1));
+        for (client, logged_in) in clients.iter_mut().zip(&mut logged_in) {
+            *logged_in |= client.is_logged_in();
+        }
+    }
+}